@@ -0,0 +1,565 @@
+//! An agent that keeps the master keys of a user in memory, similar to
+//! `ssh-agent`.
+//!
+//! The agent listens on a Unix socket in a private directory. Its path is
+//! passed to clients via the `MPW_AUTH_SOCK` environment variable. Clients
+//! never receive a master key, only the keys of single sites and the results
+//! of encrypting or decrypting stored passwords.
+//!
+//! Every connection carries one request and one response. Both are a 32-bit
+//! big-endian length followed by the message. A request starts with its type,
+//! a response with a status byte followed by the payload or an error message.
+
+extern crate byteorder;
+extern crate data_encoding;
+extern crate libc;
+
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use self::data_encoding::base64;
+
+use mpw::algorithm;
+use mpw::algorithm::{SiteType, SiteVariant, AlgorithmVersion, MasterKeyCache, Templates, site_key,
+    password_for_site_key, encrypt, decrypt, min_buffer_len, key_id};
+use mpw::clear_on_drop::ClearOnDrop;
+use mpw::config::Site;
+use mpw::master_key::{site_associated_data, stored_password};
+use mpw::secret::{SecretBox, SecretString, SecretVec, relock_after_fork};
+
+/// Environment variable containing the path of the agent's socket.
+pub const SOCKET_ENV_VAR: &str = "MPW_AUTH_SOCK";
+/// Environment variable containing the process ID of the agent.
+pub const PID_ENV_VAR: &str = "MPW_AGENT_PID";
+/// Default time after which an idle agent exits, in seconds.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
+
+/// Maximal length of a message, to avoid allocating arbitrary amounts of memory.
+const MAX_MESSAGE_LEN: u32 = 1 << 16;
+/// How long to wait for a client to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to check whether the idle timeout has elapsed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const REQUEST_FULL_NAME: u8 = 0;
+const REQUEST_SITE_KEY: u8 = 1;
+const REQUEST_ENCRYPT: u8 = 2;
+const REQUEST_DECRYPT: u8 = 3;
+const REQUEST_KEY_ID: u8 = 4;
+
+const RESPONSE_OK: u8 = 0;
+const RESPONSE_ERROR: u8 = 1;
+
+/// Agent error.
+///
+/// Only the message is kept, since the `mpw` binary just reports it.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+}
+
+impl Error {
+    /// A message violated the protocol.
+    fn protocol() -> Error {
+        Error { message: "invalid message from agent".into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<algorithm::Error> for Error {
+    fn from(e: algorithm::Error) -> Error {
+        Error { message: e.message }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error { message: format!("could not talk to agent: {}", e) }
+    }
+}
+
+fn variant_to_u8(variant: SiteVariant) -> u8 {
+    match variant {
+        SiteVariant::Password => 0,
+        SiteVariant::Login => 1,
+        SiteVariant::Answer => 2,
+    }
+}
+
+fn variant_from_u8(n: u8) -> Option<SiteVariant> {
+    match n {
+        0 => Some(SiteVariant::Password),
+        1 => Some(SiteVariant::Login),
+        2 => Some(SiteVariant::Answer),
+        _ => None,
+    }
+}
+
+/// Append a length-prefixed byte string to a message.
+fn write_bytes(message: &mut Vec<u8>, bytes: &[u8]) {
+    // Writing to a vector cannot fail.
+    message.write_u32::<BigEndian>(bytes.len() as u32).unwrap();
+    message.extend_from_slice(bytes);
+}
+
+/// Read a length-prefixed byte string from a message.
+fn read_bytes<'a>(message: &mut Cursor<&'a [u8]>) -> io::Result<&'a [u8]> {
+    let len = message.read_u32::<BigEndian>()? as usize;
+    let start = message.position() as usize;
+    let buf = *message.get_ref();
+    if buf.len() - start < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "message truncated"));
+    }
+    message.set_position((start + len) as u64);
+    Ok(&buf[start..start + len])
+}
+
+/// Send a message over a stream.
+fn send(stream: &mut UnixStream, message: &[u8]) -> io::Result<()> {
+    let mut frame = ClearOnDrop::new(Vec::with_capacity(message.len() + 4));
+    write_bytes(&mut frame, message);
+    stream.write_all(&frame)
+}
+
+/// Receive a message from a stream.
+fn receive(stream: &mut UnixStream) -> io::Result<ClearOnDrop<Vec<u8>>> {
+    let len = stream.read_u32::<BigEndian>()?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+    }
+    let mut message = ClearOnDrop::new(vec![0; len as usize]);
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// A client of a running agent.
+#[derive(Debug)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// Create a client for the agent listening on the given socket.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Client {
+        Client { path: path.into() }
+    }
+
+    /// Create a client for the agent given by the environment, if any.
+    pub fn from_env() -> Option<Client> {
+        env::var_os(SOCKET_ENV_VAR)
+            .filter(|path| !path.is_empty())
+            .map(Client::new)
+    }
+
+    /// Send a request to the agent and return the payload of its response.
+    fn request(&self, request: &[u8]) -> Result<ClearOnDrop<Vec<u8>>, Error> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        send(&mut stream, request)?;
+        let response = receive(&mut stream)?;
+        if response.is_empty() {
+            return Err(Error::protocol());
+        }
+        match response[0] {
+            RESPONSE_OK => {
+                // Copy the payload instead of shifting it, which would leave its
+                // last byte behind the end.
+                let mut payload = ClearOnDrop::new(Vec::with_capacity(response.len() - 1));
+                payload.extend_from_slice(&response[1..]);
+                Ok(payload)
+            },
+            RESPONSE_ERROR => Err(Error {
+                message: format!("agent refused request: {}",
+                                 String::from_utf8_lossy(&response[1..])),
+            }),
+            _ => Err(Error::protocol()),
+        }
+    }
+
+    /// Return the full name of the user whose master keys the agent holds.
+    pub fn full_name(&self) -> Result<String, Error> {
+        let response = self.request(&[REQUEST_FULL_NAME])?;
+        String::from_utf8(response.to_vec()).map_err(|_| Error::protocol())
+    }
+
+    /// Ask the agent for the ID of the latest master key.
+    pub fn key_id(&self) -> Result<String, Error> {
+        let response = self.request(&[REQUEST_KEY_ID])?;
+        String::from_utf8(response.to_vec()).map_err(|_| Error::protocol())
+    }
+
+    /// Let the agent derive the key of a site.
+    pub fn site_key(&self, site_name: &[u8], site_counter: u32, site_variant: SiteVariant,
+            site_context: &[u8], version: AlgorithmVersion)
+        -> Result<SecretBox<[u8; 32]>, Error>
+    {
+        let mut request = vec![REQUEST_SITE_KEY];
+        write_bytes(&mut request, site_name);
+        request.write_u32::<BigEndian>(site_counter).unwrap();
+        //^ This unwrap is safe, because writing to a vector cannot fail.
+        request.push(variant_to_u8(site_variant));
+        write_bytes(&mut request, site_context);
+        request.push(version.to_u32() as u8);
+        let response = self.request(&request)?;
+        if response.len() != 32 {
+            return Err(Error::protocol());
+        }
+        let mut key = SecretBox::new([0; 32]);
+        key.borrow_mut().copy_from_slice(&response);
+        Ok(key)
+    }
+
+    /// Let the agent encrypt data using the latest master key, binding it to
+    /// the associated data.
+    pub fn encrypt(&self, clear_text: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, Error> {
+        // Reserve enough capacity, so that the clear text is not reallocated.
+        let mut request = ClearOnDrop::new(
+            Vec::with_capacity(clear_text.len() + associated_data.len() + 9));
+        request.push(REQUEST_ENCRYPT);
+        write_bytes(&mut request, clear_text);
+        write_bytes(&mut request, associated_data);
+        let response = self.request(&request)?;
+        Ok(response.to_vec())
+    }
+
+    /// Let the agent decrypt data using the latest master key, checking that
+    /// it is bound to the associated data.
+    pub fn decrypt(&self, cipher_text: &[u8], associated_data: &[u8])
+        -> Result<ClearOnDrop<Vec<u8>>, Error>
+    {
+        let mut request = vec![REQUEST_DECRYPT];
+        write_bytes(&mut request, cipher_text);
+        write_bytes(&mut request, associated_data);
+        self.request(&request)
+    }
+
+    /// Return the password of a site, either generated or decrypted by the
+    /// agent, like `MasterKey::password_for_site`.
+    pub fn password_for_site(&self, site: &Site, templates: &Templates)
+        -> Result<SecretString, Error>
+    {
+        if site.type_ != SiteType::Stored {
+            let key = self.site_key(site.name.as_bytes(), site.counter, site.variant,
+                                    site.context.as_bytes(), site.algorithm)?;
+            return Ok(password_for_site_key(&key.borrow(), &site.type_, site.algorithm, templates,
+                                            &site.policy)?);
+        }
+        let encrypted = site.decode_stored()?;
+        let decrypted = self.decrypt(&encrypted.borrow(), &site_associated_data(site)?)?;
+        Ok(stored_password(&decrypted)?)
+    }
+
+    /// Let the agent encrypt a password to be stored for a site, like
+    /// `MasterKey::encrypt_password`.
+    pub fn encrypt_password(&self, password: &str, site: &Site) -> Result<String, Error> {
+        let encrypted = self.encrypt(password.as_bytes(), &site_associated_data(site)?)?;
+        Ok(base64::encode(&encrypted))
+    }
+}
+
+/// Process a request using the given master keys.
+///
+/// Returns the payload of the response or an error message.
+fn process(request: &[u8], full_name: &str, keys: &MasterKeyCache)
+    -> Result<ClearOnDrop<Vec<u8>>, String>
+{
+    let invalid = |_| "invalid request".to_string();
+    let master_key = |version| match keys.get(full_name.as_bytes(), version) {
+        Ok(Some(key)) => Ok(key),
+        _ => Err("master key not available".to_string()),
+    };
+    let mut message = Cursor::new(request);
+    let request_type = message.read_u8().map_err(invalid)?;
+    match request_type {
+        REQUEST_FULL_NAME => Ok(ClearOnDrop::new(full_name.as_bytes().to_vec())),
+        REQUEST_KEY_ID => {
+            let key_id = key_id(&*master_key(AlgorithmVersion::latest())?);
+            Ok(ClearOnDrop::new(key_id.into_bytes()))
+        },
+        REQUEST_SITE_KEY => {
+            let site_name = read_bytes(&mut message).map_err(invalid)?;
+            let counter = message.read_u32::<BigEndian>().map_err(invalid)?;
+            let variant = variant_from_u8(message.read_u8().map_err(invalid)?)
+                .ok_or("invalid site variant")?;
+            let context = read_bytes(&mut message).map_err(invalid)?;
+            let version = AlgorithmVersion::from_u32(u32::from(message.read_u8().map_err(invalid)?))
+                .ok_or("invalid algorithm version")?;
+            let key = site_key(&*master_key(version)?, site_name, counter, variant, context, version)
+                .map_err(|e| e.message)?;
+            let key = key.borrow();
+            Ok(ClearOnDrop::new(key.to_vec()))
+        },
+        REQUEST_ENCRYPT => {
+            let clear_text = read_bytes(&mut message).map_err(invalid)?;
+            let associated_data = read_bytes(&mut message).map_err(invalid)?;
+            let mut buffer = vec![0; min_buffer_len(clear_text.len())];
+            encrypt(clear_text, &*master_key(AlgorithmVersion::latest())?, associated_data,
+                    &mut buffer)
+                .map_err(|e| e.message)?;
+            Ok(ClearOnDrop::new(buffer))
+        },
+        REQUEST_DECRYPT => {
+            let cipher_text = read_bytes(&mut message).map_err(invalid)?;
+            let associated_data = read_bytes(&mut message).map_err(invalid)?;
+            let mut buffer = SecretVec::from_slice(cipher_text);
+            let mut buffer = buffer.borrow_mut();
+            let clear_text = decrypt(&*master_key(AlgorithmVersion::latest())?, associated_data,
+                                     &mut buffer)
+                .map_err(|e| e.message)?;
+            Ok(ClearOnDrop::new(clear_text.to_vec()))
+        },
+        _ => Err("unknown request".into()),
+    }
+}
+
+/// Return the user ID of the process on the other end of the stream.
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let return_code = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if return_code != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// Return the user ID of the process on the other end of the stream.
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    let return_code = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if return_code != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Answer the request of a single client.
+fn handle(mut stream: UnixStream, full_name: &str, keys: &MasterKeyCache) -> io::Result<()> {
+    // Like ssh-agent, only talk to processes of the same user.
+    let uid = unsafe { libc::geteuid() };
+    if peer_uid(&stream)? != uid {
+        return Ok(());
+    }
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let request = receive(&mut stream)?;
+    let response = match process(&request, full_name, keys) {
+        Ok(payload) => {
+            // Reserve enough capacity, so that the payload is not reallocated.
+            let mut response = ClearOnDrop::new(Vec::with_capacity(payload.len() + 1));
+            response.push(RESPONSE_OK);
+            response.extend_from_slice(&payload);
+            response
+        },
+        Err(message) => {
+            let mut response = vec![RESPONSE_ERROR];
+            response.extend_from_slice(message.as_bytes());
+            ClearOnDrop::new(response)
+        },
+    };
+    send(&mut stream, &response)
+}
+
+/// Answer requests until the agent was idle for the given duration.
+fn serve(listener: &UnixListener, full_name: &str, keys: &MasterKeyCache,
+        idle_timeout: Duration)
+    -> io::Result<()>
+{
+    listener.set_nonblocking(true)?;
+    let mut last_request = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Errors are the client's problem, the agent keeps running.
+                let _ = handle(stream, full_name, keys);
+                last_request = Instant::now();
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last_request.elapsed() >= idle_timeout {
+                    return Ok(());
+                }
+                thread::sleep(POLL_INTERVAL);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Create a private directory for the socket and bind it.
+fn bind() -> io::Result<(PathBuf, UnixListener)> {
+    let base = env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let dir = base.join(format!("mpw-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let path = dir.join("agent.sock");
+    let listener = UnixListener::bind(&path)?;
+    Ok((path, listener))
+}
+
+/// Remove the socket and its directory.
+fn unbind(path: &Path) {
+    let _ = fs::remove_file(path);
+    if let Some(dir) = path.parent() {
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+/// Fork into the background, detached from the terminal.
+///
+/// Returns the process ID of the child in the parent and None in the child.
+/// The child locks the memory of its secrets again.
+fn daemonize() -> io::Result<Option<libc::pid_t>> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            relock_after_fork();
+            unsafe {
+                libc::setsid();
+                let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+                if null >= 0 {
+                    libc::dup2(null, 0);
+                    libc::dup2(null, 1);
+                    libc::dup2(null, 2);
+                    libc::close(null);
+                }
+            }
+            Ok(None)
+        },
+        pid => Ok(Some(pid)),
+    }
+}
+
+/// Run an agent for the given user.
+///
+/// Prints the commands to set up the environment for clients, so that it can
+/// be used like `eval $(mpw agent)`. Unless `foreground` is set, the agent
+/// forks into the background.
+pub fn run(full_name: &str, master_password: ClearOnDrop<String>, idle_timeout: Duration,
+        foreground: bool)
+    -> Result<(), Error>
+{
+    let (path, listener) = bind()?;
+    let pid = if foreground {
+        process::id() as libc::pid_t
+    } else {
+        match daemonize() {
+            Ok(Some(pid)) => pid,
+            Ok(None) => 0,
+            Err(e) => {
+                unbind(&path);
+                return Err(e.into());
+            },
+        }
+    };
+    if pid != 0 {
+        println!("{}={}; export {};", SOCKET_ENV_VAR, path.display(), SOCKET_ENV_VAR);
+        println!("{}={}; export {};", PID_ENV_VAR, pid, PID_ENV_VAR);
+        println!("echo Agent pid {};", pid);
+        if !foreground {
+            // The child owns the socket now.
+            return Ok(());
+        }
+    }
+
+    // All versions are derived in advance, because the master password is not
+    // kept.
+    let mut keys = MasterKeyCache::new();
+    let derived = [AlgorithmVersion::V0, AlgorithmVersion::V1, AlgorithmVersion::V2,
+                   AlgorithmVersion::V3].iter()
+        .map(|&version| keys.get_or_derive(
+            full_name.as_bytes(), master_password.as_bytes(), version).map(|_| ()))
+        .collect::<Result<Vec<()>, _>>();
+    drop(master_password);
+    let result = match derived {
+        Ok(_) => serve(&listener, full_name, &keys, idle_timeout).map_err(Error::from),
+        Err(e) => Err(Error::from(e)),
+    };
+    unbind(&path);
+    result
+}
+
+#[test]
+fn test_agent() {
+    use mpw::algorithm::{Policy, password_for_site};
+    use mpw::config::SiteConfig;
+
+    let full_name = "Max Müller";
+    let mut keys = MasterKeyCache::new();
+    keys.get_or_derive(full_name.as_bytes(), b"passwort", AlgorithmVersion::V3).unwrap();
+    let master_key = *keys.get(full_name.as_bytes(), AlgorithmVersion::V3).unwrap().unwrap();
+
+    let dir = env::temp_dir().join(format!("mpw-test-agent-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+    let path = dir.join("agent.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || {
+        serve(&listener, full_name, &keys, Duration::from_millis(500)).unwrap();
+    });
+
+    let client = Client::new(&path);
+    assert_eq!(client.full_name().unwrap(), full_name);
+    assert_eq!(client.key_id().unwrap(), key_id(&master_key));
+
+    let site_key = client.site_key(
+        b"google.com", 1, SiteVariant::Password, b"", AlgorithmVersion::V3).unwrap();
+    let templates = Templates::new();
+    let password = password_for_site_key(
+        &site_key.borrow(), &SiteType::GeneratedLong, AlgorithmVersion::V3, &templates,
+        &Policy::default())
+        .unwrap();
+    let expected = password_for_site(
+        &master_key, b"google.com", &SiteType::GeneratedLong, 1, SiteVariant::Password, b"",
+        AlgorithmVersion::V3, &templates, &Policy::default()).unwrap();
+    assert_eq!(*password.borrow(), *expected.borrow());
+
+    let encrypted = client.encrypt(b"secret", b"github.com").unwrap();
+    assert_eq!(&client.decrypt(&encrypted, b"github.com").unwrap()[..], b"secret");
+    assert!(client.decrypt(&encrypted, b"gitlab.com").unwrap_err().message
+            .starts_with("agent refused request: "));
+
+    let mut stored = SiteConfig::new("github.com");
+    stored.type_ = Some(SiteType::Stored);
+    let encrypted = client.encrypt_password("secret", &Site::from_config(&stored).unwrap())
+        .unwrap();
+    stored.encrypted = Some(encrypted.into());
+    let site = Site::from_config(&stored).unwrap();
+    assert_eq!(&*client.password_for_site(&site, &templates).unwrap().borrow(), "secret");
+
+    // The key for older versions was not derived.
+    let e = client.site_key(
+        b"google.com", 1, SiteVariant::Password, b"", AlgorithmVersion::V0);
+    assert_eq!(e.unwrap_err().message, "agent refused request: master key not available");
+
+    server.join().unwrap();
+    unbind(&path);
+}
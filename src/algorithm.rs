@@ -9,19 +9,19 @@ extern crate byteorder;
 
 use std::convert::{TryInto, TryFrom};
 use std::cmp::max;
+use std::collections::HashMap;
 use std::io;
 use std::io::Write;
 use std::error::Error as StdError;
 use std::fmt;
 
-use self::ring::{aead, digest, hmac, rand};
+use self::ring::{aead, digest, hkdf, hmac, rand};
 use self::ring::rand::{SecureRandom, SystemRandom};
 use self::ring_pwhash::scrypt::{scrypt, ScryptParams};
 use self::data_encoding::hex;
 use self::byteorder::{BigEndian, WriteBytesExt};
-use self::conv::ValueInto;
 
-use clear_on_drop::ClearOnDrop;
+use secret::{Ref, SecretBox, SecretString};
 
 lazy_static! {
     /// Scrypt parameters used by the Master Password algorithm.
@@ -43,6 +43,7 @@ impl SiteVariant {
     /// Try to construct a SiteVariant from a string.
     ///
     /// Returns None if the string does not correspond to a variant.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<SiteVariant> {
         match s {
             "p" | "password"
@@ -93,7 +94,7 @@ impl<'de> ::serde::Deserialize<'de> for SiteVariant {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 /// Type of the site password.
 pub enum SiteType {
     GeneratedMaximum,
@@ -105,12 +106,20 @@ pub enum SiteType {
     GeneratedName,
     GeneratedPhrase,
     Stored,
+    /// A template defined in the configuration.
+    Custom(String),
 }
 
+/// The names of the built-in site types, as they are serialized.
+const BUILTIN_TYPE_NAMES: &[&str] = &[
+    "maximum", "long", "medium", "basic", "short", "pin", "name", "phrase", "stored"
+];
+
 impl SiteType {
     /// Try to construct a SiteType from a string.
     ///
     /// Returns None if the string does not correspond to a variant.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<SiteType> {
         match s {
             "x" | "max" | "maximum"
@@ -134,6 +143,14 @@ impl SiteType {
             _ => None,
         }
     }
+
+    /// Construct a SiteType from a string.
+    ///
+    /// Strings that do not correspond to a built-in type refer to a custom
+    /// template.
+    pub fn from_name(s: &str) -> SiteType {
+        SiteType::from_str(s).unwrap_or_else(|| SiteType::Custom(s.into()))
+    }
 }
 
 impl ::serde::Serialize for SiteType {
@@ -150,6 +167,7 @@ impl ::serde::Serialize for SiteType {
             SiteType::GeneratedName => "name",
             SiteType::GeneratedPhrase => "phrase",
             SiteType::Stored => "stored",
+            SiteType::Custom(ref name) => name,
         })
     }
 }
@@ -164,14 +182,13 @@ impl<'de> ::serde::Deserialize<'de> for SiteType {
             type Value = SiteType;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                write!(formatter, r#"one of the following strings: "x", "max", "maximum", "l", "long", "m", "med", "medium", "b", "basic", "s", "short", "i", "pin", "n", "name", "p", "phrase", "stored""#)
+                write!(formatter, r#"one of the following strings: "x", "max", "maximum", "l", "long", "m", "med", "medium", "b", "basic", "s", "short", "i", "pin", "n", "name", "p", "phrase", "stored" or the name of a custom template"#)
             }
 
             fn visit_str<E>(self, value: &str) -> Result<SiteType, E>
                 where E: ::serde::de::Error
             {
-                SiteType::from_str(value)
-                    .ok_or_else(|| E::invalid_value(::serde::de::Unexpected::Str(value), &self))
+                Ok(SiteType::from_name(value))
             }
         }
 
@@ -179,6 +196,102 @@ impl<'de> ::serde::Deserialize<'de> for SiteType {
     }
 }
 
+/// Version of the Master Password algorithm.
+///
+/// Older versions are only supported to reproduce passwords that were
+/// created with them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlgorithmVersion {
+    /// Interprets the bytes of the site key as signed characters when
+    /// encoding the password, and measures names in characters.
+    V0,
+    /// Measures the full name and the site name in characters.
+    V1,
+    /// Measures the full name in characters.
+    V2,
+    /// The current version, measures everything in bytes.
+    V3,
+}
+
+impl AlgorithmVersion {
+    /// Return the latest version of the algorithm.
+    pub fn latest() -> AlgorithmVersion {
+        AlgorithmVersion::V3
+    }
+
+    /// Try to construct an AlgorithmVersion from its number.
+    ///
+    /// Returns None if there is no such version.
+    pub fn from_u32(n: u32) -> Option<AlgorithmVersion> {
+        match n {
+            0 => Some(AlgorithmVersion::V0),
+            1 => Some(AlgorithmVersion::V1),
+            2 => Some(AlgorithmVersion::V2),
+            3 => Some(AlgorithmVersion::V3),
+            _ => None,
+        }
+    }
+
+    /// Try to construct an AlgorithmVersion from a string.
+    ///
+    /// Returns None if the string does not correspond to a version.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<AlgorithmVersion> {
+        let s = s.strip_prefix('v').unwrap_or(s);
+        s.parse().ok().and_then(AlgorithmVersion::from_u32)
+    }
+
+    /// Return the number of the version.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            AlgorithmVersion::V0 => 0,
+            AlgorithmVersion::V1 => 1,
+            AlgorithmVersion::V2 => 2,
+            AlgorithmVersion::V3 => 3,
+        }
+    }
+}
+
+impl ::serde::Serialize for AlgorithmVersion {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ::serde::Serializer
+    {
+        serializer.serialize_u32(self.to_u32())
+    }
+}
+
+impl<'de> ::serde::Deserialize<'de> for AlgorithmVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        struct Visitor;
+
+        impl<'de> ::serde::de::Visitor<'de> for Visitor {
+            type Value = AlgorithmVersion;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "one of the following integers: 0, 1, 2, 3")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<AlgorithmVersion, E>
+                where E: ::serde::de::Error
+            {
+                u32::try_from(value).ok().and_then(AlgorithmVersion::from_u32)
+                    .ok_or_else(|| E::invalid_value(::serde::de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<AlgorithmVersion, E>
+                where E: ::serde::de::Error
+            {
+                u32::try_from(value).ok().and_then(AlgorithmVersion::from_u32)
+                    .ok_or_else(|| E::invalid_value(::serde::de::Unexpected::Unsigned(value), &self))
+            }
+        }
+
+        deserializer.deserialize_u32(Visitor)
+    }
+}
+
 /// Represent a password variant as a string.
 fn scope_for_variant(variant: SiteVariant) -> &'static str {
     match variant {
@@ -189,7 +302,7 @@ fn scope_for_variant(variant: SiteVariant) -> &'static str {
 }
 
 /// Master Password algorithm kind of error.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     /// An `std::io::Error` occured.
     Io,
@@ -199,6 +312,34 @@ pub enum ErrorKind {
     SiteNameTooLong,
     /// The site context was longer than 2^32 bytes.
     SiteContextTooLong,
+    /// A site refered to a template that is not defined.
+    UnknownTemplate,
+    /// A template cannot be used to generate passwords.
+    InvalidTemplate,
+    /// A character class cannot be defined.
+    InvalidCharacterClass,
+    /// No password of the site type can satisfy the site's policy.
+    UnsatisfiablePolicy,
+    /// A stored password was encrypted for a different site.
+    WrongSite,
+    /// A stored password was encrypted before passwords were bound to their
+    /// site, so it could have been copied from a different one.
+    Unbound,
+    /// A stored password could not be authenticated, because it was
+    /// encrypted with a different key or has been tampered with.
+    AuthenticationFailed,
+    /// A stored password is too short to have been encrypted.
+    TruncatedCipherText,
+    /// A decrypted password was not padded correctly.
+    BadPadding,
+    /// A password could not be encrypted.
+    EncryptionFailed,
+    /// A master key was used with an incompatible algorithm version.
+    WrongVersion,
+    /// The stored password of a site is missing or not properly encoded.
+    InvalidStoredPassword,
+    /// A stored password was encrypted using an unknown format version.
+    UnsupportedFormat,
 }
 
 /// Master Password algorithm error.
@@ -209,6 +350,18 @@ pub struct Error {
     pub kind: ErrorKind,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
         let message = match kind {
@@ -216,53 +369,146 @@ impl From<ErrorKind> for Error {
             ErrorKind::FullNameTooLong => "full name too long",
             ErrorKind::SiteNameTooLong => "site name too long",
             ErrorKind::SiteContextTooLong => "site context too long",
+            ErrorKind::UnknownTemplate => "unknown template",
+            ErrorKind::InvalidTemplate => "invalid template",
+            ErrorKind::InvalidCharacterClass => "invalid character class",
+            ErrorKind::UnsatisfiablePolicy => "password policy cannot be satisfied",
+            ErrorKind::WrongSite => "stored password belongs to a different site",
+            ErrorKind::Unbound => "stored password is not bound to its site",
+            ErrorKind::AuthenticationFailed
+                => "wrong master password or stored password has been tampered with",
+            ErrorKind::TruncatedCipherText => "stored password is truncated",
+            ErrorKind::BadPadding => "stored password has invalid padding",
+            ErrorKind::EncryptionFailed => "failed to encrypt password",
+            ErrorKind::WrongVersion => "master key of wrong algorithm version",
+            ErrorKind::InvalidStoredPassword => "invalid stored password",
+            ErrorKind::UnsupportedFormat => "unsupported format of stored password",
         };
-        Error { message: message.into(), kind: kind }
+        Error { message: message.into(), kind }
+    }
+}
+
+impl Error {
+    /// Create an error for a character class that is not defined.
+    fn unknown_class(class: char) -> Error {
+        Error {
+            message: format!("unknown character class '{}'", class),
+            kind: ErrorKind::InvalidTemplate,
+        }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error {
-            message: e.description().into(),
+            message: e.to_string(),
             kind: ErrorKind::Io,
         }
     }
 }
 
-/// Derive a master key from a full name and a master password.
-pub fn master_key_for_user_v3(full_name: &[u8], master_password: &[u8])
-    -> Result<ClearOnDrop<[u8; 64]>, Error>
-{
+/// Count the characters of a UTF-8 encoded buffer.
+///
+/// Versions of the algorithm before v3 accidentally used this instead of the
+/// number of bytes.
+fn utf8_char_count(buf: &[u8]) -> usize {
+    buf.iter().filter(|&&b| b & 0xc0 != 0x80).count()
+}
+
+/// Calculate the salt used to derive the master key of a user.
+///
+/// Different algorithm versions with the same salt derive the same master key.
+pub(crate) fn master_key_salt(full_name: &[u8], version: AlgorithmVersion) -> Result<Vec<u8>, Error> {
     let mut master_key_salt = Vec::new();
     master_key_salt.write_all(scope_for_variant(SiteVariant::Password).as_bytes())?;
-    let master_key_salt_len = full_name.len().try_into().map_err(|_|
+    let full_name_len = if version < AlgorithmVersion::V3 {
+        utf8_char_count(full_name)
+    } else {
+        full_name.len()
+    };
+    let master_key_salt_len = full_name_len.try_into().map_err(|_|
         Error::from(ErrorKind::FullNameTooLong))?;
     master_key_salt.write_u32::<BigEndian>(master_key_salt_len)?;
     master_key_salt.write_all(full_name)?;
     assert!(!master_key_salt.is_empty());
+    Ok(master_key_salt)
+}
 
-    let mut master_key = ClearOnDrop::new([0; 64]);
-    scrypt(master_password, &master_key_salt, &SCRYPT_PARAMS, &mut *master_key);
+/// Derive a master key from a full name and a master password.
+pub fn master_key_for_user(full_name: &[u8], master_password: &[u8], version: AlgorithmVersion)
+    -> Result<SecretBox<[u8; 64]>, Error>
+{
+    let master_key_salt = master_key_salt(full_name, version)?;
+
+    let mut master_key = SecretBox::new([0; 64]);
+    scrypt(master_password, &master_key_salt, &SCRYPT_PARAMS, &mut *master_key.borrow_mut());
 
     Ok(master_key)
 }
 
-/// Deterministially generate a password for a site.
-pub fn password_for_site_v3(master_key: &[u8; 64], site_name: &[u8], site_type: SiteType,
-        site_counter: u32, site_variant: SiteVariant, site_context: &[u8])
-    -> Result<ClearOnDrop<String>, Error>
+/// Cache of the master keys of a user for different algorithm versions.
+///
+/// Deriving a master key is expensive, so it is only done once for all
+/// versions that use the same salt.
+#[derive(Default)]
+pub struct MasterKeyCache {
+    keys: Vec<(Vec<u8>, SecretBox<[u8; 64]>)>,
+}
+
+impl MasterKeyCache {
+    /// Create an empty cache.
+    pub fn new() -> MasterKeyCache {
+        MasterKeyCache { keys: Vec::new() }
+    }
+
+    /// Return the cached master key for the given version, if any.
+    pub fn get(&self, full_name: &[u8], version: AlgorithmVersion)
+        -> Result<Option<Ref<'_, [u8; 64]>>, Error>
+    {
+        let salt = master_key_salt(full_name, version)?;
+        Ok(self.keys.iter().find(|(s, _)| *s == salt).map(|(_, key)| key.borrow()))
+    }
+
+    /// Return the master key for the given version, deriving it if necessary.
+    pub fn get_or_derive(&mut self, full_name: &[u8], master_password: &[u8],
+            version: AlgorithmVersion)
+        -> Result<Ref<'_, [u8; 64]>, Error>
+    {
+        let salt = master_key_salt(full_name, version)?;
+        let index = match self.keys.iter().position(|(s, _)| *s == salt) {
+            Some(i) => i,
+            None => {
+                let key = master_key_for_user(full_name, master_password, version)?;
+                self.keys.push((salt, key));
+                self.keys.len() - 1
+            },
+        };
+        Ok(self.keys[index].1.borrow())
+    }
+}
+
+/// Deterministically derive the key of a site from which its password is generated.
+pub fn site_key(master_key: &[u8; 64], site_name: &[u8], site_counter: u32,
+        site_variant: SiteVariant, site_context: &[u8], version: AlgorithmVersion)
+    -> Result<SecretBox<[u8; 32]>, Error>
 {
+    // Before v2, names were measured in characters instead of bytes.
+    let len = |buf: &[u8]| if version < AlgorithmVersion::V2 {
+        utf8_char_count(buf)
+    } else {
+        buf.len()
+    };
+
     let mut site_password_salt = Vec::new();
     let site_scope = scope_for_variant(site_variant).as_bytes();
     site_password_salt.write_all(site_scope)?;
-    let site_name_len = site_name.len().try_into().map_err(|_|
+    let site_name_len = len(site_name).try_into().map_err(|_|
         Error::from(ErrorKind::SiteNameTooLong))?;
     site_password_salt.write_u32::<BigEndian>(site_name_len)?;
     site_password_salt.write_all(site_name)?;
     site_password_salt.write_u32::<BigEndian>(site_counter)?;
     if !site_context.is_empty() {
-        let site_context_len = site_context.len().try_into().map_err(|_|
+        let site_context_len = len(site_context).try_into().map_err(|_|
             Error::from(ErrorKind::SiteContextTooLong))?;
         site_password_salt.write_u32::<BigEndian>(site_context_len)?;
         site_password_salt.write_all(site_context)?;
@@ -271,42 +517,331 @@ pub fn password_for_site_v3(master_key: &[u8; 64], site_name: &[u8], site_type:
 
     let signing_key = hmac::SigningKey::new(&digest::SHA256, master_key);
     let digest = hmac::sign(&signing_key, &site_password_salt);
-    let site_password_seed = digest.as_ref();
-    debug_assert!(!site_password_seed.is_empty());
+    let mut site_key = SecretBox::new([0; 32]);
+    site_key.borrow_mut().copy_from_slice(digest.as_ref());
+
+    Ok(site_key)
+}
+
+/// Deterministially generate a password for a site, satisfying the site's policy.
+#[allow(clippy::too_many_arguments)]
+pub fn password_for_site(master_key: &[u8; 64], site_name: &[u8], site_type: &SiteType,
+        site_counter: u32, site_variant: SiteVariant, site_context: &[u8],
+        version: AlgorithmVersion, templates: &Templates, policy: &Policy)
+    -> Result<SecretString, Error>
+{
+    let site_key = site_key(
+        master_key, site_name, site_counter, site_variant, site_context, version)?;
+    let site_key = site_key.borrow();
+    password_for_site_key(&site_key, site_type, version, templates, policy)
+}
 
+/// Generate the password of a site from its key, satisfying the site's policy.
+pub fn password_for_site_key(site_key: &[u8; 32], site_type: &SiteType,
+        version: AlgorithmVersion, templates: &Templates, policy: &Policy)
+    -> Result<SecretString, Error>
+{
     // Encode the password from the seed using the template.
-    let site_password = generate_password(site_type, &site_password_seed);
+    generate_password(site_type, site_key, version, templates, policy)
+}
 
-    Ok(site_password)
+/// Interpret a byte of the password seed as the given algorithm version does.
+///
+/// Version 0 converted the signed seed bytes to big-endian 16-bit integers on
+/// little-endian machines. Later versions simply use the unsigned byte.
+fn seed_value(seed_byte: u8, version: AlgorithmVersion) -> u16 {
+    if version == AlgorithmVersion::V0 {
+        (seed_byte as i8 as i16 as u16).swap_bytes()
+    } else {
+        u16::from(seed_byte)
+    }
 }
 
 /// Generate a password for the given site type from a given seed.
-fn generate_password(site_type: SiteType, seed: &[u8]) -> ClearOnDrop<String> {
-    let template = template_for_type(site_type, seed[0]);
-    if template.len() >= seed.len() {
-        panic!(format!("template too long for given password seed: {} >= {}",
-                       template.len(), seed.len()));
+///
+/// If the password does not satisfy the policy, the seed is deterministically
+/// re-keyed until it does. Passwords that already satisfy the policy are the
+/// same as without one.
+fn generate_password(site_type: &SiteType, seed: &[u8], version: AlgorithmVersion,
+        templates: &Templates, policy: &Policy)
+    -> Result<SecretString, Error>
+{
+    let choices = templates.templates_for_type(site_type)?;
+    if !policy.is_satisfiable(&choices, templates) {
+        return Err(Error {
+            message: "no template of the site type can satisfy the password policy".into(),
+            kind: ErrorKind::UnsatisfiablePolicy,
+        });
     }
-    let mut password = ClearOnDrop::new(String::with_capacity(template.len()));
-    for (i, c) in template.chars().enumerate() {
-        password.push(
-            character_from_class(c, seed[i + 1])
-        );
+    let mut rekeyed = SecretBox::new([0; 32]);
+    for attempt in 0..MAX_POLICY_ATTEMPTS {
+        if attempt > 0 {
+            let signing_key = hmac::SigningKey::new(&digest::SHA256, seed);
+            let mut message = Vec::with_capacity(POLICY_SCOPE.len() + 4);
+            message.extend_from_slice(POLICY_SCOPE);
+            message.write_u32::<BigEndian>(attempt)?;
+            rekeyed.borrow_mut().copy_from_slice(hmac::sign(&signing_key, &message).as_ref());
+        }
+        let rekeyed_seed = rekeyed.borrow();
+        let seed = if attempt == 0 { seed } else { &rekeyed_seed[..] };
+        let template = template_for_type(&choices, seed_value(seed[0], version));
+        let password = encode_template(template, seed, version, templates)?;
+        if policy.is_satisfied_by(&password.borrow()) {
+            return Ok(password);
+        }
     }
+    Err(Error {
+        message: format!("password policy not satisfied after {} attempts",
+                         MAX_POLICY_ATTEMPTS),
+        kind: ErrorKind::UnsatisfiablePolicy,
+    })
+}
 
-    password
+/// Scope used to derive a new seed if a password does not satisfy the policy.
+const POLICY_SCOPE: &[u8] = b"com.lyndir.masterpassword.policy";
+/// Maximal number of seeds to try to satisfy a policy.
+const MAX_POLICY_ATTEMPTS: u32 = 1000;
+
+/// Encode a password from a seed using the given template.
+fn encode_template(template: &str, seed: &[u8], version: AlgorithmVersion,
+        templates: &Templates)
+    -> Result<SecretString, Error>
+{
+    let len = template.chars().count();
+    if len >= seed.len() {
+        return Err(Error {
+            message: format!("template too long for given password seed: {} >= {}",
+                             len, seed.len()),
+            kind: ErrorKind::InvalidTemplate,
+        });
+    }
+    if let Some(c) = template.chars().find(|&c| templates.characters_in_class(c).is_none()) {
+        return Err(Error::unknown_class(c));
+    }
+    let password = template.chars().enumerate().map(|(i, c)| {
+        let class_chars = templates.characters_in_class(c).unwrap();
+        //^ This unwrap is safe, because the classes were checked above.
+        character_from_class(class_chars, seed_value(seed[i + 1], version))
+    });
+
+    Ok(SecretString::from_chars(password))
 }
 
 /// Generate a random password for the given site type.
-pub fn random_password_for_site(rng: &SystemRandom, site_type: SiteType) -> Result<ClearOnDrop<String>, ()> {
-    let mut seed = ClearOnDrop::new(vec![0; 21]);
-    rng.fill(seed.as_mut()).map_err(|_| ())?;
-    Ok(generate_password(site_type, &seed))
+#[allow(clippy::result_unit_err)]
+pub fn random_password_for_site(rng: &SystemRandom, site_type: &SiteType, templates: &Templates)
+    -> Result<SecretString, ()>
+{
+    let mut seed = SecretBox::new([0; SEED_LEN]);
+    rng.fill(&mut seed.borrow_mut()[..]).map_err(|_| ())?;
+    let seed = seed.borrow();
+    generate_password(
+        site_type, &seed[..], AlgorithmVersion::latest(), templates, &Policy::default())
+        .map_err(|_| ())
+}
+
+/// Length of the seed passwords are generated from.
+///
+/// Every character of a template consumes a byte of the seed, and one byte
+/// is used to choose the template.
+const SEED_LEN: usize = 32;
+
+/// Templates and character classes that are defined in addition to the
+/// built-in ones, usually in the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: HashMap<String, Vec<String>>,
+    classes: HashMap<char, String>,
+}
+
+impl Templates {
+    /// Create a set containing only the built-in templates.
+    pub fn new() -> Templates {
+        Templates::default()
+    }
+
+    /// Define a character class that can be used by custom templates.
+    ///
+    /// Built-in classes cannot be redefined.
+    pub fn add_class(&mut self, class: char, characters: &str) -> Result<(), Error> {
+        let invalid = |reason: &str| Error {
+            message: format!("cannot define character class '{}': {}", class, reason),
+            kind: ErrorKind::InvalidCharacterClass,
+        };
+        if builtin_characters_in_class(class).is_some() || self.classes.contains_key(&class) {
+            return Err(invalid("already defined"));
+        }
+        if characters.is_empty() {
+            return Err(invalid("no characters given"));
+        }
+        self.classes.insert(class, characters.into());
+        Ok(())
+    }
+
+    /// Define a named list of templates, which is used like the built-in site
+    /// types.
+    ///
+    /// The templates must only use known character classes and must be short
+    /// enough for the seed of a site.
+    pub fn add_template(&mut self, name: &str, templates: Vec<String>) -> Result<(), Error> {
+        let invalid = |reason: String| Error {
+            message: format!("cannot define template '{}': {}", name, reason),
+            kind: ErrorKind::InvalidTemplate,
+        };
+        if SiteType::from_str(name).is_some() || self.templates.contains_key(name) {
+            return Err(invalid("already defined".into()));
+        }
+        if templates.is_empty() {
+            return Err(invalid("no templates given".into()));
+        }
+        for template in &templates {
+            let len = template.chars().count();
+            if len == 0 {
+                return Err(invalid("empty template".into()));
+            }
+            if len >= SEED_LEN {
+                return Err(invalid(format!("'{}' is longer than {} characters",
+                                           template, SEED_LEN - 1)));
+            }
+            if let Some(c) = template.chars().find(|&c| self.characters_in_class(c).is_none()) {
+                return Err(invalid(format!("unknown character class '{}'", c)));
+            }
+        }
+        self.templates.insert(name.into(), templates);
+        Ok(())
+    }
+
+    /// Fail unless the type is built in or refers to a defined template.
+    ///
+    /// The error lists the names that can be used instead.
+    pub fn check_type(&self, ty: &SiteType) -> Result<(), Error> {
+        let name = match *ty {
+            SiteType::Custom(ref name) if !self.templates.contains_key(name) => name,
+            _ => return Ok(()),
+        };
+        let mut custom: Vec<&str> = self.templates.keys().map(|s| s.as_str()).collect();
+        custom.sort();
+        let known: Vec<&str> = BUILTIN_TYPE_NAMES.iter().cloned().chain(custom).collect();
+        Err(Error {
+            message: format!("unknown template '{}', expected one of: {}", name,
+                             known.join(", ")),
+            kind: ErrorKind::UnknownTemplate,
+        })
+    }
+
+    /// Return the templates to choose from for the given type.
+    fn templates_for_type(&self, ty: &SiteType) -> Result<Vec<&str>, Error> {
+        self.check_type(ty)?;
+        match *ty {
+            SiteType::Custom(ref name) => Ok(self.templates[name].iter()
+                .map(|s| s.as_str()).collect()),
+            SiteType::Stored => Err(Error {
+                message: "stored passwords are not generated".into(),
+                kind: ErrorKind::InvalidTemplate,
+            }),
+            ref ty => Ok(builtin_templates_for_type(ty)),
+        }
+    }
+
+    /// Return all the characters occuring in the given class, if it is defined.
+    fn characters_in_class(&self, class: char) -> Option<&str> {
+        builtin_characters_in_class(class)
+            .or_else(|| self.classes.get(&class).map(|s| s.as_str()))
+    }
+}
+
+/// Kind of character a password policy can require.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CharacterRequirement {
+    /// An uppercase letter.
+    Uppercase,
+    /// A lowercase letter.
+    Lowercase,
+    /// A decimal digit.
+    Digit,
+    /// Anything but a letter, a digit or whitespace.
+    Symbol,
+}
+
+impl CharacterRequirement {
+    /// Whether the given character satisfies the requirement.
+    fn is_satisfied_by(self, c: char) -> bool {
+        match self {
+            CharacterRequirement::Uppercase => c.is_uppercase(),
+            CharacterRequirement::Lowercase => c.is_lowercase(),
+            CharacterRequirement::Digit => c.is_ascii_digit(),
+            CharacterRequirement::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+}
+
+/// Restrictions a site imposes on its passwords.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// Kinds of characters that have to occur at least once.
+    pub required: Option<Vec<CharacterRequirement>>,
+    /// Characters that must not occur.
+    pub forbidden: Option<String>,
+}
+
+impl Policy {
+    /// Whether the policy does not restrict passwords at all.
+    pub fn is_empty(&self) -> bool {
+        *self == Policy::default()
+    }
+
+    /// Return the characters of a class that are not forbidden.
+    fn allowed_characters(&self, class_chars: &str) -> String {
+        class_chars.chars().filter(|&c| !self.forbids(c)).collect()
+    }
+
+    /// Return the kinds of characters that have to occur.
+    fn required(&self) -> &[CharacterRequirement] {
+        self.required.as_deref().unwrap_or(&[])
+    }
+
+    /// Whether the given character must not occur.
+    fn forbids(&self, c: char) -> bool {
+        self.forbidden.as_ref().is_some_and(|forbidden| forbidden.contains(c))
+    }
+
+    /// Whether a password of the given length is allowed.
+    fn allows_length(&self, len: usize) -> bool {
+        self.min_length.is_none_or(|min| len >= min) &&
+        self.max_length.is_none_or(|max| len <= max)
+    }
+
+    /// Whether any of the given templates can generate a password satisfying
+    /// the policy.
+    fn is_satisfiable(&self, choices: &[&str], templates: &Templates) -> bool {
+        choices.iter().any(|template| {
+            if !self.allows_length(template.chars().count()) {
+                return false;
+            }
+            let classes: Vec<String> = template.chars()
+                .map(|c| self.allowed_characters(templates.characters_in_class(c).unwrap_or("")))
+                .collect();
+            !classes.iter().any(|class_chars| class_chars.is_empty()) &&
+            self.required().iter().all(|&requirement| classes.iter().any(|class_chars|
+                class_chars.chars().any(|c| requirement.is_satisfied_by(c))))
+        })
+    }
+
+    /// Whether the password satisfies the policy.
+    fn is_satisfied_by(&self, password: &str) -> bool {
+        self.allows_length(password.chars().count()) &&
+        !password.chars().any(|c| self.forbids(c)) &&
+        self.required().iter().all(|&requirement|
+            password.chars().any(|c| requirement.is_satisfied_by(c)))
+    }
 }
 
 /// Return an array of internal strings that express the template to use for the given type.
-fn templates_for_type(ty: SiteType) -> Vec<&'static str> {
-    match ty {
+fn builtin_templates_for_type(ty: &SiteType) -> Vec<&'static str> {
+    match *ty {
         SiteType::GeneratedMaximum => vec![
             "anoxxxxxxxxxxxxxxxxx", "axxxxxxxxxxxxxxxxxno"
         ],
@@ -336,18 +871,14 @@ fn templates_for_type(ty: SiteType) -> Vec<&'static str> {
         SiteType::GeneratedPhrase => vec![
             "cvcc cvc cvccvcv cvc", "cvc cvccvcvcv cvcv", "cv cvccv cvc cvcvccv",
         ],
-        SiteType::Stored
-            => panic!("Expected generated type"),
+        SiteType::Stored | SiteType::Custom(_)
+            => panic!("Expected built-in generated type"),
     }
 }
 
-/// Return an internal string that contains the password encoding template of the given type.
-fn template_for_type(ty: SiteType, seed_byte: u8) -> &'static str {
-    let templates = templates_for_type(ty);
-    let count = u8::try_from(templates.len()).unwrap();
-    //^ This unwrap is safe, because the templates are hardcoded and much shorter than 256
-    //  characters.
-    templates[usize::from(seed_byte % count)]
+/// Choose the template that encodes the given seed value.
+fn template_for_type<'a>(templates: &[&'a str], seed_value: u16) -> &'a str {
+    templates[usize::from(seed_value) % templates.len()]
 }
 
 /// Return an internal string that contains all the characters occuring in the given class.
@@ -361,40 +892,43 @@ fn template_for_type(ty: SiteType, seed_byte: u8) -> &'static str {
 /// - 'n': digit
 /// - 'o': special symbol
 /// - 'x': letter (any case) or digit or special symbol
-fn characters_in_class(class: char) -> &'static str {
+fn builtin_characters_in_class(class: char) -> Option<&'static str> {
     match class {
-        'V' => "AEIOU",
-        'C' => "BCDFGHJKLMNPQRSTVWXYZ",
-        'v' => "aeiou",
-        'c' => "bcdfghjklmnpqrstvwxyz",
-        'A' => "AEIOUBCDFGHJKLMNPQRSTVWXYZ",
-        'a' => "AEIOUaeiouBCDFGHJKLMNPQRSTVWXYZbcdfghjklmnpqrstvwxyz",
-        'n' => "0123456789",
-        'o' => "@&%?,=[]_:-+*$#!'^~;()/.",
-        'x' => "AEIOUaeiouBCDFGHJKLMNPQRSTVWXYZbcdfghjklmnpqrstvwxyz0123456789!@#$%^&*()",
-        ' ' => " ",
-        _ => panic!("Unknown character class"),
+        'V' => Some("AEIOU"),
+        'C' => Some("BCDFGHJKLMNPQRSTVWXYZ"),
+        'v' => Some("aeiou"),
+        'c' => Some("bcdfghjklmnpqrstvwxyz"),
+        'A' => Some("AEIOUBCDFGHJKLMNPQRSTVWXYZ"),
+        'a' => Some("AEIOUaeiouBCDFGHJKLMNPQRSTVWXYZbcdfghjklmnpqrstvwxyz"),
+        'n' => Some("0123456789"),
+        'o' => Some("@&%?,=[]_:-+*$#!'^~;()/."),
+        'x' => Some("AEIOUaeiouBCDFGHJKLMNPQRSTVWXYZbcdfghjklmnpqrstvwxyz0123456789!@#$%^&*()"),
+        ' ' => Some(" "),
+        _ => None,
     }
 }
 
 /// Calculate the bits of entropy of a given template.
-fn entropy_of_template(template: &str) -> f64 {
+#[cfg(test)]
+fn entropy_of_template(template: &str, templates: &Templates) -> f64 {
+    use self::conv::ValueInto;
+
     let mut bits = 0.;
     for class in template.chars() {
-        let possibilities: f64 = characters_in_class(class).len().value_into()
+        let possibilities: f64 = templates.characters_in_class(class)
+            .expect("unknown character class")
+            .chars().count().value_into()
             .expect("failed to convert `usize` to `f64`");
         bits += possibilities.log2();
     }
     bits
 }
 
-/// Return a character from given character class that encodes the given byte.
-fn character_from_class(class: char, seed_byte: u8) -> char {
-    let class_chars = characters_in_class(class);
-    let index = usize::from(seed_byte % u8::try_from(class_chars.len()).unwrap());
+/// Return a character from given character class that encodes the given seed value.
+fn character_from_class(class_chars: &str, seed_value: u16) -> char {
+    let index = usize::from(seed_value) % class_chars.chars().count();
     class_chars.chars().nth(index).unwrap()
-    //^ These unwraps are save, because the character classes are hardcoded and shorter than 256
-    //  characters.
+    //^ This unwrap is safe, because the index is smaller than the number of characters.
 }
 
 /// Encode a fingerprint for a buffer.
@@ -403,8 +937,79 @@ pub fn id_for_buf(buf: &[u8]) -> String {
     hex::encode(digest.as_ref())
 }
 
+/// Identify a master key, like the `keyID` of the official apps.
+///
+/// This can be stored to detect a mistyped master password.
+pub fn key_id(master_key: &[u8; 64]) -> String {
+    id_for_buf(master_key)
+}
+
+/// Colour of an identicon.
+///
+/// The values are the numbers of the corresponding ANSI terminal colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdenticonColor {
+    Red = 1,
+    Green = 2,
+    Yellow = 3,
+    Blue = 4,
+    Magenta = 5,
+    Cyan = 6,
+    Mono = 7,
+}
+
+impl IdenticonColor {
+    /// Choose the colour encoded by the given seed byte.
+    fn from_seed(seed: u8) -> IdenticonColor {
+        match seed % 7 {
+            0 => IdenticonColor::Red,
+            1 => IdenticonColor::Green,
+            2 => IdenticonColor::Yellow,
+            3 => IdenticonColor::Blue,
+            4 => IdenticonColor::Magenta,
+            5 => IdenticonColor::Cyan,
+            _ => IdenticonColor::Mono,
+        }
+    }
+
+    /// Return the number of the ANSI terminal colour.
+    pub fn ansi(self) -> u8 {
+        self as u8
+    }
+
+    /// Return the name of the colour.
+    pub fn name(self) -> &'static str {
+        match self {
+            IdenticonColor::Red => "red",
+            IdenticonColor::Green => "green",
+            IdenticonColor::Yellow => "yellow",
+            IdenticonColor::Blue => "blue",
+            IdenticonColor::Magenta => "magenta",
+            IdenticonColor::Cyan => "cyan",
+            IdenticonColor::Mono => "mono",
+        }
+    }
+}
+
+/// A visual fingerprint of a user, as shown by the official apps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identicon {
+    pub left_arm: &'static str,
+    pub body: &'static str,
+    pub right_arm: &'static str,
+    pub accessory: &'static str,
+    pub color: IdenticonColor,
+}
+
+impl fmt::Display for Identicon {
+    /// Write the glyphs of the identicon, without colour.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}{}", self.left_arm, self.body, self.right_arm, self.accessory)
+    }
+}
+
 /// Encode a visual fingerprint for a user.
-pub fn identicon(full_name: &[u8], master_password: &[u8]) -> String {
+pub fn identicon(full_name: &[u8], master_password: &[u8]) -> Identicon {
     let left_arm = [ "╔", "╚", "╰", "═" ];
     let right_arm = [ "╗", "╝", "╯", "═" ];
     let body = [ "█", "░", "▒", "▓", "☺", "☻" ];
@@ -419,18 +1024,17 @@ pub fn identicon(full_name: &[u8], master_password: &[u8]) -> String {
     let digest = hmac::sign(&signing_key, full_name);
     let identicon_seed = digest.as_ref();
 
-    // TODO color
-
     let get_part = |set: &[&'static str], seed: u8| {
         set[usize::from(seed % u8::try_from(set.len()).unwrap())]
         //^ This unwrap is safe, because the sets are short and hardcoded above.
     };
-    let mut identicon = String::with_capacity(256);
-    identicon.push_str(get_part(&left_arm[..], identicon_seed[0]));
-    identicon.push_str(get_part(&body[..], identicon_seed[1]));
-    identicon.push_str(get_part(&right_arm[..], identicon_seed[2]));
-    identicon.push_str(get_part(&accessory[..], identicon_seed[3]));
-    identicon
+    Identicon {
+        left_arm: get_part(&left_arm[..], identicon_seed[0]),
+        body: get_part(&body[..], identicon_seed[1]),
+        right_arm: get_part(&right_arm[..], identicon_seed[2]),
+        accessory: get_part(&accessory[..], identicon_seed[3]),
+        color: IdenticonColor::from_seed(identicon_seed[4]),
+    }
 }
 
 /// Length of the nonce of the used encryption algorithm (chacha20).
@@ -441,14 +1045,91 @@ const NONCE_LEN: usize = 12;
 /// Note that this has to be smaller than 256 due to how he padding is done.
 const PAD_LEN: usize = 20;
 
+/// Minimal length of a nonce followed by sealed, padded data.
+const MIN_SEALED_LEN: usize = NONCE_LEN + PAD_LEN + aead::MAX_TAG_LEN;
+
 /// Calculate the length of the clear text after padding.
 fn padded_len(clear_text_len: usize) -> usize {
     max(clear_text_len + 1, PAD_LEN)
 }
 
+/// Identifies encrypted data in the versioned format.
+///
+/// Data without it was encrypted without associated data.
+const BLOB_MAGIC: &[u8] = b"MPW";
+/// Version of the format of encrypted data.
+///
+/// Since version 2, the data is encrypted with the storage key instead of
+/// the master key.
+const BLOB_VERSION: u8 = 2;
+/// Version of the format that encrypted data with the master key.
+const BLOB_VERSION_MASTER_KEY: u8 = 1;
+/// Length of the fingerprint of the associated data.
+const FINGERPRINT_LEN: usize = 4;
+/// Length of the header preceding the nonce: magic, version and fingerprint.
+const HEADER_LEN: usize = 3 + 1 + FINGERPRINT_LEN;
+
+/// Scope used to derive the storage key from the master key.
+const STORAGE_SCOPE: &[u8] = b"com.lyndir.masterpassword.storage";
+/// Length of the key of the used encryption algorithm (chacha20).
+const STORAGE_KEY_LEN: usize = 32;
+
+/// Derive the key for encrypting stored passwords from the master key.
+///
+/// The master key is also used to derive the site keys, so it must not be
+/// used as encryption key directly.
+fn storage_key(master_key: &[u8; 64]) -> SecretBox<[u8; STORAGE_KEY_LEN]> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, STORAGE_SCOPE);
+    let mut key = SecretBox::new([0; STORAGE_KEY_LEN]);
+    hkdf::extract_and_expand(&salt, master_key, &[], &mut key.borrow_mut()[..]);
+    key
+}
+
 /// Calculate the minimal length of the encryption buffer.
 pub fn min_buffer_len(clear_text_len: usize) -> usize {
-    padded_len(clear_text_len) + NONCE_LEN + aead::MAX_TAG_LEN
+    HEADER_LEN + padded_len(clear_text_len) + NONCE_LEN + aead::MAX_TAG_LEN
+}
+
+/// Encode the parameters of a site that a stored password is bound to.
+///
+/// The result is authenticated when encrypting the password, so that it
+/// cannot be moved to a different site.
+pub fn associated_data(site_name: &[u8], site_counter: u32, site_variant: SiteVariant,
+        site_context: &[u8])
+    -> Result<Vec<u8>, Error>
+{
+    let site_name_len = u32::try_from(site_name.len())
+        .map_err(|_| Error::from(ErrorKind::SiteNameTooLong))?;
+    let site_context_len = u32::try_from(site_context.len())
+        .map_err(|_| Error::from(ErrorKind::SiteContextTooLong))?;
+    let scope = scope_for_variant(site_variant).as_bytes();
+    let mut data = Vec::with_capacity(scope.len() + site_name.len() + site_context.len() + 12);
+    data.extend_from_slice(scope);
+    // Writing to a vector cannot fail.
+    data.write_u32::<BigEndian>(site_name_len).unwrap();
+    data.extend_from_slice(site_name);
+    data.write_u32::<BigEndian>(site_counter).unwrap();
+    data.write_u32::<BigEndian>(site_context_len).unwrap();
+    data.extend_from_slice(site_context);
+    Ok(data)
+}
+
+/// Write the header of the versioned format for the given associated data.
+fn blob_header(associated_data: &[u8]) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..3].copy_from_slice(BLOB_MAGIC);
+    header[3] = BLOB_VERSION;
+    let fingerprint = digest::digest(&digest::SHA256, associated_data);
+    header[4..].copy_from_slice(&fingerprint.as_ref()[..FINGERPRINT_LEN]);
+    header
+}
+
+/// Authenticate the header as well, so that the version cannot be changed.
+fn header_and_associated_data(header: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(header.len() + associated_data.len());
+    data.extend_from_slice(header);
+    data.extend_from_slice(associated_data);
+    data
 }
 
 /// Pad the password of length `len` to a minimal length `PAD_LEN`.
@@ -458,10 +1139,9 @@ pub fn min_buffer_len(clear_text_len: usize) -> usize {
 /// This is to avoid making it possible to gain information on the length of
 /// short passwords.
 fn pad(buf: &mut [u8], len: usize) {
-    let make_message = |need, got|
-        format!("padding buffer too short: need {}, got {}", need, got);
-    assert!(buf.len() >= PAD_LEN, make_message(PAD_LEN, buf.len()));
-    assert!(buf.len() >= len + 1, make_message(len + 1, buf.len()));
+    assert!(buf.len() >= PAD_LEN,
+            "padding buffer too short: need {}, got {}", PAD_LEN, buf.len());
+    assert!(buf.len() > len, "padding buffer too short: need {}, got {}", len + 1, buf.len());
     let padding_byte = if len >= PAD_LEN { 0 } else { (PAD_LEN - len).try_into().unwrap() };
     //^ This unwrap is safe, because `PAD_LEN` is small.
     for b in &mut buf[len..] {
@@ -472,70 +1152,166 @@ fn pad(buf: &mut [u8], len: usize) {
 /// Remove the padding from a password.
 ///
 /// This is the inverse of `pad`.
-fn unpad(buf: &[u8]) -> &[u8] {
-    let padding_byte = buf[buf.len() - 1];
-    let padding_size = usize::from(padding_byte);
-    for byte in &buf[buf.len() - padding_size..] {
-        debug_assert_eq!(*byte, padding_byte);
-    }
-    if padding_byte != 0 {
-        &buf[0..buf.len() - padding_size]
-    } else {
-        &buf[0..buf.len() - 1]
+fn unpad(buf: &[u8]) -> Result<&[u8], Error> {
+    let padding_byte = *buf.last().ok_or_else(|| Error::from(ErrorKind::BadPadding))?;
+    let padding_size = max(usize::from(padding_byte), 1);
+    if padding_size > buf.len() || usize::from(padding_byte) > PAD_LEN {
+        return Err(Error::from(ErrorKind::BadPadding));
     }
+    let (clear_text, padding) = buf.split_at(buf.len() - padding_size);
+    if padding.iter().any(|&b| b != padding_byte) {
+        return Err(Error::from(ErrorKind::BadPadding));
+    }
+    Ok(clear_text)
 }
 
-/// Encrypt data using the master key.
+/// Encrypt data using the master key, binding it to the associated data.
+///
+/// The buffer has to be at least `min_buffer_len(clear_text.len())` long.
 ///
 /// This is not specified by the Master Password algorithm.
-pub fn encrypt(clear_text: &[u8], master_key: &[u8; 64], buffer: &mut [u8]) {
-    assert!(buffer.len() >= min_buffer_len(clear_text.len()));
+pub fn encrypt(clear_text: &[u8], master_key: &[u8; 64], associated_data: &[u8],
+        buffer: &mut [u8])
+    -> Result<(), Error>
+{
+    let need = min_buffer_len(clear_text.len());
+    if buffer.len() < need {
+        return Err(Error {
+            message: format!("encryption buffer too short: need {}, got {}", need, buffer.len()),
+            kind: ErrorKind::EncryptionFailed,
+        });
+    }
 
+    let header = blob_header(associated_data);
+    let (header_buffer, buffer) = buffer.split_at_mut(HEADER_LEN);
+    header_buffer.copy_from_slice(&header);
     {
-        let (mut nonce, mut rest) = buffer.split_at_mut(NONCE_LEN);
+        let (nonce, rest) = buffer.split_at_mut(NONCE_LEN);
 
         let rng = rand::SystemRandom::new();
-        rng.fill(nonce).expect("failed to generate random nonce");
+        rng.fill(nonce).map_err(|_| Error {
+            message: "failed to generate random nonce".into(),
+            kind: ErrorKind::EncryptionFailed,
+        })?;
 
         {
-            let (mut input, _) = rest.split_at_mut(clear_text.len());
+            let (input, _) = rest.split_at_mut(clear_text.len());
             input.clone_from_slice(clear_text);
         }
 
         // Pad short passwords so their length cannot be guessed by looking
         // at the cipher text.
-        let (mut input, _) = rest.split_at_mut(padded_len(clear_text.len()));
-        pad(&mut input, clear_text.len());
+        let (input, _) = rest.split_at_mut(padded_len(clear_text.len()));
+        pad(input, clear_text.len());
     }
 
-    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &master_key[0..32])
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &storage_key(master_key).borrow()[..])
         .expect("invalid CHACHA20_POLY1305 key");
-    let (nonce, mut in_out) = buffer.split_at_mut(NONCE_LEN);
-    aead::seal_in_place(&key, nonce, &[], in_out, aead::MAX_TAG_LEN)
-        .expect("failed to encrypt password");
+    let (nonce, in_out) = buffer.split_at_mut(NONCE_LEN);
+    let ad = header_and_associated_data(&header, associated_data);
+    aead::seal_in_place(&key, nonce, &ad, in_out, aead::MAX_TAG_LEN)
+        .map_err(|_| Error::from(ErrorKind::EncryptionFailed))?;
+    Ok(())
 }
 
 /// Decrypt data using the master key.
 /// Decryption is in-place, a slice to the decrypted clear text is returned.
 ///
+/// The data has to be bound to the given associated data. Data encrypted
+/// before the versioned format was introduced is not bound to anything and is
+/// refused, see `decrypt_unbound`.
+///
 /// This is not specified by the Master Password algorithm.
-pub fn decrypt<'a>(master_key: &[u8; 64], buffer: &'a mut [u8]) -> &'a [u8] {
-    let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &master_key[0..32])
+pub fn decrypt<'a>(master_key: &[u8; 64], associated_data: &[u8], buffer: &'a mut [u8])
+    -> Result<&'a [u8], Error>
+{
+    if buffer.len() < MIN_SEALED_LEN {
+        return Err(Error::from(ErrorKind::TruncatedCipherText));
+    }
+    if !is_bound_format(buffer) {
+        return Err(Error::from(ErrorKind::Unbound));
+    }
+    if buffer.len() < HEADER_LEN + MIN_SEALED_LEN {
+        return Err(Error::from(ErrorKind::TruncatedCipherText));
+    }
+    if buffer[3] != BLOB_VERSION && buffer[3] != BLOB_VERSION_MASTER_KEY {
+        return Err(Error {
+            message: format!("unsupported version {} of encrypted data", buffer[3]),
+            kind: ErrorKind::UnsupportedFormat,
+        });
+    }
+    if buffer[4..HEADER_LEN] != blob_header(associated_data)[4..] {
+        return Err(Error::from(ErrorKind::WrongSite));
+    }
+    let (header, rest) = buffer.split_at_mut(HEADER_LEN);
+    let ad = header_and_associated_data(header, associated_data);
+    let opened = if header[3] == BLOB_VERSION {
+        open(&storage_key(master_key).borrow()[..], &ad, rest)
+    } else {
+        // Version 1 was encrypted with the master key directly.
+        open(&master_key[0..STORAGE_KEY_LEN], &ad, rest)
+    };
+    opened.ok_or_else(|| Error::from(ErrorKind::AuthenticationFailed)).and_then(unpad)
+}
+
+/// Decrypt data encrypted before the versioned format was introduced.
+/// Decryption is in-place, a slice to the decrypted clear text is returned.
+///
+/// Such data is not bound to a site, so it could have been copied from
+/// another one. Only use this to re-encrypt it after asking the user.
+///
+/// This is not specified by the Master Password algorithm.
+pub fn decrypt_unbound<'a>(master_key: &[u8; 64], buffer: &'a mut [u8])
+    -> Result<&'a [u8], Error>
+{
+    if buffer.len() < MIN_SEALED_LEN {
+        return Err(Error::from(ErrorKind::TruncatedCipherText));
+    }
+    open(&master_key[0..STORAGE_KEY_LEN], &[], buffer)
+        .ok_or_else(|| Error::from(ErrorKind::AuthenticationFailed))
+        .and_then(unpad)
+}
+
+/// Return whether encrypted data is bound to associated data.
+///
+/// Very rarely, the random nonce of unbound data starts like the header.
+/// Decrypting such data fails and it has to be decrypted with
+/// `decrypt_unbound`.
+pub fn is_bound_format(cipher_text: &[u8]) -> bool {
+    cipher_text.starts_with(BLOB_MAGIC)
+}
+
+/// Return whether encrypted data is in the current format.
+///
+/// Data in older formats can still be decrypted, but should be re-encrypted.
+pub fn is_current_format(cipher_text: &[u8]) -> bool {
+    cipher_text.len() >= HEADER_LEN && cipher_text.starts_with(BLOB_MAGIC)
+        && cipher_text[3] == BLOB_VERSION
+}
+
+/// Open a nonce followed by the sealed data in place.
+///
+/// Returns the padded clear text, or None if it could not be authenticated.
+fn open<'a>(key: &[u8], associated_data: &[u8], buffer: &'a mut [u8])
+    -> Option<&'a [u8]>
+{
+    let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, key)
         .expect("invalid CHACHA20_POLY1305 key");
-    assert!(buffer.len() > NONCE_LEN, "invalid cipher text");
-    let (nonce, mut in_out) = buffer.split_at_mut(NONCE_LEN);
-    let padded = aead::open_in_place(&key, nonce, &[], 0, in_out)
-        .expect("failed to decrypt password");
-    unpad(padded)
+    if buffer.len() < MIN_SEALED_LEN {
+        return None;
+    }
+    let (nonce, in_out) = buffer.split_at_mut(NONCE_LEN);
+    aead::open_in_place(&key, nonce, associated_data, 0, in_out).ok().map(|padded| &*padded)
 }
 
 #[test]
 fn test_key_for_user_v3() {
     let full_name = "John Doe";
     let master_password = "password";
-    let master_key = master_key_for_user_v3(
+    let master_key = master_key_for_user(
         full_name.as_bytes(),
-        master_password.as_bytes()
+        master_password.as_bytes(),
+        AlgorithmVersion::V3
     ).unwrap();
     let expected_master_key: [u8; 64] = [
         27, 177, 181, 88, 106, 115, 177, 174, 150, 213, 214, 9, 53, 44, 141,
@@ -544,7 +1320,16 @@ fn test_key_for_user_v3() {
         10, 86, 114, 44, 225, 160, 170, 250, 210, 194, 87, 12, 220, 20, 36, 120,
         232
     ];
-    assert_eq!(&master_key[..], &expected_master_key[..]);
+    assert_eq!(&master_key.borrow()[..], &expected_master_key[..]);
+}
+
+#[test]
+fn test_key_id() {
+    // Test vector from an export of the official implementation.
+    let master_key = master_key_for_user(
+        b"Robert Lee Mitchell", b"banana colored duckling", AlgorithmVersion::V3).unwrap();
+    assert_eq!(key_id(&master_key.borrow()),
+               "98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302");
 }
 
 #[test]
@@ -554,9 +1339,10 @@ fn test_template_entropy() {
     /// Calculate minimal bits of entropy.
     // TODO: Figure out how to calculate actual entropy
     fn bits(ty: SiteType) -> f64 {
-        let mut min = ::std::f64::INFINITY;
-        for t in &templates_for_type(ty) {
-            min = entropy_of_template(*t).min(min);
+        let templates = Templates::new();
+        let mut min = f64::INFINITY;
+        for t in &templates.templates_for_type(&ty).unwrap() {
+            min = entropy_of_template(t, &templates).min(min);
         }
         min
     }
@@ -571,20 +1357,122 @@ fn test_template_entropy() {
     assert!(bits(GeneratedPhrase) > 55.7);
 }
 
+#[test]
+fn test_custom_templates() {
+    let mut templates = Templates::new();
+    templates.add_class('d', "23456789").unwrap();
+    templates.add_template("corporate", vec!["Cvcvdddd".into()]).unwrap();
+
+    let seed: Vec<u8> = (0..32).collect();
+    let ty = SiteType::Custom("corporate".into());
+    let password = generate_password(&ty, &seed, AlgorithmVersion::V3, &templates, &Policy::default())
+        .unwrap();
+    assert_eq!(&*password.borrow(), "Cifu7892");
+    let policy = Policy::default();
+    let password = generate_password(
+        &SiteType::GeneratedPIN, &seed, AlgorithmVersion::V3, &templates, &policy).unwrap();
+    assert_eq!(&*password.borrow(), "1234");
+
+    let unknown = SiteType::Custom("unknown".into());
+    let err = generate_password(&unknown, &seed, AlgorithmVersion::V3, &templates, &policy)
+        .unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownTemplate);
+    let err = generate_password(
+        &SiteType::Stored, &seed, AlgorithmVersion::V3, &templates, &policy).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidTemplate);
+
+    // Templates have to fit into the seed.
+    let longest = "d".repeat(31);
+    templates.add_template("longest", vec![longest.clone()]).unwrap();
+    let err = templates.add_template("too long", vec![longest + "d"]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidTemplate);
+
+    for &(name, template) in &[("empty", ""), ("unknown class", "Cvcq"), ("long", "Cvcv"),
+                               ("corporate", "Cvcv")] {
+        let err = templates.add_template(name, vec![template.into()]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidTemplate);
+    }
+    assert_eq!(templates.add_template("none", vec![]).unwrap_err().kind,
+               ErrorKind::InvalidTemplate);
+    assert_eq!(templates.add_class('n', "0123").unwrap_err().kind,
+               ErrorKind::InvalidCharacterClass);
+    assert_eq!(templates.add_class('d', "0123").unwrap_err().kind,
+               ErrorKind::InvalidCharacterClass);
+    assert_eq!(templates.add_class('e', "").unwrap_err().kind,
+               ErrorKind::InvalidCharacterClass);
+}
+
+#[test]
+fn test_password_policy() {
+    use self::CharacterRequirement::*;
+
+    let templates = Templates::new();
+    let generate = |ty: SiteType, seed: &[u8], policy: &Policy|
+        generate_password(&ty, seed, AlgorithmVersion::V3, &templates, policy);
+
+    let policy = Policy {
+        required: Some(vec![Uppercase, Lowercase, Digit]),
+        forbidden: Some("0O1l".into()),
+        ..Policy::default()
+    };
+    for i in 0..64u8 {
+        let seed: Vec<u8> = (0..32).map(|j| i.wrapping_mul(31).wrapping_add(j * 7)).collect();
+        let plain = generate(SiteType::GeneratedBasic, &seed, &Policy::default()).unwrap();
+        let password = generate(SiteType::GeneratedBasic, &seed, &policy).unwrap();
+        let (password, plain) = (password.borrow(), plain.borrow());
+        assert_eq!(password.len(), 8);
+        assert!(password.chars().any(|c| c.is_uppercase()));
+        assert!(password.chars().any(|c| c.is_lowercase()));
+        assert!(password.chars().any(|c| c.is_ascii_digit()));
+        assert!(!password.chars().any(|c| "0O1l".contains(c)));
+        if policy.is_satisfied_by(&plain) {
+            // Passwords that already satisfy the policy are not changed.
+            assert_eq!(*password, *plain);
+        }
+        // The result is reproducible.
+        let again = generate(SiteType::GeneratedBasic, &seed, &policy).unwrap();
+        assert_eq!(*password, *again.borrow());
+    }
+
+    let seed: Vec<u8> = (0..32).collect();
+    let policy = Policy { min_length: Some(12), max_length: Some(16), ..Policy::default() };
+    assert_eq!(generate(SiteType::GeneratedLong, &seed, &policy).unwrap().len(), 14);
+    assert_eq!(generate(SiteType::GeneratedMaximum, &seed, &policy).unwrap_err().kind,
+               ErrorKind::UnsatisfiablePolicy);
+
+    let policy = Policy { required: Some(vec![Symbol]), ..Policy::default() };
+    assert_eq!(generate(SiteType::GeneratedBasic, &seed, &policy).unwrap_err().kind,
+               ErrorKind::UnsatisfiablePolicy);
+
+    let policy = Policy { forbidden: Some("0123456789".into()), ..Policy::default() };
+    assert_eq!(generate(SiteType::GeneratedPIN, &seed, &policy).unwrap_err().kind,
+               ErrorKind::UnsatisfiablePolicy);
+}
+
 #[test]
 fn test_password_for_site_v3() {
     let full_name = "John Doe";
     let master_password = "password";
-    let master_key = master_key_for_user_v3(
+    let master_key = master_key_for_user(
         full_name.as_bytes(),
-        master_password.as_bytes()
+        master_password.as_bytes(),
+        AlgorithmVersion::V3
     ).unwrap();
     let site_name = "google.com";
-    let password = password_for_site_v3(
-        &master_key, site_name.as_bytes(), SiteType::GeneratedLong, 1,
-        SiteVariant::Password, &[]
+    let password = password_for_site(
+        &master_key.borrow(), site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+        SiteVariant::Password, &[], AlgorithmVersion::V3, &Templates::new(),
+        &Policy::default()
     ).unwrap();
-    assert_eq!(*password, "QubnJuvaMoke2~");
+    assert_eq!(&*password.borrow(), "QubnJuvaMoke2~");
+
+    // The policy is applied as well.
+    let policy = Policy { min_length: Some(20), ..Policy::default() };
+    let err = password_for_site(
+        &master_key.borrow(), site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+        SiteVariant::Password, &[], AlgorithmVersion::V3, &Templates::new(), &policy
+    ).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnsatisfiablePolicy);
 }
 
 #[test]
@@ -592,7 +1480,12 @@ fn test_identicon() {
     let full_name = "John Doe";
     let master_password = "password";
     let identicon = identicon(full_name.as_bytes(), master_password.as_bytes());
-    assert_eq!(identicon, "╔░╝⌚");
+    assert_eq!(identicon.to_string(), "╔░╝⌚");
+    assert_eq!(identicon.color, IdenticonColor::Red);
+
+    let other = super::identicon(b"Robert Lee Mitchell", b"banana colored duckling");
+    assert_eq!(other.to_string(), "╚☻╯⛄");
+    assert_eq!(other.color, IdenticonColor::Green);
 }
 
 #[test]
@@ -600,17 +1493,20 @@ fn test_unicode_user_name() {
     let full_name = "Max Müller";
     let master_password = "passwort";
     let identicon = identicon(full_name.as_bytes(), master_password.as_bytes());
-    assert_eq!(identicon, "═▒╝♚");
-    let master_key = master_key_for_user_v3(
+    assert_eq!(identicon.to_string(), "═▒╝♚");
+    assert_eq!(identicon.color, IdenticonColor::Cyan);
+    let master_key = master_key_for_user(
         full_name.as_bytes(),
-        master_password.as_bytes()
+        master_password.as_bytes(),
+        AlgorithmVersion::V3
     ).unwrap();
     let site_name = "de.wikipedia.org";
-    let password = password_for_site_v3(
-        &master_key, site_name.as_bytes(), SiteType::GeneratedLong, 1,
-        SiteVariant::Password, &[]
+    let password = password_for_site(
+        &master_key.borrow(), site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+        SiteVariant::Password, &[], AlgorithmVersion::V3, &Templates::new(),
+        &Policy::default()
     ).unwrap();
-    assert_eq!(*password, "DaknJezb6,Zula");
+    assert_eq!(&*password.borrow(), "DaknJezb6,Zula");
 }
 
 #[test]
@@ -618,17 +1514,20 @@ fn test_unicode_site_name() {
     let full_name = "Zhang Wei";
     let master_password = "password";
     let identicon = identicon(full_name.as_bytes(), master_password.as_bytes());
-    assert_eq!(identicon, "╔░╗◒");
-    let master_key = master_key_for_user_v3(
+    assert_eq!(identicon.to_string(), "╔░╗◒");
+    assert_eq!(identicon.color, IdenticonColor::Red);
+    let master_key = master_key_for_user(
         full_name.as_bytes(),
-        master_password.as_bytes()
+        master_password.as_bytes(),
+        AlgorithmVersion::V3
     ).unwrap();
     let site_name = "山东大学.cn";
-    let password = password_for_site_v3(
-        &master_key, site_name.as_bytes(), SiteType::GeneratedLong, 1,
-        SiteVariant::Password, &[]
+    let password = password_for_site(
+        &master_key.borrow(), site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+        SiteVariant::Password, &[], AlgorithmVersion::V3, &Templates::new(),
+        &Policy::default()
     ).unwrap();
-    assert_eq!(*password, "ZajmGabl0~Zoza");
+    assert_eq!(&*password.borrow(), "ZajmGabl0~Zoza");
 }
 
 #[test]
@@ -637,7 +1536,7 @@ fn test_padding_short() {
     pad(&mut vec, 5);
     assert_eq!(&vec,
         &[1, 2, 3, 4, 5, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15]);
-    assert_eq!(unpad(&vec), &[1, 2, 3, 4, 5]);
+    assert_eq!(unpad(&vec).unwrap(), &[1, 2, 3, 4, 5]);
 }
 
 #[test]
@@ -648,7 +1547,20 @@ fn test_padding_long() {
         let mut expected = vec![100; len + 1];
         expected[len] = 0;
         assert_eq!(&vec, &expected);
-        assert_eq!(unpad(&vec), &vec![100; len][..]);
+        assert_eq!(unpad(&vec).unwrap(), &vec![100; len][..]);
+    }
+}
+
+#[test]
+fn test_bad_padding() {
+    for vec in &[
+        vec![],
+        vec![1, 2, 3, 4, 5],
+        vec![1, 2, 3, 4, 5, 3, 3],
+        vec![1, 2, 3, 4, 5, 3, 2, 3],
+        vec![200; 210],
+    ] {
+        assert_eq!(unpad(vec).unwrap_err().kind, ErrorKind::BadPadding);
     }
 }
 
@@ -656,8 +1568,203 @@ fn test_padding_long() {
 fn test_encryption() {
     let clear_text = b"This is a secret.";
     let key = [1; 64];
+    let ad = associated_data(b"github.com", 1, SiteVariant::Password, b"").unwrap();
     let mut buffer = vec![0; min_buffer_len(clear_text.len())];
-    encrypt(clear_text, &key, &mut buffer);
-    let decrypted = decrypt(&key, &mut buffer);
-    assert_eq!(clear_text, decrypted);
+    encrypt(clear_text, &key, &ad, &mut buffer).unwrap();
+    assert!(buffer.starts_with(b"MPW\x02"));
+    let mut copy = buffer.clone();
+    assert_eq!(clear_text, decrypt(&key, &ad, &mut copy).unwrap());
+
+    // The encrypted password cannot be moved to another site.
+    for other in &[
+        associated_data(b"gitlab.com", 1, SiteVariant::Password, b"").unwrap(),
+        associated_data(b"github.com", 2, SiteVariant::Password, b"").unwrap(),
+        associated_data(b"github.com", 1, SiteVariant::Login, b"").unwrap(),
+        associated_data(b"github.com", 1, SiteVariant::Answer, b"mother").unwrap(),
+    ] {
+        let err = decrypt(&key, other, &mut buffer.clone()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::WrongSite);
+    }
+
+    // Changing the fingerprint as well does not help.
+    let other = associated_data(b"gitlab.com", 1, SiteVariant::Password, b"").unwrap();
+    let mut moved = buffer.clone();
+    moved[..HEADER_LEN].copy_from_slice(&blob_header(&other));
+    let err = decrypt(&key, &other, &mut moved).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::AuthenticationFailed);
+
+    let err = decrypt(&[2; 64], &ad, &mut buffer.clone()).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::AuthenticationFailed);
+
+    // Unknown versions are not mistaken for tampering.
+    let mut future = buffer.clone();
+    future[3] = BLOB_VERSION + 1;
+    let err = decrypt(&key, &ad, &mut future).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnsupportedFormat);
+
+    // Corrupted cipher texts result in errors instead of panics.
+    for &len in &[0, 3, HEADER_LEN, HEADER_LEN + MIN_SEALED_LEN - 1] {
+        let err = decrypt(&key, &ad, &mut buffer[..len].to_vec()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TruncatedCipherText);
+    }
+    let mut corrupted = buffer.clone();
+    corrupted[HEADER_LEN + NONCE_LEN] ^= 1;
+    let err = decrypt(&key, &ad, &mut corrupted).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::AuthenticationFailed);
+
+    let mut badly_padded = buffer.clone();
+    {
+        let (header, rest) = badly_padded.split_at_mut(HEADER_LEN);
+        let (nonce, in_out) = rest.split_at_mut(NONCE_LEN);
+        for b in in_out.iter_mut() {
+            *b = 0xff;
+        }
+        let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305,
+                                                &storage_key(&key).borrow()[..]).unwrap();
+        aead::seal_in_place(&sealing_key, nonce, &header_and_associated_data(header, &ad),
+                            in_out, aead::MAX_TAG_LEN).unwrap();
+    }
+    let err = decrypt(&key, &ad, &mut badly_padded).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::BadPadding);
+
+    let err = encrypt(clear_text, &key, &ad, &mut buffer[1..]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::EncryptionFailed);
+}
+
+#[test]
+fn test_decrypt_legacy() {
+    // Encrypted without associated data before the versioned format.
+    let key = [1; 64];
+    let ad = associated_data(b"github.com", 1, SiteVariant::Password, b"").unwrap();
+    let mut buffer = vec![0; min_buffer_len(6) - HEADER_LEN];
+    buffer[NONCE_LEN..NONCE_LEN + 6].copy_from_slice(b"secret");
+    pad(&mut buffer[NONCE_LEN..NONCE_LEN + PAD_LEN], 6);
+    for &nonce in &[b"123456789012", b"MPW\x01abcdefgh"] {
+        buffer[..NONCE_LEN].copy_from_slice(nonce);
+        let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key[0..32]).unwrap();
+        let mut sealed = buffer.clone();
+        {
+            let (nonce, in_out) = sealed.split_at_mut(NONCE_LEN);
+            aead::seal_in_place(&sealing_key, nonce, &[], in_out, aead::MAX_TAG_LEN).unwrap();
+        }
+        assert!(!is_current_format(&sealed));
+        assert!(decrypt(&key, &ad, &mut sealed.clone()).is_err());
+        assert_eq!(decrypt_unbound(&key, &mut sealed).unwrap(), b"secret");
+    }
+    let mut sealed = buffer.clone();
+    sealed[..NONCE_LEN].copy_from_slice(b"123456789012");
+    assert!(!is_bound_format(&sealed));
+    assert_eq!(decrypt(&key, &ad, &mut sealed).unwrap_err().kind, ErrorKind::Unbound);
+
+    // Encrypted with the master key in version 1 of the versioned format.
+    let mut header = blob_header(&ad);
+    header[3] = BLOB_VERSION_MASTER_KEY;
+    let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key[0..32]).unwrap();
+    let mut sealed = header.to_vec();
+    sealed.extend_from_slice(&buffer);
+    {
+        let (nonce, in_out) = sealed[HEADER_LEN..].split_at_mut(NONCE_LEN);
+        let ad = header_and_associated_data(&header, &ad);
+        aead::seal_in_place(&sealing_key, nonce, &ad, in_out, aead::MAX_TAG_LEN).unwrap();
+    }
+    assert!(!is_current_format(&sealed));
+    assert!(is_bound_format(&sealed));
+    let other = associated_data(b"gitlab.com", 1, SiteVariant::Password, b"").unwrap();
+    assert_eq!(decrypt(&key, &other, &mut sealed.clone()).unwrap_err().kind,
+               ErrorKind::WrongSite);
+    assert_eq!(decrypt(&key, &ad, &mut sealed).unwrap(), b"secret");
+}
+
+#[test]
+fn test_storage_key() {
+    // The master key is not used for encryption directly.
+    let key = [1; 64];
+    let derived = storage_key(&key);
+    assert!(derived.borrow()[..] != key[0..32]);
+    assert_eq!(&derived.borrow()[..], &storage_key(&key).borrow()[..]);
+    assert!(derived.borrow()[..] != storage_key(&[2; 64]).borrow()[..]);
+
+    let ad = associated_data(b"github.com", 1, SiteVariant::Password, b"").unwrap();
+    let mut buffer = vec![0; min_buffer_len(6)];
+    encrypt(b"secret", &key, &ad, &mut buffer).unwrap();
+    assert!(is_current_format(&buffer));
+    let header = buffer[..HEADER_LEN].to_vec();
+    let opened = open(&key[0..32], &header_and_associated_data(&header, &ad),
+                      &mut buffer.clone()[HEADER_LEN..]).is_some();
+    assert!(!opened);
+    assert_eq!(decrypt(&key, &ad, &mut buffer).unwrap(), b"secret");
+}
+
+#[test]
+fn test_password_for_site_versions() {
+    // Test vectors of the official implementation.
+    let full_name = "Robert Lee Mitchell";
+    let master_password = "banana colored duckling";
+    let site_name = "masterpasswordapp.com";
+    let expected = [
+        (AlgorithmVersion::V0, "Feji5@ReduWosh"),
+        (AlgorithmVersion::V1, "Jejr5[RepuSosp"),
+        (AlgorithmVersion::V2, "Jejr5[RepuSosp"),
+        (AlgorithmVersion::V3, "Jejr5[RepuSosp"),
+    ];
+    for &(version, expected_password) in &expected {
+        let master_key = master_key_for_user(
+            full_name.as_bytes(),
+            master_password.as_bytes(),
+            version
+        ).unwrap();
+        let password = password_for_site(
+            &master_key.borrow(), site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+            SiteVariant::Password, &[], version, &Templates::new(), &Policy::default()
+        ).unwrap();
+        assert_eq!(&*password.borrow(), expected_password);
+    }
+}
+
+#[test]
+fn test_unicode_versions() {
+    let full_name = "Max Müller";
+    let master_password = "passwort";
+    let site_name = "山东大学.cn";
+    let expected = [
+        (AlgorithmVersion::V0, "JaplKapeGasl3]"),
+        (AlgorithmVersion::V1, "JaldCadeCasd3;"),
+        (AlgorithmVersion::V2, "XalsPecaSuvu0@"),
+        (AlgorithmVersion::V3, "BabwRalo0]Rimo"),
+    ];
+    let mut cache = MasterKeyCache::new();
+    for &(version, expected_password) in &expected {
+        let master_key = cache.get_or_derive(
+            full_name.as_bytes(),
+            master_password.as_bytes(),
+            version
+        ).unwrap();
+        let password = password_for_site(
+            &master_key, site_name.as_bytes(), &SiteType::GeneratedLong, 1,
+            SiteVariant::Password, &[], version, &Templates::new(), &Policy::default()
+        ).unwrap();
+        assert_eq!(&*password.borrow(), expected_password);
+    }
+    // Versions before v3 share the master key.
+    assert_eq!(cache.keys.len(), 2);
+}
+
+#[test]
+fn test_master_key_cache_ascii() {
+    let mut cache = MasterKeyCache::new();
+    for &version in &[AlgorithmVersion::V0, AlgorithmVersion::V3] {
+        cache.get_or_derive(b"John Doe", b"password", version).unwrap();
+    }
+    assert_eq!(cache.keys.len(), 1);
+    assert!(cache.get(b"John Doe", AlgorithmVersion::V2).unwrap().is_some());
+    assert!(cache.get(b"Jane Doe", AlgorithmVersion::V2).unwrap().is_none());
+}
+
+#[test]
+fn test_algorithm_version_from_str() {
+    assert_eq!(AlgorithmVersion::from_str("0"), Some(AlgorithmVersion::V0));
+    assert_eq!(AlgorithmVersion::from_str("v2"), Some(AlgorithmVersion::V2));
+    assert_eq!(AlgorithmVersion::from_str("3"), Some(AlgorithmVersion::V3));
+    assert_eq!(AlgorithmVersion::from_str("4"), None);
+    assert_eq!(AlgorithmVersion::from_str("-1"), None);
 }
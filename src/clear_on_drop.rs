@@ -1,15 +1,25 @@
 extern crate libc;
 extern crate errno;
 
+use std::collections::HashMap;
 use std::convert::AsMut;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::intrinsics;
+use std::process;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering};
 
 use self::libc::c_void;
 use self::errno::{errno, Errno};
 
+use secret::{PAGE_SIZE, SLOT_LEN, allocate_buffer, free_buffer};
+
+/// Error of locking memory.
 #[derive(Debug, Clone, Copy)]
-enum Error {
+pub enum Error {
     /// Some of the specified address range does not correspond to mapped pages
     /// in the address space of the process.
     NoMemory,
@@ -37,12 +47,97 @@ impl From<Errno> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoMemory => f.write_str("the limit of locked memory (RLIMIT_MEMLOCK) was \
+                                            exceeded or the memory is not mapped"),
+            Error::Permission => f.write_str("not permitted to lock memory"),
+            Error::Again => f.write_str("some of the memory could not be locked"),
+            Error::Invalid => f.write_str("invalid address range"),
+            Error::Other(errno) => write!(f, "{}", errno),
+        }
+    }
+}
+
+/// What to do if memory holding secrets cannot be locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MlockPolicy {
+    /// Continue silently.
+    Ignore,
+    /// Print a warning the first time and continue.
+    Warn,
+    /// Print an error and exit the process.
+    Refuse,
+}
+
+impl MlockPolicy {
+    /// Parse the policy from its name.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<MlockPolicy> {
+        match s {
+            "ignore" => Some(MlockPolicy::Ignore),
+            "warn" => Some(MlockPolicy::Warn),
+            "refuse" => Some(MlockPolicy::Refuse),
+            _ => None,
+        }
+    }
+}
+
+/// The policy of the process, `MlockPolicy::Warn` by default.
+static MLOCK_POLICY: AtomicUsize = AtomicUsize::new(MlockPolicy::Warn as usize);
+/// Whether a warning about failing to lock memory was printed.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Set what to do if memory holding secrets cannot be locked.
+pub fn set_mlock_policy(policy: MlockPolicy) {
+    MLOCK_POLICY.store(policy as usize, Ordering::SeqCst);
+}
+
+/// Return what is done if memory holding secrets cannot be locked.
+pub fn mlock_policy() -> MlockPolicy {
+    match MLOCK_POLICY.load(Ordering::SeqCst) {
+        p if p == MlockPolicy::Ignore as usize => MlockPolicy::Ignore,
+        p if p == MlockPolicy::Refuse as usize => MlockPolicy::Refuse,
+        _ => MlockPolicy::Warn,
+    }
+}
+
+/// Lock memory holding secrets, handling failures according to the policy.
+pub(crate) fn lock(slice: &[u8]) {
+    if slice.is_empty() {
+        return;
+    }
+    lock_range(slice.as_ptr(), slice.len());
+}
+
+/// Lock an address range, handling failures according to the policy.
+pub(crate) fn lock_range(address: *const u8, len: usize) {
+    let error = match mlock(address, len) {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    // Stderr may be closed, for instance in the agent.
+    match mlock_policy() {
+        MlockPolicy::Ignore => {},
+        MlockPolicy::Warn => if !WARNED.swap(true, Ordering::SeqCst) {
+            let _ = writeln!(io::stderr(), "warning: could not lock memory holding secrets, \
+                                            they may be swapped to disk: {}", error);
+        },
+        MlockPolicy::Refuse => {
+            let _ = writeln!(io::stderr(), "error: could not lock memory holding secrets: {}",
+                             error);
+            process::exit(1);
+        },
+    }
+}
+
 /// Lock part of the calling process's virtual memory into RAM.
 ///
 /// This prevents that memory from being paged to the swap area.
-fn mlock(slice: &[u8]) -> Result<(), Error> {
+fn mlock(address: *const u8, len: usize) -> Result<(), Error> {
     let return_code = unsafe {
-        libc::mlock(slice.as_ptr() as *const c_void, slice.len())
+        libc::mlock(address as *const c_void, len)
     };
     if return_code == 0 {
         return Ok(());
@@ -54,9 +149,14 @@ fn mlock(slice: &[u8]) -> Result<(), Error> {
 ///
 /// After this call, all pages that contain a part of the specified memory
 /// range can be moved to external swap space again by the kernel.
-fn munlock(slice: &[u8]) -> Result<(), Error> {
+pub(crate) fn munlock(slice: &[u8]) -> Result<(), Error> {
+    munlock_range(slice.as_ptr(), slice.len())
+}
+
+/// Unlock pages in the given address range, see `munlock`.
+fn munlock_range(address: *const u8, len: usize) -> Result<(), Error> {
     let return_code = unsafe {
-        libc::munlock(slice.as_ptr() as *const c_void, slice.len())
+        libc::munlock(address as *const c_void, len)
     };
     if return_code == 0 {
         return Ok(());
@@ -64,12 +164,107 @@ fn munlock(slice: &[u8]) -> Result<(), Error> {
     Err(errno().into())
 }
 
+lazy_static! {
+    /// The pages holding contents of `ClearOnDrop` on the heap, with the
+    /// number of contents in each of them.
+    static ref HEAP_PAGES: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Lock the pages on the heap, even if another thread panicked while
+/// holding them.
+fn heap_pages() -> MutexGuard<'static, HashMap<usize, usize>> {
+    HEAP_PAGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Return the addresses of the pages containing the given memory.
+fn pages_of(address: *const u8, len: usize) -> Vec<usize> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let page_size = *PAGE_SIZE;
+    let start = address as usize & !(page_size - 1);
+    let end = address as usize + len;
+    (start..end).step_by(page_size).collect()
+}
+
+/// Lock the pages of contents on the heap.
+///
+/// Pages are counted, so that they stay locked while they hold other
+/// contents.
+fn lock_heap(pages: &[usize]) {
+    let mut locked = heap_pages();
+    for &page in pages {
+        let count = locked.entry(page).or_insert(0);
+        if *count == 0 {
+            lock_range(page as *const u8, *PAGE_SIZE);
+        }
+        *count += 1;
+    }
+}
+
+/// Lock the pages of contents on the heap again after `fork`.
+pub(crate) fn relock_heap() {
+    for &page in heap_pages().keys() {
+        lock_range(page as *const u8, *PAGE_SIZE);
+    }
+}
+
+/// Unlock the pages of contents on the heap that hold no other contents.
+fn unlock_heap(pages: &[usize]) {
+    let mut locked = heap_pages();
+    for &page in pages {
+        let count = locked.get_mut(&page).unwrap();
+        //^ This unwrap is safe, because the page was counted by `lock_heap`.
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            let _ = munlock_range(page as *const u8, *PAGE_SIZE);
+        }
+    }
+}
+
+/// Overwrite the given memory with zeros.
+///
+/// The writes are volatile and followed by a compiler fence, so that they are
+/// neither optimized away nor reordered after the memory is freed.
+pub fn clear(slice: &mut [u8]) {
+    unsafe { clear_range(slice.as_mut_ptr(), slice.len()) };
+}
+
+/// Overwrite the given memory with zeros, like `clear`.
+///
+/// # Safety
+///
+/// The memory has to be allocated and writable, but may be uninitialized.
+unsafe fn clear_range(address: *mut u8, len: usize) {
+    for i in 0..len {
+        ptr::write_volatile(address.add(i), 0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
 /// A cheap, mutable reference-to-mutable reference conversion.
 ///
 /// Because it is implemented for String as well, it is unsafe to call.
 /// (It allows violating memory safety by setting a non-UTF-8 string.)
 pub trait UnsafeAsMut {
+    /// Return the bytes of the value.
+    ///
+    /// # Safety
+    ///
+    /// The bytes have to stay valid for the type, like UTF-8 for `String`.
     unsafe fn as_mut(&mut self) -> &mut [u8];
+
+    /// Return the start and length of the memory holding the bytes,
+    /// including unused capacity, which may be uninitialized.
+    ///
+    /// # Safety
+    ///
+    /// Only zeros may be written to the memory.
+    unsafe fn allocation(&mut self) -> (*mut u8, usize) {
+        let bytes = self.as_mut();
+        (bytes.as_mut_ptr(), bytes.len())
+    }
 }
 
 /* Ideally this should be used, but it conflicts with the implementation for String.
@@ -86,6 +281,16 @@ impl UnsafeAsMut for Vec<u8> {
     unsafe fn as_mut(&mut self) -> &mut [u8] {
         AsMut::as_mut(self)
     }
+
+    unsafe fn allocation(&mut self) -> (*mut u8, usize) {
+        (self.as_mut_ptr(), self.capacity())
+    }
+}
+
+impl UnsafeAsMut for [u8; 32] {
+    unsafe fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
 }
 
 impl UnsafeAsMut for [u8; 64] {
@@ -98,24 +303,70 @@ impl UnsafeAsMut for String {
     unsafe fn as_mut(&mut self) -> &mut [u8] {
         AsMut::as_mut(self.as_mut_vec())
     }
+
+    unsafe fn allocation(&mut self) -> (*mut u8, usize) {
+        self.as_mut_vec().allocation()
+    }
+}
+
+/// Where the container of a `ClearOnDrop` lives.
+#[derive(Debug)]
+enum Storage<T> {
+    /// In a slot of the locked arena of the `secret` module.
+    Pooled(*mut T),
+    /// On the heap, if it is too large for a slot or the arena is full.
+    Boxed(Box<T>),
 }
 
 /// A container representing a byte slice that is set to zero on drop.
 ///
 /// Useful to make sure that secret data is cleared from memory after use.
-// TODO: Investigate mprotect.
+/// Secrets that are kept for longer should use the protected memory of the
+/// `secret` module instead.
+///
+/// Small containers, like arrays, are pooled in memory that is locked once.
+/// The contents of vectors and strings are locked where they are on the heap
+/// and unlocked on drop, unless their pages still hold other contents. This
+/// includes their unused capacity, which should suffice for all contents,
+/// since memory left behind by a reallocation is neither locked nor cleared.
 #[derive(Debug)]
 pub struct ClearOnDrop<T: UnsafeAsMut> {
-    container: Box<T>
+    container: Storage<T>,
+    /// The pages locked for contents outside of the arena.
+    locked: Vec<usize>,
 }
 
+// The container is owned like a `Box`.
+unsafe impl<T: UnsafeAsMut + Send> Send for ClearOnDrop<T> {}
+
 impl<T: UnsafeAsMut> ClearOnDrop<T> {
     pub fn new(container: T) -> ClearOnDrop<T> {
-        // Make sure the string is not swapped by using mlock.
-        let mut result = ClearOnDrop { container: Box::new(container) };
-        unsafe {
-            let slice = result.container.deref_mut().as_mut();
-            let _ = mlock(slice);  // This sometimes fails for some reason.
+        let slot = if mem::size_of::<T>() <= SLOT_LEN && mem::align_of::<T>() <= SLOT_LEN {
+            allocate_buffer()
+        } else {
+            None
+        };
+        let container = match slot {
+            Some(slot) => {
+                let slot = slot as *mut T;
+                unsafe { ptr::write(slot, container) };
+                Storage::Pooled(slot)
+            },
+            None => Storage::Boxed(Box::new(container)),
+        };
+        let mut result = ClearOnDrop { container, locked: Vec::new() };
+        // Make sure contents on the heap are not swapped by using mlock.
+        let (start, len) = unsafe { result.deref_mut().allocation() };
+        let pages = pages_of(start, len);
+        let start = start as usize;
+        // Contents in the slot itself, like arrays, are locked with the arena.
+        let in_slot = match result.container {
+            Storage::Pooled(slot) => slot as usize <= start && start < slot as usize + SLOT_LEN,
+            Storage::Boxed(_) => false,
+        };
+        if !in_slot {
+            lock_heap(&pages);
+            result.locked = pages;
         }
         result
     }
@@ -125,25 +376,34 @@ impl<T: UnsafeAsMut> Deref for ClearOnDrop<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.container
+        match self.container {
+            Storage::Pooled(slot) => unsafe { &*slot },
+            Storage::Boxed(ref container) => container,
+        }
     }
 }
 
 impl<T: UnsafeAsMut> DerefMut for ClearOnDrop<T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.container
+        match self.container {
+            Storage::Pooled(slot) => unsafe { &mut *slot },
+            Storage::Boxed(ref mut container) => container,
+        }
     }
 }
 
 impl<T: UnsafeAsMut> Drop for ClearOnDrop<T> {
     #[inline(never)]
     fn drop(&mut self) {
-        // We use a volatile memset that makes sure it is not optimized away. It
-        // is safe to overwrite strings with zeros, because it is valid UTF-8.
+        // It is safe to overwrite strings with zeros, because it is valid UTF-8.
         unsafe {
-            let slice = self.container.deref_mut().as_mut();
-            intrinsics::volatile_set_memory(slice.as_ptr() as *mut c_void, 0, slice.len());
-            let _ = munlock(slice);  // This sometimes fails for some reason.
+            let (start, len) = self.deref_mut().allocation();
+            clear_range(start, len);
+            unlock_heap(&self.locked);
+            if let Storage::Pooled(slot) = self.container {
+                ptr::drop_in_place(slot);
+                free_buffer(slot as *mut u8);
+            }
         }
     }
 }
@@ -165,3 +425,103 @@ fn test_clear_on_drop_array() {
     let a = [1; 64];
     let _ = ClearOnDrop::new(a);
 }
+
+#[test]
+fn test_clear_on_drop_pooled() {
+    let a = ClearOnDrop::new([1; 64]);
+    match a.container {
+        Storage::Pooled(_) => {},
+        Storage::Boxed(_) => panic!("small container on the heap"),
+    }
+    assert_eq!(&a[..], &[1; 64][..]);
+    let v = ClearOnDrop::new(vec![2; 1000]);
+    assert_eq!(&v[..], &[2; 1000][..]);
+}
+
+#[test]
+fn test_clear_on_drop_unlocks_heap() {
+    let a = ClearOnDrop::new([1; 64]);
+    assert!(a.locked.is_empty());
+
+    // Other tests lock pages concurrently, so only the pages of these
+    // contents are inspected.
+    let page_size = *PAGE_SIZE;
+    let v = ClearOnDrop::new(vec![2; 3 * page_size]);
+    assert!(v.locked.len() >= 3);
+    assert_eq!(v.locked[0], v.as_ptr() as usize & !(page_size - 1));
+    let pages = v.locked.clone();
+    let counts = |pages: &[usize]| -> Vec<usize> {
+        let locked = heap_pages();
+        pages.iter().map(|page| locked.get(page).cloned().unwrap_or(0)).collect()
+    };
+    // The pages in the middle only hold these contents.
+    let middle = &pages[1..pages.len() - 1];
+    assert!(counts(middle).iter().all(|&count| count == 1));
+    drop(v);
+    assert!(counts(middle).iter().all(|&count| count == 0));
+}
+
+#[test]
+fn test_clear_on_drop_capacity() {
+    // The memory is locked before anything is written to it.
+    let page_size = *PAGE_SIZE;
+    let mut v = ClearOnDrop::new(Vec::with_capacity(3 * page_size));
+    assert!(v.is_empty());
+    assert!(v.locked.len() >= 3);
+    let (start, len) = unsafe { v.allocation() };
+    assert_eq!(v.locked, pages_of(start, len));
+
+    /// A container borrowing its memory, of which only a part is used.
+    struct Reserved<'a> {
+        memory: &'a mut [u8],
+        len: usize,
+    }
+
+    impl<'a> UnsafeAsMut for Reserved<'a> {
+        unsafe fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.memory[..self.len]
+        }
+
+        unsafe fn allocation(&mut self) -> (*mut u8, usize) {
+            (self.memory.as_mut_ptr(), self.memory.len())
+        }
+    }
+
+    // Bytes beyond the length are cleared as well.
+    let mut memory = [0xff; 64];
+    {
+        let secret = ClearOnDrop::new(Reserved { memory: &mut memory, len: 0 });
+        assert!(secret.memory.iter().all(|&b| b == 0xff));
+    }
+    assert_eq!(&memory[..], &[0; 64][..]);
+}
+
+#[test]
+fn test_clear_on_drop_zeroes_memory() {
+    /// A container borrowing its memory, so that it can be inspected after
+    /// the drop.
+    struct Borrowed<'a>(&'a mut [u8]);
+
+    impl<'a> UnsafeAsMut for Borrowed<'a> {
+        unsafe fn as_mut(&mut self) -> &mut [u8] {
+            self.0
+        }
+    }
+
+    let mut memory = [0xff; 64];
+    {
+        let secret = ClearOnDrop::new(Borrowed(&mut memory));
+        assert!(secret.0.iter().all(|&b| b == 0xff));
+    }
+    assert_eq!(&memory[..], &[0; 64][..]);
+}
+
+#[test]
+fn test_mlock_policy() {
+    assert_eq!(MlockPolicy::from_str("refuse"), Some(MlockPolicy::Refuse));
+    assert_eq!(MlockPolicy::from_str("never"), None);
+    assert_eq!(mlock_policy(), MlockPolicy::Warn);
+    assert_eq!(Error::from(Errno(libc::ENOMEM)).to_string(),
+               "the limit of locked memory (RLIMIT_MEMLOCK) was exceeded or the memory is not \
+                mapped");
+}
@@ -0,0 +1,355 @@
+//! Copy passwords to the clipboard and clear them again after a timeout.
+//!
+//! The X11 and Wayland selections are owned by `xclip` and `wl-copy`
+//! respectively, because they have to be served after `mpw` exited. Without a
+//! display server, the OSC 52 escape sequence asks the terminal to set the
+//! clipboard.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use ring::digest;
+use data_encoding::base64;
+
+use mpw::clear_on_drop::ClearOnDrop;
+
+/// Default time after which the clipboard is cleared, in seconds.
+pub const DEFAULT_CLEAR_TIMEOUT: u64 = 45;
+/// Maximal length of clipboard contents that are read back, in bytes.
+///
+/// Longer contents are certainly not a password copied by `mpw`. The buffer
+/// is allocated in advance, so that the contents are never copied by a
+/// reallocation.
+const MAX_CONTENTS_LEN: usize = 4096;
+/// Hidden subcommand of `mpw` that clears the clipboard after a timeout.
+pub const CLEAR_COMMAND: &str = "clear-clipboard";
+
+/// Clipboard kind of error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An `std::io::Error` occured.
+    Io,
+    /// A helper program failed.
+    Command,
+    /// The requested backend is unknown.
+    UnknownBackend,
+}
+
+/// Clipboard error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    // Only the message is reported by the binary.
+    #[allow(dead_code)]
+    pub kind: ErrorKind,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error {
+            message: format!("could not access clipboard: {}", e),
+            kind: ErrorKind::Io,
+        }
+    }
+}
+
+/// A clipboard that can hold a password.
+pub trait Clipboard {
+    /// Replace the contents of the clipboard.
+    fn set(&mut self, contents: &str) -> Result<(), Error>;
+
+    /// Return the contents of the clipboard.
+    ///
+    /// Returns None if the clipboard cannot be read.
+    fn get(&mut self) -> Result<Option<ClearOnDrop<String>>, Error>;
+
+    /// Clear the clipboard.
+    fn clear(&mut self) -> Result<(), Error> {
+        self.set("")
+    }
+}
+
+/// A clipboard accessed via helper programs.
+#[derive(Debug)]
+pub struct CommandClipboard {
+    copy: &'static [&'static str],
+    paste: &'static [&'static str],
+    clear: &'static [&'static str],
+}
+
+impl CommandClipboard {
+    /// Use the Wayland clipboard via `wl-copy` and `wl-paste`.
+    pub fn wayland() -> CommandClipboard {
+        CommandClipboard {
+            copy: &["wl-copy"],
+            paste: &["wl-paste", "--no-newline"],
+            clear: &["wl-copy", "--clear"],
+        }
+    }
+
+    /// Use the X11 clipboard selection via `xclip`.
+    pub fn x11() -> CommandClipboard {
+        CommandClipboard {
+            copy: &["xclip", "-selection", "clipboard", "-in"],
+            paste: &["xclip", "-selection", "clipboard", "-out"],
+            clear: &["xclip", "-selection", "clipboard", "-in", "/dev/null"],
+        }
+    }
+
+    /// Run a helper program, optionally writing the given input to it.
+    fn run(args: &[&str], input: Option<&str>) -> Result<(), Error> {
+        let mut child = Command::new(args[0])
+            .args(&args[1..])
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| Error {
+                message: format!("could not run {}: {}", args[0], e),
+                kind: ErrorKind::Command,
+            })?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error {
+                message: format!("{} failed with {}", args[0], status),
+                kind: ErrorKind::Command,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Clipboard for CommandClipboard {
+    fn set(&mut self, contents: &str) -> Result<(), Error> {
+        CommandClipboard::run(self.copy, Some(contents))
+    }
+
+    fn get(&mut self) -> Result<Option<ClearOnDrop<String>>, Error> {
+        let mut child = Command::new(self.paste[0])
+            .args(&self.paste[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| Error {
+                message: format!("could not run {}: {}", self.paste[0], e),
+                kind: ErrorKind::Command,
+            })?;
+        // One more byte than allowed is read, so that longer contents differ.
+        let limit = MAX_CONTENTS_LEN + 1;
+        let mut contents = ClearOnDrop::new(String::with_capacity(limit));
+        if let Some(stdout) = child.stdout.take() {
+            // Non-text contents are certainly not ours.
+            let _ = stdout.take(limit as u64).read_to_string(&mut contents);
+        }
+        // An empty clipboard makes some helpers fail, it is not ours either.
+        child.wait()?;
+        Ok(Some(contents))
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        CommandClipboard::run(self.clear, None)
+    }
+}
+
+/// A clipboard set by the terminal via the OSC 52 escape sequence.
+///
+/// Terminals usually refuse to report the clipboard, so it cannot be read.
+#[derive(Debug)]
+pub struct Osc52<W: Write> {
+    terminal: W,
+}
+
+impl<W: Write> Osc52<W> {
+    /// Send the escape sequences to the given terminal.
+    pub fn new(terminal: W) -> Osc52<W> {
+        Osc52 { terminal }
+    }
+}
+
+impl Osc52<::std::fs::File> {
+    /// Send the escape sequences to the controlling terminal.
+    pub fn tty() -> Result<Osc52<::std::fs::File>, Error> {
+        let terminal = OpenOptions::new().write(true).open("/dev/tty")?;
+        Ok(Osc52::new(terminal))
+    }
+}
+
+impl<W: Write> Clipboard for Osc52<W> {
+    fn set(&mut self, contents: &str) -> Result<(), Error> {
+        let encoded = ClearOnDrop::new(base64::encode(contents.as_bytes()));
+        let mut sequence = ClearOnDrop::new(String::with_capacity(encoded.len() + 8));
+        sequence.push_str("\x1b]52;c;");
+        sequence.push_str(&encoded);
+        sequence.push('\x07');
+        self.terminal.write_all(sequence.as_bytes())?;
+        self.terminal.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<Option<ClearOnDrop<String>>, Error> {
+        Ok(None)
+    }
+}
+
+/// Open the clipboard of the given backend.
+///
+/// `auto` prefers Wayland, then X11 and falls back to OSC 52.
+pub fn open(backend: &str) -> Result<Box<dyn Clipboard>, Error> {
+    let backend = match backend {
+        "auto" if env::var_os("WAYLAND_DISPLAY").is_some() => "wayland",
+        "auto" if env::var_os("DISPLAY").is_some() => "x11",
+        "auto" => "osc52",
+        backend => backend,
+    };
+    match backend {
+        "wayland" => Ok(Box::new(CommandClipboard::wayland())),
+        "x11" => Ok(Box::new(CommandClipboard::x11())),
+        "osc52" => Ok(Box::new(Osc52::tty()?)),
+        _ => Err(Error {
+            message: format!("unknown clipboard backend '{}'", backend),
+            kind: ErrorKind::UnknownBackend,
+        }),
+    }
+}
+
+/// Fingerprint of the clipboard contents, so that they don't have to be kept.
+fn fingerprint(contents: &str) -> digest::Digest {
+    digest::digest(&digest::SHA256, contents.as_bytes())
+}
+
+/// Wait for the timeout and clear the clipboard if it still holds the
+/// contents with the given fingerprint.
+///
+/// Clipboards that cannot be read are always cleared. Returns whether the
+/// clipboard was cleared.
+fn clear_after<C, F>(clipboard: &mut C, expected: &[u8], timeout: Duration, sleep: F)
+    -> Result<bool, Error>
+    where C: Clipboard + ?Sized, F: FnOnce(Duration)
+{
+    sleep(timeout);
+    if let Some(contents) = clipboard.get()? {
+        if fingerprint(&contents).as_ref() != expected {
+            return Ok(false);
+        }
+    }
+    clipboard.clear()?;
+    Ok(true)
+}
+
+/// Copy a password to the clipboard of the given backend.
+///
+/// Unless the timeout is zero, a background process clears the clipboard
+/// after the timeout, if it still holds the password. It runs `mpw` again
+/// instead of forking, so that it only knows the fingerprint of the password
+/// and none of the other secrets of this process.
+pub fn copy(backend: &str, password: &str, timeout: Duration) -> Result<(), Error> {
+    open(backend)?.set(password)?;
+    if timeout == Duration::from_secs(0) {
+        return Ok(());
+    }
+    // Don't keep a pipe to stdout open, the terminal is opened separately if
+    // needed.
+    let mut child = Command::new(env::current_exe()?)
+        .arg(CLEAR_COMMAND)
+        .arg(backend)
+        .arg(timeout.as_secs().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Other users can see the arguments, but not the pipe.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(fingerprint(password).as_ref())?;
+    }
+    Ok(())
+}
+
+/// Clear the clipboard of the given backend after the timeout, if it still
+/// holds the contents whose fingerprint is read from stdin.
+///
+/// This is run by `CLEAR_COMMAND` in the process started by `copy`. Returns
+/// whether the clipboard was cleared.
+pub fn clear_later(backend: &str, timeout: Duration) -> Result<bool, Error> {
+    let mut expected = Vec::new();
+    io::stdin().read_to_end(&mut expected)?;
+    let mut clipboard = open(backend)?;
+    clear_after(&mut *clipboard, &expected, timeout, thread::sleep)
+}
+
+#[test]
+fn test_clear_after() {
+    use std::cell::RefCell;
+
+    /// A clipboard in memory, which optionally cannot be read.
+    struct Fake<'a> {
+        contents: &'a RefCell<String>,
+        readable: bool,
+        clears: usize,
+    }
+
+    impl<'a> Clipboard for Fake<'a> {
+        fn set(&mut self, contents: &str) -> Result<(), Error> {
+            *self.contents.borrow_mut() = contents.into();
+            Ok(())
+        }
+
+        fn get(&mut self) -> Result<Option<ClearOnDrop<String>>, Error> {
+            Ok(if self.readable {
+                Some(ClearOnDrop::new(self.contents.borrow().clone()))
+            } else {
+                None
+            })
+        }
+
+        fn clear(&mut self) -> Result<(), Error> {
+            self.clears += 1;
+            self.set("")
+        }
+    }
+
+    let timeout = Duration::from_secs(45);
+    let contents = RefCell::new(String::new());
+    let mut clipboard = Fake { contents: &contents, readable: true, clears: 0 };
+    clipboard.set("secret").unwrap();
+    let digest = fingerprint("secret");
+    let secret = digest.as_ref();
+
+    // The clipboard is cleared after the timeout if it is unchanged.
+    let mut slept = None;
+    assert!(clear_after(&mut clipboard, secret, timeout, |t| slept = Some(t)).unwrap());
+    assert_eq!(slept, Some(timeout));
+    assert_eq!(*contents.borrow(), "");
+    assert_eq!(clipboard.clears, 1);
+
+    // Something else was copied in the meantime.
+    clipboard.set("secret").unwrap();
+    let cleared = clear_after(&mut clipboard, secret, timeout, |_| {
+        *contents.borrow_mut() = "something else".into();
+    }).unwrap();
+    assert!(!cleared);
+    assert_eq!(*contents.borrow(), "something else");
+    assert_eq!(clipboard.clears, 1);
+
+    // Clipboards that cannot be read are cleared anyway.
+    clipboard.readable = false;
+    assert!(clear_after(&mut clipboard, secret, timeout, |_| {}).unwrap());
+    assert_eq!(*contents.borrow(), "");
+    assert_eq!(clipboard.clears, 2);
+}
+
+#[test]
+fn test_osc52() {
+    let mut clipboard = Osc52::new(Vec::new());
+    clipboard.set("secret").unwrap();
+    clipboard.clear().unwrap();
+    assert!(clipboard.get().unwrap().is_none());
+    assert_eq!(clipboard.terminal, b"\x1b]52;c;c2VjcmV0\x07\x1b]52;c;\x07");
+}
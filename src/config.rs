@@ -1,9 +1,50 @@
+extern crate data_encoding;
 extern crate toml;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+use self::data_encoding::base64;
+
+use algorithm::{SiteType, SiteVariant, AlgorithmVersion, Templates, Policy, is_current_format,
+    is_bound_format};
+use secret::SecretVec;
+
+
+/// The environment variable naming the config file.
+pub const CONFIG_ENV_VAR: &str = "MPW_CONFIG";
+
+/// Return where the config file is expected if none is given.
+///
+/// This is `$MPW_CONFIG`, `$XDG_CONFIG_HOME/mpw/config.toml` or
+/// `~/.config/mpw/config.toml`, whichever is set first. Returns None if not
+/// even the home directory is known.
+pub fn default_path() -> Option<PathBuf> {
+    default_path_from(env::var_os(CONFIG_ENV_VAR), env::var_os("XDG_CONFIG_HOME"),
+                      env::var_os("HOME"))
+}
 
-use algorithm::{SiteType, SiteVariant};
-
+/// Determine the default path of the config file from the environment.
+///
+/// Empty variables count as unset, like the XDG base directory
+/// specification demands.
+fn default_path_from(mpw_config: Option<OsString>, config_home: Option<OsString>,
+                     home: Option<OsString>)
+    -> Option<PathBuf>
+{
+    let set = |var: Option<OsString>| var.filter(|v| !v.is_empty()).map(PathBuf::from);
+    if let Some(path) = set(mpw_config) {
+        return Some(path);
+    }
+    set(config_home)
+        .or_else(|| set(home).map(|home| home.join(".config")))
+        .map(|dir| dir.join("mpw").join("config.toml"))
+}
 
 /// Merge two options, prefering Some and the new one.
 pub fn merge_options<T>(old: Option<T>, new: Option<T>) -> Option<T> {
@@ -16,14 +57,20 @@ pub fn merge_options<T>(old: Option<T>, new: Option<T>) -> Option<T> {
 }
 
 /// Configuration kind of error.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     /// Tried to merge configs for different full names.
     ConflictingFullName,
+    /// Tried to merge configs for different master keys.
+    ConflictingKeyId,
     /// Tried to merge configs with conflicting stored passwords.
     ConflictingStoredPasswords,
     /// Got a stored password when supposed to generate one.
     ConflictingStoredGenerated,
+    /// A custom template or character class is invalid.
+    InvalidTemplate,
+    /// A site refers to a template that is not defined.
+    UnknownTemplate,
 }
 
 /// Master Password algorithm error.
@@ -34,35 +81,70 @@ pub struct Error {
     pub kind: ErrorKind,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
         let message = match kind {
             ErrorKind::ConflictingFullName
                 => "can only merge configs for the same site",
+            ErrorKind::ConflictingKeyId
+                => "can only merge configs for the same master key",
             ErrorKind::ConflictingStoredPasswords
                 => "cannot merge two encrypted passwords for the same site",
             ErrorKind::ConflictingStoredGenerated
                 => "got a stored password for a supposedly generated password",
+            ErrorKind::InvalidTemplate
+                => "invalid custom template",
+            ErrorKind::UnknownTemplate
+                => "unknown template",
         };
-        Error { message: message.into(), kind: kind }
+        Error { message: message.into(), kind }
     }
 }
 
 /// Represent the configuration state that can be stored on disk.
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
 pub struct Config<'a> {
     #[serde(borrow)]
     pub full_name: Option<Cow<'a, str>>,
+    /// Identifies the master key, so that a mistyped master password is
+    /// detected before any password is shown.
+    pub key_id: Option<String>,
+    /// The identicon of the user, to warn about a mistyped master password.
+    pub identicon: Option<String>,
+    /// Custom templates by name, usable as the type of a site.
+    pub templates: Option<BTreeMap<String, Vec<String>>>,
+    /// Custom character classes, usable by custom templates.
+    pub classes: Option<BTreeMap<String, String>>,
     pub sites: Option<Vec<SiteConfig<'a>>>,
 }
 
 impl<'a> Config<'a> {
     /// Create a new empty configuration.
     pub fn new() -> Config<'a> {
-        Config { full_name: None, sites: None }
+        Config {
+            full_name: None,
+            key_id: None,
+            identicon: None,
+            templates: None,
+            classes: None,
+            sites: None,
+        }
     }
 
     /// Try to create a configuration given a TOML string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &'a str) -> Result<Config<'a>, toml::de::Error> {
         toml::from_str(s)
     }
@@ -79,6 +161,18 @@ impl<'a> Config<'a> {
         if other.full_name.is_some() {
             self.full_name = other.full_name;
         }
+        if other.key_id.is_some() {
+            self.key_id = other.key_id;
+        }
+        if other.identicon.is_some() {
+            self.identicon = other.identicon;
+        }
+        if let Some(other_templates) = other.templates {
+            self.templates.get_or_insert_with(BTreeMap::new).extend(other_templates);
+        }
+        if let Some(other_classes) = other.classes {
+            self.classes.get_or_insert_with(BTreeMap::new).extend(other_classes);
+        }
         if let Some(other_sites) = other.sites {
             if let Some(ref mut sites) = self.sites {
                 sites.extend(other_sites);
@@ -87,6 +181,41 @@ impl<'a> Config<'a> {
             }
         }
     }
+
+    /// Collect the custom templates and character classes.
+    ///
+    /// Fails if any of them is invalid, for instance because a template is too
+    /// long to be generated from the seed of a site, or if a site refers to a
+    /// template that is not defined.
+    pub fn templates(&self) -> Result<Templates, Error> {
+        let invalid = |message: String| Error { message, kind: ErrorKind::InvalidTemplate };
+        let mut templates = Templates::new();
+        if let Some(ref classes) = self.classes {
+            for (name, characters) in classes {
+                let mut chars = name.chars();
+                let class = match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return Err(invalid(format!(
+                        "character class '{}' must be named by a single character", name))),
+                };
+                templates.add_class(class, characters).map_err(|e| invalid(e.message))?;
+            }
+        }
+        if let Some(ref custom) = self.templates {
+            for (name, list) in custom {
+                templates.add_template(name, list.clone()).map_err(|e| invalid(e.message))?;
+            }
+        }
+        for site in self.sites.iter().flat_map(|sites| sites.iter()) {
+            if let Some(ref type_) = site.type_ {
+                templates.check_type(type_).map_err(|e| Error {
+                    message: format!("{}: {}", site.name, e.message),
+                    kind: ErrorKind::UnknownTemplate,
+                })?;
+            }
+        }
+        Ok(templates)
+    }
 }
 
 /// The configuration that can be stored about a site.
@@ -100,20 +229,41 @@ pub struct SiteConfig<'a> {
     pub variant: Option<SiteVariant>,
     #[serde(borrow)]
     pub context: Option<Cow<'a, str>>,
+    pub algorithm: Option<AlgorithmVersion>,
+    #[serde(borrow)]
+    pub login_name: Option<Cow<'a, str>>,
+    /// Other names to find the site by, they do not change the password.
+    #[serde(borrow)]
+    pub aliases: Option<Vec<Cow<'a, str>>>,
+    /// Labels to find the site by.
+    #[serde(borrow)]
+    pub tags: Option<Vec<Cow<'a, str>>>,
     #[serde(borrow)]
     pub encrypted: Option<Cow<'a, str>>,
+    /// Extensions of the official apps as JSON, like the URL of the site.
+    /// They are not used, only kept for exporting them again.
+    #[serde(borrow)]
+    pub ext_mpw: Option<Cow<'a, str>>,
+    // This has to be the last field, because it is encoded as a TOML table.
+    pub policy: Option<Policy>,
 }
 
 impl<'a> SiteConfig<'a> {
     /// Create a new site configuration with the given domain name.
-    pub fn new(name: &'a str) -> SiteConfig<'a> {
+    pub fn new<S: Into<Cow<'a, str>>>(name: S) -> SiteConfig<'a> {
         SiteConfig {
             name: name.into(),
             type_: None,
             counter: None,
             variant: None,
             context: None,
+            algorithm: None,
+            login_name: None,
+            aliases: None,
+            tags: None,
             encrypted: None,
+            ext_mpw: None,
+            policy: None,
         }
     }
 
@@ -125,15 +275,31 @@ impl<'a> SiteConfig<'a> {
         if self.name != other.name {
             return Err(Error::from(ErrorKind::ConflictingFullName));
         }
-        self.type_ = merge_options(self.type_, other.type_);
+        self.type_ = merge_options(self.type_.take(), other.type_);
         self.counter = merge_options(self.counter, other.counter);
         self.variant = merge_options(self.variant, other.variant);
+        self.algorithm = merge_options(self.algorithm, other.algorithm);
         if !(self.encrypted.is_none() && other.encrypted.is_none()) {
             return Err(Error::from(ErrorKind::ConflictingStoredPasswords));
         }
         if other.context.is_some() {
             self.context = other.context;
         }
+        if other.login_name.is_some() {
+            self.login_name = other.login_name;
+        }
+        if other.aliases.is_some() {
+            self.aliases = other.aliases;
+        }
+        if other.tags.is_some() {
+            self.tags = other.tags;
+        }
+        if other.ext_mpw.is_some() {
+            self.ext_mpw = other.ext_mpw;
+        }
+        if other.policy.is_some() {
+            self.policy = other.policy;
+        }
         Ok(())
     }
 }
@@ -146,18 +312,18 @@ pub struct Site<'a> {
     pub counter: u32,
     pub variant: SiteVariant,
     pub context: Cow<'a, str>,
+    pub algorithm: AlgorithmVersion,
+    pub login_name: Option<Cow<'a, str>>,
     pub encrypted: Option<Cow<'a, str>>,
+    pub policy: Policy,
 }
 
 impl<'a> Site<'a> {
     /// Create a site from a given config. Missing values are filled with defaults.
     pub fn from_config(config: &'a SiteConfig<'a>) -> Result<Site<'a>, Error> {
         let variant = config.variant.unwrap_or(SiteVariant::Password);
-        let encrypted = match config.encrypted {
-            Some(ref s) => Some(s.as_ref().into()),
-            None => None,
-        };
-        let type_ = config.type_.unwrap_or(
+        let encrypted = config.encrypted.as_ref().map(|s| s.as_ref().into());
+        let type_ = config.type_.clone().unwrap_or_else(||
             if encrypted.is_none() {
                 match variant {
                     SiteVariant::Password => SiteType::GeneratedLong,
@@ -178,13 +344,44 @@ impl<'a> Site<'a> {
 
         Ok(Site {
             name: config.name.as_ref().into(),
-            type_: type_,
+            type_,
             counter: config.counter.unwrap_or(1),
-            variant: variant,
-            context: context,
-            encrypted: encrypted,
+            variant,
+            context,
+            algorithm: config.algorithm.unwrap_or(AlgorithmVersion::latest()),
+            login_name: config.login_name.as_ref().map(|s| s.as_ref().into()),
+            encrypted,
+            policy: config.policy.clone().unwrap_or_default(),
         })
     }
+
+    /// Decode the `encrypted` field into protected memory, where it can be
+    /// decrypted in place.
+    pub fn decode_stored(&self) -> Result<SecretVec, ::algorithm::Error> {
+        let invalid = |message: &str| ::algorithm::Error {
+            message: message.into(),
+            kind: ::algorithm::ErrorKind::InvalidStoredPassword,
+        };
+        let encrypted = self.encrypted.as_ref()
+            .ok_or_else(|| invalid("stored password without 'encrypted' field"))?;
+        base64::decode(encrypted.as_bytes())
+            .map(|decoded| SecretVec::from_slice(&decoded))
+            .map_err(|_| invalid("could not decode 'encrypted' field"))
+    }
+
+    /// Whether the password is stored in an old encryption format, so that it
+    /// should be encrypted again.
+    pub fn has_outdated_encryption(&self) -> bool {
+        self.encrypted.is_some() &&
+            self.decode_stored().is_ok_and(|encrypted| !is_current_format(&encrypted.borrow()))
+    }
+
+    /// Whether the password was stored before passwords were bound to their
+    /// site, so that it is only decrypted by `mpw reencrypt --legacy`.
+    pub fn has_unbound_encryption(&self) -> bool {
+        self.encrypted.is_some() &&
+            self.decode_stored().is_ok_and(|encrypted| !is_bound_format(&encrypted.borrow()))
+    }
 }
 
 #[test]
@@ -209,6 +406,9 @@ fn test_config_encode() {
     assert_eq!(c.encode().unwrap(), "");
     c.full_name = Some("John Doe".into());
     assert_eq!(c.encode().unwrap(), "full_name = \"John Doe\"\n");
+    c.key_id = Some("98EEF4D1".into());
+    assert_eq!(c.encode().unwrap(), "full_name = \"John Doe\"\nkey_id = \"98EEF4D1\"\n");
+    c.key_id = None;
 
     let wikipedia = SiteConfig::new("wikipedia.org");
     c.sites = Some(vec![wikipedia]);
@@ -275,3 +475,128 @@ type = "maximum"
 
     assert_eq!(config, expected_config);
 }
+
+#[test]
+fn test_aliases_decode() {
+    let config_str = r#"[[sites]]
+name = "github.com"
+aliases = ["gh", "github"]
+tags = ["work"]
+"#;
+    let config = Config::from_str(config_str).unwrap();
+    let sites = config.sites.as_ref().unwrap();
+    assert_eq!(sites[0].aliases, Some(vec!["gh".into(), "github".into()]));
+    assert_eq!(sites[0].tags, Some(vec!["work".into()]));
+    assert_eq!(config.encode().unwrap(), config_str);
+}
+
+#[test]
+fn test_algorithm_decode() {
+    let config_str = r#"[[sites]]
+name = "github.com"
+algorithm = 2
+"#;
+    let config = Config::from_str(config_str).unwrap();
+    let sites = config.sites.as_ref().unwrap();
+    assert_eq!(sites[0].algorithm, Some(AlgorithmVersion::V2));
+    assert_eq!(Site::from_config(&sites[0]).unwrap().algorithm, AlgorithmVersion::V2);
+    assert_eq!(config.encode().unwrap(), config_str);
+
+    assert!(Config::from_str("[[sites]]\nname = \"github.com\"\nalgorithm = 4\n").is_err());
+}
+
+#[test]
+fn test_templates_decode() {
+    let s = r#"
+full_name = "John Doe"
+
+[templates]
+corporate = ["Cvcvdddd", "ddddCvcv"]
+
+[classes]
+d = "23456789"
+
+[[sites]]
+name = "intranet"
+type = "corporate"
+"#;
+    let config = Config::from_str(s).unwrap();
+    assert_eq!(config.sites.as_ref().unwrap()[0].type_,
+               Some(SiteType::Custom("corporate".into())));
+    assert!(config.templates().is_ok());
+    let encoded = config.encode().unwrap();
+    assert_eq!(Config::from_str(&encoded).unwrap(), config);
+
+    let s = r#"
+[classes]
+dd = "23456789"
+"#;
+    let config = Config::from_str(s).unwrap();
+    assert_eq!(config.templates().unwrap_err().message,
+               "character class 'dd' must be named by a single character");
+
+    let s = r#"
+[templates]
+corporate = ["Cvcvdddd"]
+"#;
+    let config = Config::from_str(s).unwrap();
+    assert_eq!(config.templates().unwrap_err().message,
+               "cannot define template 'corporate': unknown character class 'd'");
+
+    let s = r#"
+[templates]
+corporate = ["Cvcvnnnn"]
+
+[[sites]]
+name = "github.com"
+type = "lnog"
+"#;
+    let config = Config::from_str(s).unwrap();
+    let e = config.templates().unwrap_err();
+    assert_eq!(e.kind, ErrorKind::UnknownTemplate);
+    assert_eq!(e.message, "github.com: unknown template 'lnog', expected one of: maximum, long, \
+                           medium, basic, short, pin, name, phrase, stored, corporate");
+}
+
+#[test]
+fn test_policy_decode() {
+    use algorithm::CharacterRequirement;
+
+    let s = r#"
+[[sites]]
+name = "bank.com"
+type = "maximum"
+
+[sites.policy]
+max_length = 16
+required = ["uppercase", "digit", "symbol"]
+forbidden = "'"
+
+[[sites]]
+name = "github.com"
+"#;
+    let config = Config::from_str(s).unwrap();
+    let sites = config.sites.as_ref().unwrap();
+    let site = Site::from_config(&sites[0]).unwrap();
+    assert_eq!(site.policy.max_length, Some(16));
+    assert_eq!(site.policy.required, Some(vec![
+        CharacterRequirement::Uppercase, CharacterRequirement::Digit,
+        CharacterRequirement::Symbol,
+    ]));
+    assert_eq!(site.policy.forbidden, Some("'".into()));
+    assert!(Site::from_config(&sites[1]).unwrap().policy.is_empty());
+    let encoded = config.encode().unwrap();
+    assert_eq!(Config::from_str(&encoded).unwrap(), config);
+}
+
+#[test]
+fn test_default_path() {
+    let os = |s: &str| Some(OsString::from(s));
+    assert_eq!(default_path_from(os("/a/mpw.toml"), os("/b"), os("/home/c")),
+               Some(PathBuf::from("/a/mpw.toml")));
+    assert_eq!(default_path_from(None, os("/b"), os("/home/c")),
+               Some(PathBuf::from("/b/mpw/config.toml")));
+    assert_eq!(default_path_from(os(""), os(""), os("/home/c")),
+               Some(PathBuf::from("/home/c/.config/mpw/config.toml")));
+    assert_eq!(default_path_from(None, None, None), None);
+}
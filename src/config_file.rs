@@ -0,0 +1,359 @@
+//! Read and write the config file without losing it.
+//!
+//! The config file may hold the only copy of stored passwords, so it is never
+//! truncated: a new version is written to a temporary file, synced to disk and
+//! renamed over the old one. The old version is kept as a backup. Concurrent
+//! runs are serialized by an advisory lock on a separate lock file, because
+//! the config file itself is replaced on every write. If the lock file cannot
+//! be created, the config is still read without a lock, since every version
+//! of it is complete.
+
+extern crate libc;
+
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// How many old versions of the config file are kept, as `<path>.bak.1`
+/// (the latest) to `<path>.bak.3`.
+pub const BACKUPS: usize = 3;
+
+/// Config file kind of error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An `std::io::Error` occured.
+    Io,
+    /// The file is locked by another process.
+    Locked,
+}
+
+/// Config file error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Error {
+    /// Create an error about accessing the given path.
+    fn io(what: &str, path: &Path, e: io::Error) -> Error {
+        Error {
+            message: format!("could not {} {}: {}", what, path.display(), e),
+            kind: ErrorKind::Io,
+        }
+    }
+}
+
+/// How a config file is locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    /// The file is only read, other readers are allowed.
+    Shared,
+    /// The file is read and written, nobody else may access it.
+    Exclusive,
+}
+
+/// A config file, locked against concurrent changes while this exists.
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: PathBuf,
+    /// The lock file, None while reading without a lock.
+    lock_file: Option<File>,
+    lock: Lock,
+}
+
+/// Append a suffix to a path.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path.as_os_str());
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+/// Return the path of a backup, counting from 1 for the latest.
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    with_suffix(path, &format!(".bak.{}", n))
+}
+
+/// Lock a file, waiting for other processes only if `wait` is set.
+fn flock(file: &File, lock: Lock, wait: bool) -> io::Result<()> {
+    let mut operation = match lock {
+        Lock::Shared => libc::LOCK_SH,
+        Lock::Exclusive => libc::LOCK_EX,
+    };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Lock the config file at the given path, waiting for other processes
+    /// that hold a conflicting lock.
+    pub fn open<P: AsRef<Path>>(path: P, lock: Lock) -> Result<ConfigFile, Error> {
+        ConfigFile::open_with(path.as_ref(), lock, true)
+    }
+
+    /// Lock the config file at the given path, failing with
+    /// `ErrorKind::Locked` if another process holds a conflicting lock.
+    pub fn try_open<P: AsRef<Path>>(path: P, lock: Lock) -> Result<ConfigFile, Error> {
+        ConfigFile::open_with(path.as_ref(), lock, false)
+    }
+
+    fn open_with(path: &Path, lock: Lock, wait: bool) -> Result<ConfigFile, Error> {
+        // Replace the target of a symbolic link instead of the link itself.
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let mut file = ConfigFile { path, lock_file: None, lock: Lock::Shared };
+        match lock {
+            Lock::Shared => file.lock_shared(wait)?,
+            Lock::Exclusive => file.lock_exclusive_with(wait)?,
+        }
+        Ok(file)
+    }
+
+    /// Return the path of the lock file.
+    fn lock_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".lock")
+    }
+
+    /// Open the lock file, creating it if necessary.
+    fn open_lock_file(&self) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .mode(0o600)
+            .open(self.lock_path())
+    }
+
+    /// Lock the lock file shared, if it can be created.
+    ///
+    /// Nothing else is created, so that configs in read-only or missing
+    /// directories can still be read.
+    fn lock_shared(&mut self, wait: bool) -> Result<(), Error> {
+        let lock_file = match self.open_lock_file() {
+            Ok(lock_file) => lock_file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound ||
+                e.kind() == io::ErrorKind::PermissionDenied ||
+                e.raw_os_error() == Some(libc::EROFS) => return Ok(()),
+            Err(e) => return Err(Error::io("open lock file", &self.lock_path(), e)),
+        };
+        self.lock_file = Some(lock_file);
+        self.lock_with(Lock::Shared, wait)
+    }
+
+    /// Lock the lock file exclusively, creating it and its directory if
+    /// necessary.
+    fn lock_exclusive_with(&mut self, wait: bool) -> Result<(), Error> {
+        if self.lock == Lock::Exclusive && self.lock_file.is_some() {
+            return Ok(());
+        }
+        if self.lock_file.is_none() {
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+                    .map_err(|e| Error::io("create directory", dir, e))?;
+            }
+            let lock_file = self.open_lock_file()
+                .map_err(|e| Error::io("open lock file", &self.lock_path(), e))?;
+            self.lock_file = Some(lock_file);
+        }
+        self.lock_with(Lock::Exclusive, wait)?;
+        self.lock = Lock::Exclusive;
+        Ok(())
+    }
+
+    /// Lock the lock file, which has to be open.
+    fn lock_with(&self, lock: Lock, wait: bool) -> Result<(), Error> {
+        let lock_file = self.lock_file.as_ref().unwrap();
+        //^ This unwrap is safe, because the callers opened the lock file.
+        flock(lock_file, lock, wait).map_err(|e| {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                Error {
+                    message: format!("{} is locked by another process", self.path.display()),
+                    kind: ErrorKind::Locked,
+                }
+            } else {
+                Error::io("lock", &self.lock_path(), e)
+            }
+        })
+    }
+
+    /// Return the path of the config file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the config file, returning None if it does not exist.
+    pub fn read(&self) -> Result<Option<String>, Error> {
+        let mut s = String::new();
+        match File::open(&self.path) {
+            Ok(mut f) => f.read_to_string(&mut s)
+                .map_err(|e| Error::io("read", &self.path, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::io("open", &self.path, e)),
+        };
+        Ok(Some(s))
+    }
+
+    /// Lock the config file exclusively, so that it can be written.
+    ///
+    /// Changing the lock is not atomic, so the file should be read again
+    /// afterwards.
+    pub fn lock_exclusive(&mut self) -> Result<(), Error> {
+        self.lock_exclusive_with(true)
+    }
+
+    /// Replace the contents of the config file.
+    ///
+    /// The previous version is kept as the latest backup. New files are only
+    /// accessible by the user, existing ones keep their permissions.
+    pub fn write(&mut self, contents: &str) -> Result<(), Error> {
+        self.lock_exclusive()?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        // A leftover from a crash is not needed, because nobody else writes
+        // while the lock is held.
+        let _ = fs::remove_file(&tmp_path);
+        let result = self.write_tmp(&tmp_path, contents)
+            .and_then(|_| self.rotate_backups())
+            .and_then(|_| fs::rename(&tmp_path, &self.path)
+                      .map_err(|e| Error::io("replace", &self.path, e)));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return result;
+        }
+        // Make sure the rename itself is on disk.
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            File::open(dir).and_then(|d| d.sync_all())
+                .map_err(|e| Error::io("sync directory", dir, e))?;
+        }
+        Ok(())
+    }
+
+    /// Write the new contents to a temporary file and sync it to disk.
+    fn write_tmp(&self, tmp_path: &Path, contents: &str) -> Result<(), Error> {
+        let mut f = OpenOptions::new().write(true).create_new(true).mode(0o600)
+            .open(tmp_path)
+            .map_err(|e| Error::io("create", tmp_path, e))?;
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            f.set_permissions(metadata.permissions())
+                .map_err(|e| Error::io("set permissions of", tmp_path, e))?;
+        }
+        f.write_all(contents.as_bytes())
+            .and_then(|_| f.sync_all())
+            .map_err(|e| Error::io("write", tmp_path, e))
+    }
+
+    /// Shift the backups by one, making the current file the latest backup.
+    ///
+    /// The current file stays in place, it is only linked as a backup.
+    fn rotate_backups(&self) -> Result<(), Error> {
+        if BACKUPS == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for n in (1..BACKUPS).rev() {
+            let from = backup_path(&self.path, n);
+            match fs::rename(&from, backup_path(&self.path, n + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                r => r.map_err(|e| Error::io("rotate backup", &from, e))?,
+            }
+        }
+        let latest = backup_path(&self.path, 1);
+        match fs::remove_file(&latest) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            r => r.map_err(|e| Error::io("remove backup", &latest, e))?,
+        }
+        fs::hard_link(&self.path, &latest)
+            .or_else(|_| fs::copy(&self.path, &latest).map(|_| ()))
+            .map_err(|e| Error::io("back up", &self.path, e))
+    }
+}
+
+#[test]
+fn test_config_file_write() {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("mpw-test-config-file-write-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("mpw").join("config.toml");
+    let mut file = ConfigFile::open(&path, Lock::Shared).unwrap();
+    assert_eq!(file.read().unwrap(), None);
+    file.write("full_name = \"John Doe\"\n").unwrap();
+    assert_eq!(file.read().unwrap().unwrap(), "full_name = \"John Doe\"\n");
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::metadata(path.parent().unwrap()).unwrap().permissions().mode() & 0o777,
+               0o700);
+    assert!(!backup_path(&path, 1).exists());
+
+    for i in 0..BACKUPS + 2 {
+        file.write(&i.to_string()).unwrap();
+    }
+    let read = |p: PathBuf| {
+        let mut s = String::new();
+        File::open(p).unwrap().read_to_string(&mut s).unwrap();
+        s
+    };
+    assert_eq!(read(path.clone()), (BACKUPS + 1).to_string());
+    for n in 1..BACKUPS + 1 {
+        assert_eq!(read(backup_path(&path, n)), (BACKUPS + 1 - n).to_string());
+    }
+    assert!(!backup_path(&path, BACKUPS + 1).exists());
+    assert!(!with_suffix(&path, ".tmp").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_file_lock() {
+    use std::env;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("mpw-test-config-file-lock-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    // Locks belong to open files, so they conflict within a process, too.
+    let shared = ConfigFile::try_open(&path, Lock::Shared).unwrap();
+    assert!(ConfigFile::try_open(&path, Lock::Shared).is_ok());
+    assert_eq!(ConfigFile::try_open(&path, Lock::Exclusive).unwrap_err().kind,
+               ErrorKind::Locked);
+    drop(shared);
+    let mut exclusive = ConfigFile::try_open(&path, Lock::Shared).unwrap();
+    exclusive.lock_exclusive().unwrap();
+    assert_eq!(ConfigFile::try_open(&path, Lock::Shared).unwrap_err().kind, ErrorKind::Locked);
+    drop(exclusive);
+    assert!(ConfigFile::try_open(&path, Lock::Exclusive).is_ok());
+
+    // Reading does not create a missing directory, writing does.
+    let path = dir.join("missing").join("config.toml");
+    let mut shared = ConfigFile::try_open(&path, Lock::Shared).unwrap();
+    assert!(!dir.join("missing").exists());
+    assert_eq!(shared.read().unwrap(), None);
+    shared.lock_exclusive().unwrap();
+    assert!(dir.join("missing").join("config.toml.lock").exists());
+    assert_eq!(ConfigFile::try_open(&path, Lock::Shared).unwrap_err().kind, ErrorKind::Locked);
+    drop(shared);
+    fs::remove_dir_all(&dir).unwrap();
+}
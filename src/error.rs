@@ -0,0 +1,92 @@
+//! The error type of the crate, which the errors of all modules convert into.
+
+extern crate toml;
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use {algorithm, config, config_file, mpsites};
+
+/// Kind of error, telling which module it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Deriving, encrypting or decrypting a password failed.
+    Algorithm(algorithm::ErrorKind),
+    /// A config is invalid or could not be merged.
+    Config(config::ErrorKind),
+    /// A config could not be parsed or encoded as TOML.
+    Toml,
+    /// The config file could not be accessed.
+    ConfigFile(config_file::ErrorKind),
+    /// Importing or exporting `.mpsites` failed.
+    Mpsites(mpsites::ErrorKind),
+}
+
+/// Error of any module of the crate.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<algorithm::Error> for Error {
+    fn from(e: algorithm::Error) -> Error {
+        Error { message: e.message, kind: ErrorKind::Algorithm(e.kind) }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Error {
+        Error { message: e.message, kind: ErrorKind::Config(e.kind) }
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        Error { message: format!("could not parse config: {}", e), kind: ErrorKind::Toml }
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Error {
+        Error { message: format!("could not encode config: {}", e), kind: ErrorKind::Toml }
+    }
+}
+
+impl From<config_file::Error> for Error {
+    fn from(e: config_file::Error) -> Error {
+        Error { message: e.message, kind: ErrorKind::ConfigFile(e.kind) }
+    }
+}
+
+impl From<mpsites::Error> for Error {
+    fn from(e: mpsites::Error) -> Error {
+        Error { message: e.message, kind: ErrorKind::Mpsites(e.kind) }
+    }
+}
+
+#[test]
+fn test_error_from() {
+    fn parse(s: &str) -> Result<::config::Config<'_>, Error> {
+        let config = ::config::Config::from_str(s)?;
+        config.templates()?;
+        Ok(config)
+    }
+
+    assert!(parse("full_name = \"John Doe\"").is_ok());
+    assert_eq!(parse("full_name = ").unwrap_err().kind, ErrorKind::Toml);
+    assert_eq!(parse("[templates]\nlong = [\"nnnn\"]").unwrap_err().kind,
+               ErrorKind::Config(config::ErrorKind::InvalidTemplate));
+}
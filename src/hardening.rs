@@ -0,0 +1,326 @@
+//! Harden the process against leaking secrets via core dumps, debuggers and
+//! swap, and report on how well it is protected.
+
+extern crate libc;
+
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Hardening error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Error {
+    /// Create an error from the last error of the operating system.
+    fn last_os_error(what: &str) -> Error {
+        Error { message: format!("could not {}: {}", what, io::Error::last_os_error()) }
+    }
+}
+
+/// Prevent core dumps of the process, so that secrets are not written to disk
+/// when it crashes.
+pub fn disable_core_dumps() -> Result<(), Error> {
+    let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(Error::last_os_error("disable core dumps"));
+    }
+    Ok(())
+}
+
+/// Prevent other processes of the user from attaching to the process with
+/// `ptrace`, and from reading its memory via `/proc`.
+#[cfg(target_os = "linux")]
+pub fn disable_ptrace() -> Result<(), Error> {
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0 as libc::c_ulong) } != 0 {
+        return Err(Error::last_os_error("make the process undumpable"));
+    }
+    Ok(())
+}
+
+/// Prevent other processes of the user from attaching to the process with
+/// `ptrace`, and from reading its memory via `/proc`.
+#[cfg(not(target_os = "linux"))]
+pub fn disable_ptrace() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Fail if a library is preloaded via `LD_PRELOAD`, because it can read all
+/// secrets of the process.
+pub fn check_preload() -> Result<(), Error> {
+    match env::var_os("LD_PRELOAD") {
+        Some(ref preload) if !preload.is_empty() => Err(Error {
+            message: format!("refusing to run with LD_PRELOAD set to '{}'",
+                             preload.to_string_lossy()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Return the soft and hard limit of a resource in bytes, `None` meaning
+/// unlimited.
+fn rlimit(resource: libc::c_int) -> io::Result<(Option<u64>, Option<u64>)> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // `rlim_t` is narrower than `u64` on some platforms.
+    #[allow(clippy::unnecessary_cast)]
+    let bytes = |l| if l == libc::RLIM_INFINITY { None } else { Some(l as u64) };
+    Ok((bytes(limit.rlim_cur), bytes(limit.rlim_max)))
+}
+
+/// Return whether the process is dumpable, if known.
+#[cfg(target_os = "linux")]
+fn is_dumpable() -> Option<bool> {
+    match unsafe { libc::prctl(libc::PR_GET_DUMPABLE) } {
+        r if r < 0 => None,
+        r => Some(r != 0),
+    }
+}
+
+/// Return whether the process is dumpable, if known.
+#[cfg(not(target_os = "linux"))]
+fn is_dumpable() -> Option<bool> {
+    None
+}
+
+/// Read a file of the `/proc` file system.
+fn read_proc(path: &str) -> io::Result<String> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Parse the amount of locked memory in bytes from `/proc/self/status`.
+fn parse_locked(status: &str) -> Option<u64> {
+    status.lines()
+        .find(|line| line.starts_with("VmLck:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+}
+
+/// A swap area.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Swap {
+    /// The device or file.
+    pub path: String,
+    /// The type, usually 'partition' or 'file'.
+    pub kind: String,
+    /// The size in bytes.
+    pub size: u64,
+    /// The used size in bytes.
+    pub used: u64,
+}
+
+impl Swap {
+    /// Return whether the swap area is kept in compressed memory instead of
+    /// being written to disk.
+    pub fn is_in_memory(&self) -> bool {
+        self.path.starts_with("/dev/zram")
+    }
+}
+
+/// Parse the swap areas from `/proc/swaps`.
+fn parse_swaps(swaps: &str) -> Vec<Swap> {
+    swaps.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return None;
+        }
+        Some(Swap {
+            // Spaces in paths are escaped as `\040`.
+            path: fields[0].replace("\\040", " "),
+            kind: fields[1].into(),
+            size: fields[2].parse::<u64>().ok()? * 1024,
+            used: fields[3].parse::<u64>().ok()? * 1024,
+        })
+    }).collect()
+}
+
+/// How well the process is protected against leaking secrets.
+///
+/// Limits are in bytes, `None` meaning unlimited. Other fields are `None` if
+/// they are unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// The maximal size of core dumps.
+    pub core_limit: Option<u64>,
+    /// Whether the process can be dumped and traced by other processes of
+    /// the user.
+    pub dumpable: Option<bool>,
+    /// The libraries preloaded via `LD_PRELOAD`.
+    pub preload: Option<String>,
+    /// The soft and hard limit of memory that can be locked.
+    pub memlock_limit: Option<(Option<u64>, Option<u64>)>,
+    /// The amount of memory that is currently locked.
+    pub locked: Option<u64>,
+    /// The active swap areas.
+    pub swaps: Option<Vec<Swap>>,
+}
+
+impl Report {
+    /// Inspect the current process.
+    pub fn collect() -> Report {
+        Report {
+            core_limit: rlimit(libc::RLIMIT_CORE).ok().and_then(|(soft, _)| soft),
+            dumpable: is_dumpable(),
+            preload: env::var_os("LD_PRELOAD")
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string_lossy().into_owned()),
+            memlock_limit: rlimit(libc::RLIMIT_MEMLOCK).ok(),
+            locked: read_proc("/proc/self/status").ok().and_then(|s| parse_locked(&s)),
+            swaps: read_proc("/proc/swaps").ok().map(|s| parse_swaps(&s)),
+        }
+    }
+
+    /// Return warnings about weaknesses of the protection.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.core_limit != Some(0) {
+            warnings.push("core dumps are enabled, they may contain secrets".into());
+        }
+        if self.dumpable == Some(true) {
+            warnings.push("the process is dumpable, other processes of the user can trace it"
+                          .into());
+        }
+        if let Some(ref preload) = self.preload {
+            warnings.push(format!("LD_PRELOAD is set to '{}', the preloaded libraries can \
+                                   read all secrets", preload));
+        }
+        if let Some((Some(0), _)) = self.memlock_limit {
+            warnings.push("memory cannot be locked, secrets may be swapped to disk".into());
+        }
+        for swap in self.swaps.iter().flat_map(|swaps| swaps.iter()) {
+            if !swap.is_in_memory() {
+                warnings.push(format!("swapping to {}, secrets that are not locked may be \
+                                       written to disk", swap.path));
+            }
+        }
+        warnings
+    }
+}
+
+/// Format an amount of bytes.
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{} KiB", bytes / 1024),
+        None => "unlimited".into(),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = "unknown".to_string();
+        writeln!(f, "core dumps:     {}", match self.core_limit {
+            Some(0) => "disabled".to_string(),
+            limit => format!("enabled (limit: {})", format_bytes(limit)),
+        })?;
+        writeln!(f, "dumpable:       {}", match self.dumpable {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
+        })?;
+        writeln!(f, "LD_PRELOAD:     {}", self.preload.as_ref().map_or("not set", |p| p.as_str()))?;
+        writeln!(f, "mlock limit:    {}", self.memlock_limit.map_or(unknown.clone(),
+            |(soft, hard)| format!("{} (hard limit: {})", format_bytes(soft), format_bytes(hard))))?;
+        writeln!(f, "locked memory:  {}", self.locked.map_or(unknown.clone(),
+            |l| format_bytes(Some(l))))?;
+        match self.swaps {
+            None => writeln!(f, "swap:           {}", unknown),
+            Some(ref swaps) if swaps.is_empty() => writeln!(f, "swap:           none"),
+            Some(ref swaps) => {
+                for (i, swap) in swaps.iter().enumerate() {
+                    writeln!(f, "{:16}{} ({}{}, {} of {} used)",
+                             if i == 0 { "swap:" } else { "" }, swap.path, swap.kind,
+                             if swap.is_in_memory() { " in memory" } else { "" },
+                             format_bytes(Some(swap.used)), format_bytes(Some(swap.size)))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+#[test]
+fn test_parse_proc() {
+    let status = "Name:\tmpw\nVmPeak:\t  10000 kB\nVmLck:\t      8 kB\nVmPin:\t 0 kB\n";
+    assert_eq!(parse_locked(status), Some(8192));
+    assert_eq!(parse_locked("Name:\tmpw\n"), None);
+
+    let swaps = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda2                               partition\t8388604\t\t1024\t\t-2
+/swap\\040file                           file\t\t1048576\t\t0\t\t-3
+/dev/zram0                              partition\t4096000\t\t0\t\t100
+";
+    let parsed = parse_swaps(swaps);
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[0], Swap {
+        path: "/dev/sda2".into(),
+        kind: "partition".into(),
+        size: 8388604 * 1024,
+        used: 1024 * 1024,
+    });
+    assert_eq!(parsed[1].path, "/swap file");
+    assert!(!parsed[1].is_in_memory());
+    assert!(parsed[2].is_in_memory());
+    assert!(parse_swaps("Filename\tType\tSize\tUsed\tPriority\n").is_empty());
+}
+
+#[test]
+fn test_report() {
+    let mut report = Report {
+        core_limit: Some(0),
+        dumpable: Some(false),
+        preload: None,
+        memlock_limit: Some((Some(65536), Some(65536))),
+        locked: Some(8192),
+        swaps: Some(vec![]),
+    };
+    assert!(report.warnings().is_empty());
+    assert_eq!(report.to_string(), "\
+core dumps:     disabled
+dumpable:       no
+LD_PRELOAD:     not set
+mlock limit:    64 KiB (hard limit: 64 KiB)
+locked memory:  8 KiB
+swap:           none
+");
+
+    report.core_limit = None;
+    report.dumpable = Some(true);
+    report.preload = Some("libevil.so".into());
+    report.memlock_limit = Some((Some(0), None));
+    report.swaps = Some(parse_swaps("header\n/dev/sda2 partition 1024 0 -2\n\
+                                     /dev/zram0 partition 1024 0 100\n"));
+    assert_eq!(report.warnings().len(), 5);
+    let s = report.to_string();
+    assert!(s.contains("core dumps:     enabled (limit: unlimited)\n"));
+    assert!(s.contains("mlock limit:    0 KiB (hard limit: unlimited)\n"));
+    assert!(s.contains("swap:           /dev/sda2 (partition, 0 KiB of 1024 KiB used)\n\
+                        \x20               /dev/zram0 (partition in memory, 0 KiB of 1024 KiB used)\n"));
+}
+
+#[test]
+fn test_disable_core_dumps() {
+    disable_core_dumps().unwrap();
+    assert_eq!(Report::collect().core_limit, Some(0));
+}
@@ -0,0 +1,188 @@
+//! Render identicons with their colour, like the official apps do.
+//!
+//! Besides terminals, identicons can be rendered as standalone SVG images.
+//! PNG images are converted from them by `rsvg-convert`.
+
+extern crate libc;
+
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use mpw::algorithm::{Identicon, IdenticonColor};
+
+/// Identicon error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error { message: format!("could not render identicon: {}", e) }
+    }
+}
+
+/// Font size of identicons in images, in pixels.
+const IMAGE_FONT_SIZE: u32 = 32;
+
+/// How many colours a terminal can display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSupport {
+    /// No colours, only the glyphs are shown.
+    None,
+    /// The 8 basic ANSI colours.
+    Basic,
+    /// The 256 colours of xterm.
+    Ansi256,
+}
+
+impl ColorSupport {
+    /// Detect the colour support of the terminal on stderr.
+    ///
+    /// Colours are disabled if `NO_COLOR` is set, see https://no-color.org.
+    pub fn detect() -> ColorSupport {
+        let is_tty = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+        ColorSupport::from_env(
+            is_tty,
+            env::var_os("NO_COLOR").as_deref(),
+            env::var_os("TERM").as_deref(),
+            env::var_os("COLORTERM").as_deref(),
+        )
+    }
+
+    /// Determine the colour support from the environment of a terminal.
+    fn from_env(is_tty: bool, no_color: Option<&OsStr>, term: Option<&OsStr>,
+                color_term: Option<&OsStr>)
+        -> ColorSupport
+    {
+        if !is_tty || no_color.is_some_and(|v| !v.is_empty()) {
+            return ColorSupport::None;
+        }
+        let term = term.and_then(OsStr::to_str).unwrap_or("");
+        if term.is_empty() || term == "dumb" {
+            ColorSupport::None
+        } else if term.contains("256color") || color_term.is_some_and(|v| !v.is_empty()) {
+            ColorSupport::Ansi256
+        } else {
+            ColorSupport::Basic
+        }
+    }
+}
+
+/// Render an identicon for a terminal with the given colour support.
+pub fn render(identicon: &Identicon, colors: ColorSupport) -> String {
+    let color = identicon.color.ansi();
+    match colors {
+        ColorSupport::None => identicon.to_string(),
+        ColorSupport::Basic => format!("\x1b[3{}m{}\x1b[0m", color, identicon),
+        ColorSupport::Ansi256 => format!("\x1b[38;5;{}m{}\x1b[0m", color, identicon),
+    }
+}
+
+/// Return the colour used for images, as hex triplet.
+fn image_color(color: IdenticonColor) -> &'static str {
+    match color {
+        IdenticonColor::Red => "#cd3131",
+        IdenticonColor::Green => "#0dbc79",
+        IdenticonColor::Yellow => "#c8a300",
+        IdenticonColor::Blue => "#2472c8",
+        IdenticonColor::Magenta => "#bc3fbc",
+        IdenticonColor::Cyan => "#11a8cd",
+        IdenticonColor::Mono => "#000000",
+    }
+}
+
+/// Render an identicon as a standalone SVG image.
+pub fn render_svg(identicon: &Identicon) -> String {
+    let width = 4 * IMAGE_FONT_SIZE;
+    let height = IMAGE_FONT_SIZE * 3 / 2;
+    // The glyphs never need to be escaped.
+    format!("\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+viewBox=\"0 0 {width} {height}\">
+  <text x=\"{x}\" y=\"{y}\" font-family=\"monospace\" font-size=\"{size}\" \
+text-anchor=\"middle\" fill=\"{color}\">{identicon}</text>
+</svg>
+",
+        width = width,
+        height = height,
+        x = width / 2,
+        y = IMAGE_FONT_SIZE * 9 / 8,
+        size = IMAGE_FONT_SIZE,
+        color = image_color(identicon.color),
+        identicon = identicon)
+}
+
+/// Render an identicon as a PNG image, converted from SVG by `rsvg-convert`.
+pub fn render_png(identicon: &Identicon) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new("rsvg-convert")
+        .args(["--format", "png"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| Error { message: format!("could not run rsvg-convert: {}", e) })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(render_svg(identicon).as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(Error { message: format!("rsvg-convert failed with {}", output.status) });
+    }
+    Ok(output.stdout)
+}
+
+#[test]
+fn test_color_support() {
+    let os = |s| Some(OsStr::new(s));
+    let xterm = os("xterm");
+    assert_eq!(ColorSupport::from_env(true, None, xterm, None), ColorSupport::Basic);
+    assert_eq!(ColorSupport::from_env(true, None, os("xterm-256color"), None),
+               ColorSupport::Ansi256);
+    assert_eq!(ColorSupport::from_env(true, None, xterm, os("truecolor")), ColorSupport::Ansi256);
+    assert_eq!(ColorSupport::from_env(false, None, xterm, None), ColorSupport::None);
+    assert_eq!(ColorSupport::from_env(true, os("1"), xterm, None), ColorSupport::None);
+    assert_eq!(ColorSupport::from_env(true, os(""), xterm, None), ColorSupport::Basic);
+    assert_eq!(ColorSupport::from_env(true, None, os("dumb"), None), ColorSupport::None);
+    assert_eq!(ColorSupport::from_env(true, None, None, None), ColorSupport::None);
+}
+
+#[test]
+fn test_render() {
+    use mpw::algorithm::identicon;
+
+    let identicon = identicon(b"John Doe", b"password");
+    assert_eq!(render(&identicon, ColorSupport::None), "╔░╝⌚");
+    assert_eq!(render(&identicon, ColorSupport::Basic), "\x1b[31m╔░╝⌚\x1b[0m");
+    assert_eq!(render(&identicon, ColorSupport::Ansi256), "\x1b[38;5;1m╔░╝⌚\x1b[0m");
+}
+
+#[test]
+fn test_render_svg() {
+    use mpw::algorithm::identicon;
+
+    let svg = render_svg(&identicon(b"John Doe", b"password"));
+    assert!(svg.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<svg "));
+    assert!(svg.contains(" fill=\"#cd3131\">╔░╝⌚</text>"));
+    assert!(svg.ends_with("</svg>\n"));
+
+    let svg = render_svg(&identicon(b"Robert Lee Mitchell", b"banana colored duckling"));
+    assert!(svg.contains(" fill=\"#0dbc79\">╚☻╯⛄</text>"));
+}
@@ -0,0 +1,51 @@
+//! A stateless password management solution.
+//!
+//! This implements the [Master Password](https://ssl.masterpasswordapp.com/algorithm.html)
+//! algorithm, which derives the passwords of sites from the full name of the
+//! user, the name of the site and a master password. Additionally, passwords
+//! can be stored encrypted with the master key.
+//!
+//! ```no_run
+//! # fn run() -> Result<(), mpw::Error> {
+//! use mpw::{MasterKey, AlgorithmVersion, Config, Site};
+//!
+//! let config = Config::from_str("[[sites]]\nname = \"github.com\"")?;
+//! let templates = config.templates()?;
+//! let key = MasterKey::new("John Doe", "password", AlgorithmVersion::latest())?;
+//! for site_config in config.sites.as_ref().unwrap() {
+//!     let site = Site::from_config(site_config)?;
+//!     let password = key.password_for_site(&site, &templates)?;
+//!     assert_eq!(&*password.borrow(), "VubeNazoRihe4(");
+//! }
+//! # Ok(())
+//! # }
+//! # run().unwrap();
+//! ```
+//!
+//! The parameters of sites are usually kept in a `Config`, which can be
+//! (de)serialized to TOML and exchanged with the official apps via the
+//! `mpsites` module. Errors of all modules convert into `mpw::Error`.
+
+#[macro_use]
+extern crate lazy_static;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod algorithm;
+pub mod clear_on_drop;
+pub mod config;
+pub mod config_file;
+mod error;
+pub mod master_key;
+pub mod mpsites;
+pub mod search;
+pub mod secret;
+
+pub use algorithm::{AlgorithmVersion, SiteType, SiteVariant, Templates, Policy, Identicon,
+    IdenticonColor, identicon};
+pub use clear_on_drop::ClearOnDrop;
+pub use config::{Config, Site, SiteConfig};
+pub use error::{Error, ErrorKind};
+pub use master_key::MasterKey;
+pub use secret::{SecretBox, SecretString, SecretVec};
@@ -1,37 +1,41 @@
-#![feature(try_from, core_intrinsics)]
-
-#[macro_use]
-extern crate lazy_static;
 #[macro_use]
 extern crate clap;
 extern crate ring;
 extern crate rpassword;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
 extern crate data_encoding;
 extern crate zxcvbn;
+extern crate mpw;
 
-use std::io::{Read, Write};
-use std::fs::File;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{Arg, App, AppSettings};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 use ring::rand::SystemRandom;
-use rpassword::read_password;
-use data_encoding::base64;
 use zxcvbn::zxcvbn;
 
-mod algorithm;
-mod clear_on_drop;
-mod config;
+mod agent;
+mod clipboard;
+mod hardening;
+mod identicon;
+mod password_source;
 
-use algorithm::{SiteVariant, SiteType, random_password_for_site,
-    master_key_for_user_v3, password_for_site_v3, identicon, min_buffer_len,
-    encrypt, decrypt};
-use clear_on_drop::ClearOnDrop;
-use config::{merge_options, Config, SiteConfig, Site};
+use mpw::{config, config_file, identicon, mpsites};
+use mpw::algorithm::{SiteVariant, SiteType, AlgorithmVersion, Templates,
+    random_password_for_site};
+use mpw::clear_on_drop::{ClearOnDrop, MlockPolicy, set_mlock_policy};
+use mpw::config::{merge_options, Config, SiteConfig, Site};
+use mpw::config_file::{ConfigFile, Lock};
+use mpw::master_key::MasterKey;
+use identicon::{ColorSupport, render_svg, render_png};
+use mpw::mpsites::{write_flat, write_json, merge_import};
+use mpw::search::{search, Mode};
+use mpw::secret::SecretString;
+use password_source::PasswordSource;
 
-static TYPE_HELP: &'static str =
+static TYPE_HELP: &str =
 "The password's template\n\
 (defaults to 'long' for password, 'name' for login, 'phrase' for answer)\n\
 \n\
@@ -42,56 +46,344 @@ b, basic          8 characters, no symbols.\n\
 s, short          Copy-friendly, 4 characters, no symbols.\n\
 i, pin            4 numbers.\n\
 n, name           9 letter name.\n\
-p, phrase         20 character sentence.\n";
-
-/// Flush to make sure the prompt is visible.
-fn flush() {
-    std::io::stdout().flush().unwrap_or_exit("could not flush stdout");
-}
+p, phrase         20 character sentence.\n\
+\n\
+Any other name refers to a template defined in the config.\n";
 
-/// Read the master password from stdin and generate the master key.
-fn generate_master_key(full_name: &str) -> ClearOnDrop<[u8; 64]> {
-    print!("Please enter the master password: ");
-    flush();
-    let master_password = read_password().unwrap_or_exit("could not read master password");
+/// Read the master password from the given source.
+///
+/// The master keys are derived from it on demand, because different
+/// algorithm versions may need different keys. Prompts and feedback go to
+/// stderr, so that stdout can be evaluated by a shell.
+///
+/// If the identicon does not match the expected one, a warning is printed
+/// before anything is derived.
+fn read_master_password(full_name: &str, source: &PasswordSource,
+                        expected_identicon: Option<&str>)
+    -> ClearOnDrop<String>
+{
+    let master_password = source.read("Please enter the master password: ")
+        .unwrap_or_else(|e| exit(&e.message));
 
     let identicon = identicon(full_name.as_bytes(), master_password.as_bytes());
-    println!("Identicon: {}", identicon);
+    eprintln!("Identicon: {}", identicon::render(&identicon, ColorSupport::detect()));
+    if let Some(expected) = expected_identicon {
+        if identicon.to_string() != expected {
+            eprintln!("WARNING: This is not the expected identicon {}! \
+                       Your master password is probably mistyped.", expected);
+        }
+    }
     if let Ok(evaluation) = zxcvbn(&master_password, &[full_name]) {
         let time = &evaluation.crack_times_display.offline_slow_hashing_1e4_per_second;
         match evaluation.score {
-            0 => println!("Your password is trivial, it can be cracked in {}.", time),
-            1 => println!("Your password is very weak, it can be cracked in {}.", time),
-            2 => println!("Your password is weak, it can be cracked in {}.", time),
-            3 => println!("Your password is so-so."),
-            _ => println!("Your password is great!"),
+            0 => eprintln!("Your password is trivial, it can be cracked in {}.", time),
+            1 => eprintln!("Your password is very weak, it can be cracked in {}.", time),
+            2 => eprintln!("Your password is weak, it can be cracked in {}.", time),
+            3 => eprintln!("Your password is so-so."),
+            _ => eprintln!("Your password is great!"),
         }
         if let Some(feedback) = evaluation.feedback {
             if let Some(warning) = feedback.warning {
-                println!("{}", warning);
+                eprintln!("{}", warning);
             }
             for s in &feedback.suggestions {
-                println!("{}", s);
+                eprintln!("{}", s);
             }
         }
     } else if master_password.is_empty() {
-        println!("Your password is empty, it can be cracked in less than a second.");
+        eprintln!("Your password is empty, it can be cracked in less than a second.");
     } else {
-        println!("Could not evaluate password, most likely because of non-ASCII symbols.");
+        eprintln!("Could not evaluate password, most likely because of non-ASCII symbols.");
     }
-    let master_key = master_key_for_user_v3(
-        full_name.as_bytes(),
-        master_password.as_bytes()
-    ).unwrap_or_exit("could not generate master key");
-    master_key
+    master_password
 }
 
-/// Read a site password to be stored from stdin.
+/// Ask a yes/no question on the terminal, defaulting to no.
+fn ask_yes_no(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    let read = match File::open("/dev/tty") {
+        Ok(tty) => BufReader::new(tty).read_line(&mut answer),
+        Err(_) => std::io::stdin().read_line(&mut answer),
+    };
+    read.is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// Read a site password to be stored from the terminal.
 fn get_site_password() -> ClearOnDrop<String> {
-    print!("Please enter the site password to be stored: ");
-    flush();
-    let password = read_password().unwrap_or_exit("could not read site password");
-    ClearOnDrop::new(password)
+    PasswordSource::Terminal.read("Please enter the site password to be stored: ")
+        .unwrap_or_exit("could not read site password")
+}
+
+/// How often an interactive user is asked for the master password.
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+
+/// Provides the master keys of a user, either via a running agent or by
+/// deriving them from the master password.
+///
+/// The master password is only read when a key is actually needed.
+struct KeySource {
+    full_name: String,
+    password_source: PasswordSource,
+    agent: Option<agent::Client>,
+    master_password: Option<ClearOnDrop<String>>,
+    /// The master keys derived so far, the latest version first.
+    master_keys: Vec<MasterKey>,
+    /// The ID of the latest master key, either expected from the config or
+    /// learned when the master key was first used.
+    key_id: Option<String>,
+    /// The identicon expected from the config, or the one confirmed by the
+    /// user.
+    identicon: Option<String>,
+    /// Whether the user has to confirm the identicon.
+    confirm_identicon: bool,
+}
+
+impl KeySource {
+    /// Use the agent given by the environment if it holds the keys of the
+    /// given user.
+    ///
+    /// If a key ID is given, the master password has to match it.
+    fn new(full_name: String, password_source: PasswordSource, use_agent: bool,
+           key_id: Option<String>)
+        -> KeySource
+    {
+        let agent = if use_agent { agent::Client::from_env() } else { None };
+        let agent = agent.and_then(|client| match client.full_name() {
+            Ok(ref name) if *name == full_name => Some(client),
+            Ok(_) => {
+                print_warnings(&["agent holds the keys of a different user, ignoring it".into()]);
+                None
+            },
+            Err(e) => {
+                print_warnings(&[format!("ignoring agent: {}", e.message)]);
+                None
+            },
+        });
+        let mut key_id = key_id;
+        let agent = agent.and_then(|client| match client.key_id() {
+            Ok(ref id) if key_id.as_ref().is_none_or(|expected| expected == id) => {
+                key_id = Some(id.clone());
+                Some(client)
+            },
+            Ok(_) => {
+                print_warnings(&["agent holds a different master key than the config, \
+                                  ignoring it".into()]);
+                None
+            },
+            Err(e) => {
+                print_warnings(&[format!("ignoring agent: {}", e.message)]);
+                None
+            },
+        });
+        KeySource {
+            full_name,
+            password_source,
+            agent,
+            master_password: None,
+            master_keys: Vec::new(),
+            key_id,
+            identicon: None,
+            confirm_identicon: false,
+        }
+    }
+
+    /// Warn if the identicon differs from the expected one, and optionally
+    /// let the user confirm it.
+    ///
+    /// Confirming the identicon requires the master password, so the agent
+    /// is not used in that case.
+    fn with_identicon(mut self, expected: Option<String>, confirm: bool) -> KeySource {
+        self.identicon = expected;
+        self.confirm_identicon = confirm;
+        if confirm {
+            self.agent = None;
+        }
+        self
+    }
+
+    /// Read the master password and check it against the key ID.
+    ///
+    /// Interactive sources are asked again if the master password is wrong.
+    fn unlock(&mut self) {
+        for attempt in 1..MAX_PASSWORD_ATTEMPTS + 1 {
+            let master_password = read_master_password(
+                &self.full_name, &self.password_source, self.identicon.as_deref());
+            let mut confirmed = None;
+            if self.confirm_identicon {
+                let shown = identicon(self.full_name.as_bytes(), master_password.as_bytes())
+                    .to_string();
+                if ask_yes_no("Is this the identicon you expect?") {
+                    confirmed = Some(shown);
+                } else if self.password_source.is_interactive() && attempt < MAX_PASSWORD_ATTEMPTS {
+                    eprintln!("Please enter the master password again.");
+                    continue;
+                } else {
+                    exit("the identicon was not confirmed");
+                }
+            }
+            let master_key = MasterKey::new(&self.full_name, &master_password,
+                                            AlgorithmVersion::latest())
+                .unwrap_or_exit("could not generate master key");
+            let id = master_key.key_id();
+            if self.key_id.as_ref().is_none_or(|expected| *expected == id) {
+                self.key_id = Some(id);
+                if confirmed.is_some() {
+                    self.identicon = confirmed;
+                }
+                self.master_keys = vec![master_key];
+                self.master_password = Some(master_password);
+                return;
+            }
+            if !self.password_source.is_interactive() || attempt == MAX_PASSWORD_ATTEMPTS {
+                break;
+            }
+            eprintln!("Wrong master password, please try again.");
+        }
+        exit("wrong master password, it does not match the key ID in the config")
+    }
+
+    /// Remember the key ID and a confirmed identicon in the config.
+    ///
+    /// Returns whether the config was changed.
+    fn remember_in(&self, config: &mut Config) -> bool {
+        let mut changed = false;
+        if config.key_id.is_none() && self.key_id.is_some() {
+            config.key_id = self.key_id.clone();
+            changed = true;
+        }
+        if self.confirm_identicon && self.identicon.is_some() && config.identicon != self.identicon {
+            config.identicon = self.identicon.clone();
+            changed = true;
+        }
+        changed
+    }
+
+    /// Get a master key usable with the given algorithm version, deriving it
+    /// if necessary.
+    fn master_key(&mut self, version: AlgorithmVersion) -> &MasterKey {
+        if self.master_password.is_none() {
+            self.unlock();
+        }
+        if let Some(i) = self.master_keys.iter().position(|key| key.supports_version(version)) {
+            return &self.master_keys[i];
+        }
+        let master_password = self.master_password.as_ref().unwrap();
+        //^ This unwrap is safe, because `unlock` either set it or exited.
+        let key = MasterKey::new(&self.full_name, master_password, version)
+            .unwrap_or_exit("could not generate master key");
+        self.master_keys.push(key);
+        self.master_keys.last().unwrap()
+        //^ This unwrap is safe, because a key was just added.
+    }
+
+    /// Return the password of a site, either generated or decrypted.
+    fn password_for_site(&mut self, site: &Site, templates: &Templates)
+        -> Result<SecretString, String>
+    {
+        if let Some(ref client) = self.agent {
+            return client.password_for_site(site, templates).map_err(|e| e.message);
+        }
+        // Stored passwords are always encrypted with the latest master key.
+        let version = if site.type_ == SiteType::Stored {
+            AlgorithmVersion::latest()
+        } else {
+            site.algorithm
+        };
+        self.master_key(version).password_for_site(site, templates).map_err(|e| e.message)
+    }
+
+    /// Decrypt a stored password that is not bound to its site.
+    ///
+    /// The agent refuses such passwords, so this always uses the master key.
+    fn unbound_password_for_site(&mut self, site: &Site) -> Result<SecretString, String> {
+        self.master_key(AlgorithmVersion::latest()).unbound_password_for_site(site)
+            .map_err(|e| e.message)
+    }
+
+    /// Encrypt a password to be stored for a site, using the latest master
+    /// key.
+    ///
+    /// The password is bound to the parameters of the site, so that it cannot
+    /// be moved to another one.
+    fn encrypt_password(&mut self, password: &str, site: &Site) -> String {
+        if let Some(ref client) = self.agent {
+            return client.encrypt_password(password, site)
+                .unwrap_or_else(|e| exit(&e.message));
+        }
+        self.master_key(AlgorithmVersion::latest()).encrypt_password(password, site)
+            .unwrap_or_else(|e| exit(&e.message))
+    }
+}
+
+/// Read the whole file at the given path.
+fn read_file(path: &str) -> std::io::Result<String> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Lock the config file at the given path, telling the user if another
+/// process holds it.
+fn open_config_file(path: &Path, lock: Lock) -> ConfigFile {
+    ConfigFile::try_open(path, lock).or_else(|e| {
+        if e.kind != config_file::ErrorKind::Locked {
+            return Err(e);
+        }
+        eprintln!("Waiting for another process to release {}...", path.display());
+        ConfigFile::open(path, lock)
+    }).unwrap_or_else(|e| exit(&e.message))
+}
+
+/// Read the config file, which is empty if it does not exist.
+fn read_config_file(file: &ConfigFile) -> String {
+    file.read().unwrap_or_else(|e| exit(&e.message)).unwrap_or_default()
+}
+
+/// Overwrite the config file, keeping the old one as backup.
+fn write_config(file: &mut ConfigFile, config: &Config) {
+    let s = config.encode()
+        .unwrap_or_exit("could not encode config");
+    debug_assert!(!s.is_empty());
+    file.write(&s).unwrap_or_else(|e| exit(&e.message));
+}
+
+/// Return the config with what was learned about the master key, if that
+/// changes it.
+///
+/// A missing config is only created to remember a confirmed identicon.
+fn remembered<'a>(keys: &KeySource, config_string: &'a str) -> Option<Config<'a>> {
+    let mut stored = Config::from_str(config_string)
+        .unwrap_or_exit("could not parse given config file");
+    if !keys.remember_in(&mut stored) || (config_string.is_empty() && !keys.confirm_identicon) {
+        return None;
+    }
+    if stored.full_name.is_none() {
+        stored.full_name = Some(keys.full_name.clone().into());
+    }
+    Some(stored)
+}
+
+/// Remember what was learned about the master key in the config file itself,
+/// leaving out the parameters given on the command line.
+fn remember_in_config_file(keys: &KeySource, file: &mut ConfigFile, config_string: &str) {
+    // Only lock the file exclusively if there is something to remember.
+    if remembered(keys, config_string).is_none() {
+        return;
+    }
+    file.lock_exclusive().unwrap_or_else(|e| exit(&e.message));
+    // Another process may have changed the file in the meantime.
+    let config_string = read_config_file(file);
+    if let Some(stored) = remembered(keys, &config_string) {
+        write_config(file, &stored);
+    }
+}
+
+/// Print warnings to stderr.
+fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
 }
 
 /// Exit the program with an error message.
@@ -131,281 +423,1008 @@ impl<T, E> UnwrapOrExit<T> for Result<T, E> {
     }
 }
 
+/// Command line options that may be given before or after the subcommand.
+///
+/// Global options end up in the matches of the side they were given on, so
+/// the subcommand is asked first.
+struct Options<'a> {
+    global: &'a ArgMatches<'a>,
+    sub: Option<&'a ArgMatches<'a>>,
+}
+
+impl<'a> Options<'a> {
+    fn value_of(&self, name: &str) -> Option<&'a str> {
+        self.sub.and_then(|m| m.value_of(name)).or_else(|| self.global.value_of(name))
+    }
+
+    fn values_of(&self, name: &str) -> Option<Vec<&'a str>> {
+        self.sub.and_then(|m| m.values_of(name)).or_else(|| self.global.values_of(name))
+            .map(|values| values.collect())
+    }
+
+    fn is_present(&self, name: &str) -> bool {
+        self.sub.is_some_and(|m| m.is_present(name)) || self.global.is_present(name)
+    }
+}
+
+/// The arguments describing the parameters of a site.
+fn site_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("type")
+            .long("type")
+            .short("t")
+            .help(TYPE_HELP)
+            .next_line_help(true)
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("counter")
+            .long("counter")
+            .short("c")
+            .help("The value of the site counter.")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("variant")
+            .long("variant")
+            .short("v")
+            .help("The kind of content to generate (defaults to 'password')\n\
+                   \n\
+                   p, password  Generate a password\n\
+                   l, login     Generate a login name\n\
+                   a, answer    Generate an answer to a question")
+            .next_line_help(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&[
+               "p", "password",
+               "l", "login",
+               "a", "answer"
+            ]),
+        Arg::with_name("algorithm")
+            .long("algorithm")
+            .short("A")
+            .help("The version of the algorithm (defaults to 3).\n\
+                   Older versions are only useful to reproduce old passwords.")
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["0", "1", "2", "3"]),
+        Arg::with_name("context")
+            .long("context")
+            .short("C")
+            .help("Empty for a universal site or the most significant word(s) of the question.")
+            .takes_value(true)
+            .number_of_values(1),
+    ]
+}
+
+/// The arguments for copying a password to the clipboard.
+fn copy_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("copy")
+            .long("copy")
+            .short("y")
+            .help("Copy the password to the clipboard instead of printing it.")
+            .requires("site"),
+        Arg::with_name("clear after")
+            .long("clear-after")
+            .help("Clear the clipboard after the given number of seconds (defaults to 45).\n\
+                   The clipboard is only cleared if it still holds the password, 0 disables \
+                   clearing.")
+            .takes_value(true)
+            .number_of_values(1)
+            .requires("copy"),
+        Arg::with_name("clipboard")
+            .long("clipboard")
+            .help("The clipboard to copy to (defaults to 'auto').\n\
+                   \n\
+                   auto     Wayland or X11 if available, otherwise OSC 52\n\
+                   wayland  Wayland via wl-copy\n\
+                   x11      X11 via xclip\n\
+                   osc52    The terminal via the OSC 52 escape sequence")
+            .next_line_help(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["auto", "wayland", "x11", "osc52"])
+            .requires("copy"),
+    ]
+}
+
+/// The arguments for the names and labels a site can be found by.
+fn search_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("alias")
+            .long("alias")
+            .help("Another name to find the site by, can be given several times.")
+            .takes_value(true)
+            .number_of_values(1)
+            .multiple(true),
+        Arg::with_name("tag")
+            .long("tag")
+            .help("A label to find the site by, can be given several times.")
+            .takes_value(true)
+            .number_of_values(1)
+            .multiple(true),
+    ]
+}
+
+/// The argument naming the site a subcommand works on.
+fn site_arg() -> Arg<'static, 'static> {
+    Arg::with_name("site")
+        .help("The domain name of the site.")
+        .index(1)
+        .required(true)
+}
+
+/// Map the deprecated flags without a subcommand to the subcommand they
+/// stand for, warning about them.
+fn deprecated_command(matches: &ArgMatches) -> &'static str {
+    let (command, replacement, old) = if matches.is_present("dump") {
+        ("dump", "mpw dump", "--dump")
+    } else if matches.is_present("add") {
+        ("add", "mpw add", "--add")
+    } else if matches.is_present("replace") {
+        ("add", "mpw add --replace", "--replace")
+    } else if matches.is_present("delete") {
+        ("rm", "mpw rm", "--delete")
+    } else if matches.is_present("store") {
+        ("store", "mpw store", "--store")
+    } else if matches.is_present("site") {
+        ("get", "mpw get", "giving a site without a command")
+    } else {
+        // Printing all passwords has to be asked for explicitly.
+        print_warnings(&["without a command, mpw only lists the sites, \
+                          use `mpw list --passwords` to print their passwords".into()]);
+        return "list";
+    };
+    print_warnings(&[format!("{} is deprecated, use `{}` instead", old, replacement)]);
+    command
+}
+
+/// Generate a random full name and print it.
+fn generate_full_name() -> String {
+    let rng = SystemRandom::new();
+    let name = random_password_for_site(&rng, &SiteType::GeneratedMaximum, &Templates::new())
+        .unwrap_or_exit("failed to generate random full name").borrow().to_string();
+    println!("generated random full name: \"{}\"", name);
+    name
+}
+
+/// Read the parameters of the given site from the command line.
+fn param_site_config<'a>(options: &Options<'a>, name: &'a str) -> SiteConfig<'a> {
+    SiteConfig {
+        name: name.into(),
+        type_: options.value_of("type").map(SiteType::from_name),
+        counter: options.value_of("counter")
+            .map(|c| c.parse().unwrap_or_exit("counter must be an unsigned 32-bit integer")),
+        variant: options.value_of("variant").map(|s| SiteVariant::from_str(s).unwrap()),
+        //^ This unwrap is safe, because clap already did the check.
+        context: options.value_of("context").map(Into::into),
+        algorithm: options.value_of("algorithm")
+            .map(|s| AlgorithmVersion::from_str(s).unwrap()),
+        //^ This unwrap is safe, because clap already did the check.
+        login_name: None,
+        aliases: options.values_of("alias").map(|v| v.into_iter().map(Into::into).collect()),
+        tags: options.values_of("tag").map(|v| v.into_iter().map(Into::into).collect()),
+        encrypted: None,
+        ext_mpw: None,
+        policy: None,
+    }
+}
+
+/// Return the distinct names of the given sites, in order.
+fn distinct_names<'c, I>(sites: I) -> Vec<&'c str>
+    where I: IntoIterator<Item = &'c SiteConfig<'c>>
+{
+    let mut names: Vec<&str> = Vec::new();
+    for site in sites {
+        if !names.contains(&&*site.name) {
+            names.push(&site.name);
+        }
+    }
+    names
+}
+
+/// Resolve the name of a site given on the command line.
+///
+/// Names and aliases from the config are used as they are. Otherwise, a glob
+/// pattern or part of a name without a dot is resolved if it matches a single
+/// site, and matching several sites is an error. Any other name, like a
+/// domain such as `example.com`, is taken to be a site that is not in the
+/// config. Similar sites are only suggested then, so that the password of
+/// another site like `mail.example.com` is never shown by accident.
+fn resolve_site<'c>(sites: &'c [SiteConfig<'c>], name: &'c str) -> &'c str {
+    if sites.iter().any(|site| site.name == name) {
+        return name;
+    }
+    let has_alias = |site: &&SiteConfig| {
+        site.aliases.iter().flat_map(|aliases| aliases.iter()).any(|alias| alias == name)
+    };
+    if let Some(site) = sites.iter().find(has_alias) {
+        return &site.name;
+    }
+    let candidates = if name.contains(['*', '?']) {
+        distinct_names(search(sites, name, Mode::Glob).into_iter().map(|m| m.site))
+    } else if !name.contains('.') {
+        distinct_names(search(sites, name, Mode::Substring).into_iter().map(|m| m.site))
+    } else {
+        Vec::new()
+    };
+    match candidates.len() {
+        0 => {
+            let similar = distinct_names(search(sites, name, Mode::Fuzzy).into_iter()
+                                         .map(|m| m.site));
+            if !similar.is_empty() {
+                print_warnings(&[format!("{} is not in the config, similar sites are: {}",
+                                         name, similar.join(", "))]);
+            }
+            name
+        },
+        1 => {
+            eprintln!("Using site {}.", candidates[0]);
+            candidates[0]
+        },
+        _ => exit(&format!("{} matches several sites, please be more specific:\n    {}",
+                           name, candidates.join("\n    "))),
+    }
+}
+
+/// Collect the sites of the config with the name of the given one, with the
+/// parameters given on the command line taking precedence.
+///
+/// If the config does not know the site, the given one is used as it is.
+fn sites_named<'a>(config: &Config<'a>, param: SiteConfig<'a>) -> Vec<SiteConfig<'a>> {
+    let mut sites: Vec<SiteConfig> = config.sites.iter()
+        .flat_map(|sites| sites.iter())
+        .filter(|site| site.name == param.name)
+        .cloned()
+        .collect();
+    if sites.is_empty() {
+        return vec![param];
+    }
+    for site in &mut sites {
+        site.type_ = merge_options(site.type_.take(), param.type_.clone());
+        site.counter = merge_options(site.counter, param.counter);
+        site.variant = merge_options(site.variant, param.variant);
+        site.context = merge_options(site.context.take(), param.context.clone());
+        site.algorithm = merge_options(site.algorithm, param.algorithm);
+    }
+    sites
+}
+
+/// Remove the sites with the given name from the config.
+///
+/// Stored passwords cannot be generated again, so they are only removed if
+/// `stored` is set. Returns how many sites were removed.
+fn remove_sites(config: &mut Config, name: &str, stored: bool) -> usize {
+    let sites = match config.sites {
+        Some(ref mut sites) => sites,
+        None => return 0,
+    };
+    let len = sites.len();
+    sites.retain(|s| s.name != name || (s.encrypted.is_some() && !stored));
+    len - sites.len()
+}
+
 fn main() {
     let matches = App::new("Master Password")
         .about("A stateless password management solution.")
         .version(crate_version!())
-        .setting(AppSettings::HidePossibleValuesInHelp)
-        .arg(Arg::with_name("site")
-             .help("The domain name of the site.")
-             .number_of_values(1)
-             .index(1)
-             .required_unless("config"))
+        .global_setting(AppSettings::HidePossibleValuesInHelp)
+        .global_setting(AppSettings::VersionlessSubcommands)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgRequiredElseHelp)
         .arg(Arg::with_name("full name")
              .long("name")
              .short("u")
              .help("The full name of the user.\nOptional if given in config.")
-             .required_unless_one(&["config", "generate name"])
+             .global(true)
              .number_of_values(1)
              .takes_value(true))
-        .arg(Arg::with_name("type")
-             .long("type")
-             .short("t")
-             .help(TYPE_HELP)
-             .next_line_help(true)
-             .takes_value(true)
-             .number_of_values(1)
-             .possible_values(&[
-                 "x", "max", "maximum",
-                 "l", "long",
-                 "m", "med", "medium",
-                 "b", "basic",
-                 "s", "short",
-                 "i", "pin",
-                 "n", "name",
-                 "p", "phrase",
-             ]))
-        .arg(Arg::with_name("counter")
-             .long("counter")
-             .short("c")
-             .help("The value of the site counter.")
-             .takes_value(true)
-             .number_of_values(1))
-        .arg(Arg::with_name("variant")
-             .long("variant")
-             .short("v")
-             .help("The kind of content to generate (defaults to 'password')\n\
-                    \n\
-                    p, password  Generate a password\n\
-                    l, login     Generate a login name\n\
-                    a, answer    Generate an answer to a question")
-             .next_line_help(true)
-             .takes_value(true)
-             .number_of_values(1)
-             .possible_values(&[
-                "p", "password",
-                "l", "login",
-                "a", "answer"
-             ]))
-        .arg(Arg::with_name("context")
-             .long("context")
-             .short("C")
-             .help("Empty for a universal site or the most significant word(s) of the question.")
-             .takes_value(true)
-             .number_of_values(1))
-        .arg(Arg::with_name("dump")
-             .long("dump")
-             .short("d")
-             .help("Dump the configuration as a TOML."))
         .arg(Arg::with_name("config")
              .long("config")
              .short("i")
-             .help("Read/write configuration from/to a TOML file.")
+             .help("Read/write configuration from/to a TOML file.\n\
+                    Defaults to $MPW_CONFIG, $XDG_CONFIG_HOME/mpw/config.toml or \
+                    ~/.config/mpw/config.toml.")
+             .global(true)
              .takes_value(true)
              .number_of_values(1))
+        .arg(Arg::with_name("no agent")
+            .long("no-agent")
+            .help("Do not use a running agent, always ask for the master password.")
+            .global(true))
+        .arg(Arg::with_name("confirm identicon")
+            .long("confirm-identicon")
+            .help("Ask to confirm the identicon and remember it in the config, \
+                   so that a mistyped master password is warned about later.")
+            .global(true))
+        .arg(Arg::with_name("password fd")
+            .long("password-fd")
+            .help("Read the master password from the first line of the given file descriptor.")
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["password file", "password env", "password command",
+                                  "pinentry"]))
+        .arg(Arg::with_name("password file")
+            .long("password-file")
+            .help("Read the master password from the first line of the given file.\n\
+                   The file must not be accessible by other users.")
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["password env", "password command", "pinentry"]))
+        .arg(Arg::with_name("password env")
+            .long("password-env")
+            .help("Read the master password from the given environment variable.")
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["password command", "pinentry"]))
+        .arg(Arg::with_name("password command")
+            .long("password-command")
+            .help("Read the master password from the first line printed by the given shell \
+                   command,\nfor instance 'pass show mpw'.")
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .conflicts_with("pinentry"))
+        .arg(Arg::with_name("pinentry")
+            .long("pinentry")
+            .help("Ask for the master password using the given pinentry program.")
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("mlock")
+            .long("mlock")
+            .help("What to do if memory holding secrets cannot be locked (defaults to 'warn').\n\
+                   \n\
+                   ignore  Continue silently\n\
+                   warn    Print a warning and continue\n\
+                   refuse  Exit with an error")
+            .next_line_help(true)
+            .global(true)
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["ignore", "warn", "refuse"]))
+        .arg(Arg::with_name("refuse preload")
+            .long("refuse-preload")
+            .help("Refuse to run if libraries are preloaded via LD_PRELOAD.")
+            .global(true))
+        .arg(Arg::with_name("create")
+            .long("create")
+            .help("Create the config file if it does not exist.")
+            .global(true))
+        // The flags from before there were subcommands, deprecated.
+        .arg(Arg::with_name("site")
+             .help("The domain name of the site.")
+             .index(1)
+             .hidden(true))
+        .args(&site_args().into_iter().map(|a| a.hidden(true)).collect::<Vec<_>>())
+        .args(&copy_args().into_iter()
+              .map(|a| a.hidden(true).conflicts_with_all(&["add", "replace", "delete", "store",
+                                                           "dump"]))
+              .collect::<Vec<_>>())
+        .arg(Arg::with_name("dump")
+             .long("dump")
+             .short("d")
+             .hidden(true))
         .arg(Arg::with_name("add")
              .long("add")
              .short("a")
-             .help("Add parameters of site password to configuration file.")
-             .requires_all(&["site", "config"])
+             .hidden(true)
+             .requires("site")
              .conflicts_with_all(&["replace", "delete", "store"]))
         .arg(Arg::with_name("replace")
              .long("replace")
              .short("r")
-             .help("Replace parameters of all site passwords in configuration file.\n\
-                    Does not delete stored passwords.")
-             .requires_all(&["site", "config"])
+             .hidden(true)
+             .requires("site")
              .conflicts_with_all(&["add", "delete", "store"]))
         .arg(Arg::with_name("delete")
              .long("delete")
              .short("D")
-             .help("Delete parameters of all site passwords in configuration file.\n\
-                    Does not delete stored passwords.")
-             .requires_all(&["site", "config"])
+             .hidden(true)
+             .requires("site")
              .conflicts_with_all(&["add", "replace", "store"]))
         .arg(Arg::with_name("store")
              .long("store")
              .short("s")
-             .help("Encrypt and store a password")
-             .requires_all(&["site", "config"])
+             .hidden(true)
+             .requires("site")
              .conflicts_with_all(&["add", "delete", "replace"]))
         .arg(Arg::with_name("generate name")
             .long("generate-name")
             .short("g")
-            .help("Generate a random full name.\n\
-                   If the name is kept secret, this is more secure.")
+            .hidden(true)
             .requires("config")
             .conflicts_with("full name"))
+        .subcommand(SubCommand::with_name("get")
+            .about("Print the password of a site, generated or decrypted.\n\
+                    Parameters given on the command line take precedence over the configuration.")
+            .arg(site_arg())
+            .args(&site_args())
+            .args(&copy_args()))
+        .subcommand(SubCommand::with_name("add")
+            .about("Add the parameters of a site to the configuration file.")
+            .arg(site_arg())
+            .args(&site_args())
+            .args(&search_args())
+            .arg(Arg::with_name("replace")
+                 .long("replace")
+                 .short("r")
+                 .help("Replace the parameters the site already has in the configuration file.\n\
+                        Does not delete stored passwords.")))
+        .subcommand(SubCommand::with_name("store")
+            .about("Encrypt the password of a site and store it in the configuration file.")
+            .arg(site_arg())
+            .args(&site_args())
+            .args(&search_args()))
+        .subcommand(SubCommand::with_name("list")
+            .about("List the sites in the configuration file, optionally only those matching a \
+                    pattern.\n\
+                    The pattern is matched against the names, aliases and tags of the sites, \
+                    the best matches first.")
+            .arg(Arg::with_name("pattern")
+                 .help("Only list the sites matching the pattern.")
+                 .index(1))
+            .arg(Arg::with_name("match")
+                 .long("match")
+                 .short("m")
+                 .help("How the pattern is matched (defaults to 'auto').\n\
+                        \n\
+                        auto       Like glob if the pattern contains * or ?, otherwise like fuzzy\n\
+                        substring  The pattern is part of the name\n\
+                        glob       The pattern matches the whole name, * any text and ? any \
+                        character\n\
+                        fuzzy      The characters of the pattern appear in the name in order")
+                 .next_line_help(true)
+                 .takes_value(true)
+                 .number_of_values(1)
+                 .possible_values(&["auto", "substring", "glob", "fuzzy"]))
+            .arg(Arg::with_name("passwords")
+                 .long("passwords")
+                 .short("p")
+                 .help("Print the passwords of the sites.")))
+        .subcommand(SubCommand::with_name("rm")
+            .about("Delete the parameters of a site from the configuration file.")
+            .arg(site_arg())
+            .arg(Arg::with_name("stored")
+                 .long("stored")
+                 .help("Also delete stored passwords, they are lost afterwards.")))
+        .subcommand(SubCommand::with_name("dump")
+            .about("Print the configuration as TOML."))
+        .subcommand(SubCommand::with_name("init")
+            .about("Create a configuration file for the user, only accessible by them.")
+            .arg(Arg::with_name("generate name")
+                 .long("generate-name")
+                 .short("g")
+                 .help("Generate a random full name.\n\
+                        If the name is kept secret, this is more secure.")
+                 .conflicts_with("full name"))
+            .arg(Arg::with_name("key id")
+                 .long("key-id")
+                 .short("k")
+                 .help("Ask for the master password and remember the ID of the master key,\n\
+                        so that a mistyped master password is rejected.")))
+        .subcommand(SubCommand::with_name("import")
+            .about("Import sites from a .mpsites file of the official apps into the configuration.\n\
+                    Both the flat and the JSON format are supported.")
+            .arg(Arg::with_name("file")
+                 .help("The .mpsites file to import.")
+                 .index(1)
+                 .required(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Export the configuration as a .mpsites file for the official apps.")
+            .arg(Arg::with_name("file")
+                 .help("The .mpsites file to write (defaults to stdout).")
+                 .index(1))
+            .arg(Arg::with_name("format")
+                 .long("format")
+                 .short("f")
+                 .help("The format of the .mpsites file (defaults to 'flat').")
+                 .takes_value(true)
+                 .number_of_values(1)
+                 .possible_values(&["flat", "json"])))
+        .subcommand(SubCommand::with_name("agent")
+            .about("Start an agent that keeps the master keys in memory.\n\
+                    Use it like `eval $(mpw agent)`, subsequent invocations will not ask \
+                    for the master password.")
+            .arg(Arg::with_name("timeout")
+                 .long("timeout")
+                 .short("t")
+                 .help("Exit after being idle for the given number of seconds (defaults to 3600).")
+                 .takes_value(true)
+                 .number_of_values(1))
+            .arg(Arg::with_name("foreground")
+                 .long("foreground")
+                 .short("f")
+                 .help("Do not fork into the background.")))
+        .subcommand(SubCommand::with_name("identicon")
+            .about("Render the identicon of the user, for instance to put it into documents.")
+            .arg(Arg::with_name("file")
+                 .help("The file to write (defaults to stdout).")
+                 .index(1))
+            .arg(Arg::with_name("format")
+                 .long("format")
+                 .short("f")
+                 .help("The format of the identicon (defaults to 'text').\n\
+                        PNG images are converted from SVG by rsvg-convert.")
+                 .takes_value(true)
+                 .number_of_values(1)
+                 .possible_values(&["text", "svg", "png"])))
+        .subcommand(SubCommand::with_name("reencrypt")
+            .about("Re-encrypt stored passwords that use an old encryption format.\n\
+                    Old formats can still be decrypted, but used the master key directly.")
+            .arg(Arg::with_name("legacy")
+                 .long("legacy")
+                 .help("Also re-encrypt passwords stored before they were bound to their site.\n\
+                        They could have been copied from another site, so check them.")))
+        .subcommand(SubCommand::with_name("doctor")
+            .about("Report how well mpw is protected against leaking secrets."))
+        .subcommand(SubCommand::with_name(clipboard::CLEAR_COMMAND)
+            .about("Clear the clipboard after a timeout, run by --copy.")
+            .setting(AppSettings::Hidden)
+            .arg(Arg::with_name("backend")
+                 .index(1)
+                 .required(true))
+            .arg(Arg::with_name("timeout")
+                 .index(2)
+                 .required(true)))
         .get_matches();
 
-    // If given, read config from path.
-    let config_path = matches.value_of("config");
-    let mut config_string = String::new();
-    let mut config = if let Some(path) = config_path {
-        let file = File::open(path);
-        match file {
-            Ok(mut f) => {
-                f.read_to_string(&mut config_string)
-                    .unwrap_or_exit("could not read given config file");
-                Config::from_str(&config_string)
-                    .unwrap_or_exit("could not parse given config file")
-            },
-            Err(_) => {
-                // If the config file is not present, assume an empty config.
-                // TODO: make sure we get an error if the config is the only argument
-                Config::new()
+    let (command, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, Some(sub_matches)),
+        _ => (deprecated_command(&matches), None),
+    };
+    let options = Options { global: &matches, sub: sub_matches };
+
+    if let Some(policy) = options.value_of("mlock") {
+        set_mlock_policy(MlockPolicy::from_str(policy).unwrap());
+        //^ This unwrap is safe, because clap already did the check.
+    }
+
+    // Keep secrets out of core dumps and away from debuggers.
+    let hardened = [hardening::disable_core_dumps(), hardening::disable_ptrace()];
+    print_warnings(&hardened.iter()
+        .filter_map(|r| r.as_ref().err().map(|e| e.message.clone()))
+        .collect::<Vec<_>>());
+    if options.is_present("refuse preload") {
+        hardening::check_preload().unwrap_or_else(|e| exit(&e.message));
+    }
+
+    // The process clearing the clipboard does not need the config.
+    if command == clipboard::CLEAR_COMMAND {
+        let backend = options.value_of("backend").unwrap();
+        //^ This unwrap is safe, because clap already checked that it is given.
+        let timeout = options.value_of("timeout").unwrap();
+        //^ This unwrap is safe, because clap already checked that it is given.
+        let timeout = timeout.parse().unwrap_or_exit("timeout must be a number of seconds");
+        clipboard::clear_later(backend, Duration::from_secs(timeout))
+            .unwrap_or_else(|e| exit(&e.message));
+        return;
+    }
+
+    // Read config from the given path or the default one. Commands that
+    // change the config lock it exclusively for the whole read-modify-write,
+    // the others only when they remember something in it.
+    let config_path = options.value_of("config").map(PathBuf::from).or_else(config::default_path);
+    let lock = match command {
+        "init" | "import" | "reencrypt" | "add" | "rm" | "store" => Lock::Exclusive,
+        _ => Lock::Shared,
+    };
+    // A missing config file is most likely a typo, so it is only created on
+    // request. Only a config at the default location is optional, and only
+    // for commands that work without one.
+    let create = command == "init" || options.is_present("create");
+    let required = options.value_of("config").is_some() ||
+        env::var_os(config::CONFIG_ENV_VAR).is_some_and(|v| !v.is_empty()) ||
+        !["get", "agent", "identicon", "doctor"].contains(&command);
+    let missing = |path: &Path| -> ! {
+        exit(&format!("config file {} does not exist, create it with `mpw init` or --create",
+                      path.display()))
+    };
+    let config_path = config_path.and_then(|path| match fs::metadata(&path) {
+        Ok(_) => Some(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if create {
+                Some(path)
+            } else if required {
+                missing(&path)
+            } else {
+                None
             }
-        }
-    } else {
-        Config::new()
+        },
+        Err(e) => exit(&format!("could not access config file {}: {}", path.display(), e)),
+    });
+    let mut config_file = config_path.map(|path| open_config_file(&path, lock));
+    let config_string = match config_file {
+        Some(ref file) => match file.read() {
+            Ok(Some(s)) => s,
+            Ok(None) if create => String::new(),
+            Ok(None) => missing(file.path()),
+            Err(e) => exit(&e.message),
+        },
+        None => String::new(),
     };
+    let mut config = Config::from_str(&config_string).unwrap_or_else(|e| exit(&format!(
+        "could not parse config file {}: {}",
+        config_file.as_ref().map_or(Path::new(""), |f| f.path()).display(), e)));
+    // Sites referring to an undefined template are most likely typos.
+    let templates = config.templates().unwrap_or_else(|e| exit(&e.message));
+    if let Some(type_) = options.value_of("type") {
+        templates.check_type(&SiteType::from_name(type_)).unwrap_or_else(|e| exit(&e.message));
+    }
 
-    // Read config from CLI parameters.
-    let mut param_config = Config::new();
-    let rng = SystemRandom::new();
-    param_config.full_name = if matches.is_present("generate name") {
-        let name: String = random_password_for_site(&rng, SiteType::GeneratedMaximum)
-            .unwrap_or_exit("failed to generate random full name").clone();
-        println!("generated random full name: \"{}\"", name);
-        Some(name.into())
+    let confirm_identicon = options.is_present("confirm identicon");
+    let password_source = if let Some(fd) = options.value_of("password fd") {
+        PasswordSource::Fd(fd.parse().unwrap_or_exit("password-fd must be a file descriptor"))
+    } else if let Some(path) = options.value_of("password file") {
+        PasswordSource::File(path.into())
+    } else if let Some(name) = options.value_of("password env") {
+        PasswordSource::Env(name.into())
+    } else if let Some(command) = options.value_of("password command") {
+        PasswordSource::Command(command.into())
+    } else if let Some(program) = options.value_of("pinentry") {
+        PasswordSource::Pinentry(program.into())
     } else {
-        matches.value_of("full name").map(Into::into)
+        PasswordSource::Terminal
     };
-    let param_site_name = matches.value_of("site");
-    if let Some(name) = param_site_name {
-        let param_site_config = SiteConfig {
-            name: name.into(),
-            type_: matches.value_of("type").map(|s| SiteType::from_str(s).unwrap()),
-            //^ This unwrap is safe, because clap already did the check.
-            counter: matches.value_of("counter")
-            .map(|c| c.parse().unwrap_or_exit("counter must be an unsigned 32-bit integer")),
-            variant: matches.value_of("variant").map(|s| SiteVariant::from_str(s).unwrap()),
+
+    match command {
+        "doctor" => {
+            let report = hardening::Report::collect();
+            print!("{}", report);
+            print_warnings(&report.warnings());
+            return;
+        },
+        "init" => {
+            let file = config_file.as_mut().unwrap_or_exit("need a config file to create");
+            if file.read().unwrap_or_else(|e| exit(&e.message)).is_some() {
+                exit(&format!("config file {} already exists", file.path().display()));
+            }
+            let full_name = if options.is_present("generate name") {
+                generate_full_name()
+            } else {
+                options.value_of("full name")
+                    .unwrap_or_exit("need a full name, use --name or --generate-name")
+                    .to_string()
+            };
+            let mut keys = KeySource::new(full_name.clone(), password_source.clone(), false, None)
+                .with_identicon(None, confirm_identicon);
+            let mut new_config = Config::new();
+            new_config.full_name = Some(full_name.into());
+            if confirm_identicon || options.is_present("key id") {
+                keys.unlock();
+                keys.remember_in(&mut new_config);
+            }
+            write_config(file, &new_config);
+            eprintln!("Created config file {}.", file.path().display());
+            return;
+        },
+        "import" => {
+            let file = config_file.as_mut().unwrap_or_exit("need a config file to import into");
+            let import_file = options.value_of("file").unwrap();
             //^ This unwrap is safe, because clap already did the check.
-            context: matches.value_of("context").map(Into::into),
-            encrypted: None,
-        };
-        param_config.sites = Some(vec![param_site_config]);
+            let import_string = read_file(import_file).unwrap_or_exit("could not read given file");
+            let (imported, warnings) = mpsites::read(&import_string)
+                .unwrap_or_else(|e| exit(&format!("could not parse given file: {}", e.message)));
+            print_warnings(&warnings);
+            let warnings = merge_import(&mut config, imported)
+                .unwrap_or_else(|e| exit(&e.message));
+            print_warnings(&warnings);
+            write_config(file, &config);
+            return;
+        },
+        "export" => {
+            let export = match options.value_of("format") {
+                Some("json") => write_json(&config),
+                _ => write_flat(&config),
+            };
+            let (s, warnings) = export
+                .unwrap_or_else(|e| exit(&format!("could not export config: {}", e.message)));
+            print_warnings(&warnings);
+            if let Some(file) = options.value_of("file") {
+                let mut f = File::create(file)
+                    .unwrap_or_exit("could not create given file");
+                f.write_all(s.as_bytes())
+                    .unwrap_or_exit("could not write to given file");
+            } else {
+                print!("{}", s);
+            }
+            return;
+        },
+        "agent" => {
+            let full_name = merge_options(
+                config.full_name.as_ref().map(|n| n.as_ref()),
+                options.value_of("full name"),
+            ).unwrap_or_exit("need full name to generate master key");
+            let timeout = options.value_of("timeout")
+                .map(|t| t.parse().unwrap_or_exit("timeout must be a number of seconds"))
+                .unwrap_or(agent::DEFAULT_IDLE_TIMEOUT);
+            // The key ID and identicon in the config only apply to its user.
+            let (key_id, expected_identicon) =
+                if config.full_name.as_ref().map(|n| n.as_ref()) == Some(full_name) {
+                    (config.key_id.clone(), config.identicon.clone())
+                } else {
+                    (None, None)
+                };
+            let mut keys = KeySource::new(full_name.to_string(), password_source.clone(), false,
+                                          key_id)
+                .with_identicon(expected_identicon, confirm_identicon);
+            keys.unlock();
+            if let Some(ref mut file) = config_file {
+                remember_in_config_file(&keys, file, &config_string);
+            }
+            let master_password = keys.master_password.take().unwrap();
+            //^ This unwrap is safe, because `unlock` either set it or exited.
+            // The agent derives its own keys.
+            drop(keys);
+            // The forked agent would inherit the lock and hold it until it exits.
+            drop(config_file);
+            agent::run(full_name, master_password, Duration::from_secs(timeout),
+                       options.is_present("foreground"))
+                .unwrap_or_else(|e| exit(&e.message));
+            return;
+        },
+        "identicon" => {
+            let full_name = merge_options(
+                config.full_name.as_ref().map(|n| n.as_ref()),
+                options.value_of("full name"),
+            ).unwrap_or_exit("need full name to generate identicon");
+            let master_password = read_master_password(
+                full_name, &password_source, config.identicon.as_deref());
+            let identicon = identicon(full_name.as_bytes(), master_password.as_bytes());
+            let rendered = match options.value_of("format") {
+                Some("svg") => render_svg(&identicon).into_bytes(),
+                Some("png") => render_png(&identicon).unwrap_or_else(|e| exit(&e.message)),
+                _ => format!("{}\n", identicon).into_bytes(),
+            };
+            if let Some(file) = options.value_of("file") {
+                let mut f = File::create(file)
+                    .unwrap_or_exit("could not create given file");
+                f.write_all(&rendered)
+                    .unwrap_or_exit("could not write to given file");
+            } else {
+                std::io::stdout().write_all(&rendered)
+                    .unwrap_or_exit("could not write identicon");
+            }
+            return;
+        },
+        "reencrypt" => {
+            let file = config_file.as_mut().unwrap_or_exit("need a config file to re-encrypt");
+            let full_name = merge_options(
+                config.full_name.as_ref().map(|n| n.as_ref()),
+                options.value_of("full name"),
+            ).unwrap_or_exit("need full name to generate master key").to_string();
+            let mut keys = KeySource::new(full_name, password_source.clone(),
+                                          !options.is_present("no agent"), config.key_id.clone())
+                .with_identicon(config.identicon.clone(), confirm_identicon);
+            let mut count = 0;
+            for site_config in config.sites.iter_mut().flat_map(|sites| sites.iter_mut()) {
+                let encrypted = {
+                    let site = Site::from_config(site_config).unwrap_or_else(|e| exit(&e.message));
+                    if !site.has_outdated_encryption() {
+                        continue;
+                    }
+                    let password = match keys.password_for_site(&site, &templates) {
+                        Ok(password) => password,
+                        Err(e) => {
+                            if !options.is_present("legacy") {
+                                exit(&format!("could not decrypt stored password for {}: {} \
+                                               (use --legacy for passwords stored by old \
+                                               versions)", site.name, e));
+                            }
+                            let password = keys.unbound_password_for_site(&site)
+                                .unwrap_or_else(|_| exit(&format!(
+                                    "could not decrypt stored password for {}: {}",
+                                    site.name, e)));
+                            eprintln!("warning: the stored password for {} was not bound to \
+                                       its site, check that it belongs to it", site.name);
+                            password
+                        },
+                    };
+                    let password = password.borrow();
+                    keys.encrypt_password(&password, &site)
+                };
+                site_config.encrypted = Some(encrypted.into());
+                count += 1;
+            }
+            if keys.remember_in(&mut config) || count > 0 {
+                write_config(file, &config);
+            }
+            eprintln!("Re-encrypted {} stored password(s).", count);
+            return;
+        },
+        _ => {},
     }
 
-    if matches.is_present("replace") || matches.is_present("delete") {
-        // Remove all sites that have the given name, unless they stored a
-        // password.
-        let param_site_name = param_site_name.unwrap();
-        //^ This unwrap is safe, because clap already did the check.
-        if let Some(ref mut sites) = config.sites {
-            sites.retain(|s|
-                s.name != param_site_name || s.encrypted.is_some());
+    // The full name given on the command line has to agree with the config.
+    let param_full_name = if options.is_present("generate name") {
+        Some(generate_full_name())
+    } else {
+        options.value_of("full name").map(Into::into)
+    };
+    if let Some(name) = param_full_name {
+        if config.full_name.as_ref().is_some_and(|config_name| *config_name != name) {
+           exit("full name given as parameter conflicts with config");
         }
+        config.full_name = Some(name.into());
     }
+    let use_agent = !options.is_present("no agent");
+    let site_name = options.value_of("site");
 
-    let mut master_key = None;
+    match command {
+        "dump" => {
+            // Output config.
+            let s = config.encode()
+                .unwrap_or_exit("could not encode config");
+            debug_assert!(!s.is_empty());
+            println!("{}", s);
+            return;
+        },
+        "add" => {
+            let file = config_file.as_mut()
+                .unwrap_or_exit("need a config file to add the site to");
+            let site_name = site_name.unwrap();
+            //^ This unwrap is safe, because clap already did the check.
+            if options.is_present("replace") {
+                remove_sites(&mut config, site_name, false);
+            }
+            let site_config = param_site_config(&options, site_name);
+            config.sites.get_or_insert_with(Vec::new).push(site_config);
+            write_config(file, &config);
+            return;
+        },
+        "rm" => {
+            let file = config_file.as_mut()
+                .unwrap_or_exit("need a config file to delete the site from");
+            let site_name = site_name.unwrap();
+            //^ This unwrap is safe, because clap already did the check.
+            if remove_sites(&mut config, site_name, options.is_present("stored")) == 0 {
+                let has_stored = config.sites.iter().flat_map(|sites| sites.iter())
+                    .any(|s| s.name == site_name);
+                if has_stored {
+                    exit(&format!("{} only has stored passwords, use --stored to delete them",
+                                  site_name));
+                }
+                exit(&format!("there is no site {} in the config", site_name));
+            }
+            write_config(file, &config);
+            return;
+        },
+        "store" => {
+            let file = config_file.as_mut()
+                .unwrap_or_exit("need a config file to store the password in");
+            let full_name = config.full_name.as_ref()
+                .unwrap_or_exit("need full name to generate master key");
+            let mut keys = KeySource::new(full_name.to_string(), password_source.clone(),
+                                          use_agent, config.key_id.clone())
+                .with_identicon(config.identicon.clone(), confirm_identicon);
+            let site_name = site_name.unwrap();
+            //^ This unwrap is safe, because clap already did the check.
+            let mut site_config = param_site_config(&options, site_name);
+            site_config.type_ = Some(SiteType::Stored);
 
-    // Merge parameters into config.
-    if let (Some(config_name), Some(param_name)) =
-        (config.full_name.as_ref(), param_config.full_name.as_ref())
-    {
-        if config_name != param_name {
-           exit("full name given as parameter conflicts with config");
-        }
-    }
-    if matches.is_present("store") {
-        let full_name = merge_options(
-            config.full_name.as_ref(),
-            param_config.full_name.as_ref(),
-        ).unwrap_or_exit("need full name to generate master key");
-        let key = generate_master_key(full_name);
-
-        let password = get_site_password();
-        let mut buffer = vec![0; min_buffer_len(password.len())];
-        encrypt(password.as_ref(), &key, &mut buffer);
-        let site = &mut param_config.sites.as_mut().unwrap()[0];
-        //^ This unwrap is safe, because we now it was set to Some before.
-        site.encrypted = Some(
-            base64::encode(&buffer).into()
-        );
-        site.type_ = Some(SiteType::Stored);
-        master_key = Some(key);
-    }
-    config.merge(param_config);
-
-    if matches.is_present("add") ||
-       matches.is_present("replace") ||
-       matches.is_present("delete") ||
-       matches.is_present("store") {
-        // Overwrite config file.
-        let s = config.encode()
-            .unwrap_or_exit("could not encode config");
-        debug_assert!(s != "");
-        let path = config_path.as_ref().unwrap();
-        //^ This unwrap is safe, because clap already did the check.
-        let mut f = File::create(path)
-            .unwrap_or_exit("could not overwrite given config file");
-        f.write_all(s.as_bytes())
-            .unwrap_or_exit("could not write to given config file");
-        return;
+            let password = get_site_password();
+            let encrypted = {
+                let site = Site::from_config(&site_config).unwrap_or_else(|e| exit(&e.message));
+                keys.encrypt_password(&password, &site)
+            };
+            site_config.encrypted = Some(encrypted.into());
+            config.sites.get_or_insert_with(Vec::new).push(site_config);
+            keys.remember_in(&mut config);
+            write_config(file, &config);
+            return;
+        },
+        _ => {},
     }
 
-    if matches.is_present("dump") {
-        // Output config.
-        let s = config.encode()
-            .unwrap_or_exit("could not encode config");
-        debug_assert!(s != "");
-        println!("{}", s);
-        return;
-    }
+    // Only `get` and `list` are left.
+    let sites = config.sites.as_ref().map_or(&[][..], |sites| &sites[..]);
+    let site_configs = if let Some(name) = site_name {
+        sites_named(&config, param_site_config(&options, resolve_site(sites, name)))
+    } else {
+        let selected: Vec<&SiteConfig> = match options.value_of("pattern") {
+            Some(pattern) => {
+                let mode = Mode::from_str(options.value_of("match").unwrap_or("auto")).unwrap();
+                //^ This unwrap is safe, because clap already did the check.
+                let matches = search(sites, pattern, mode);
+                if matches.is_empty() {
+                    exit(&format!("no site matches {}", pattern));
+                }
+                matches.into_iter().map(|m| m.site).collect()
+            },
+            None => sites.iter().collect(),
+        };
+        if !options.is_present("passwords") {
+            for name in distinct_names(selected) {
+                println!("{}", name);
+            }
+            return;
+        }
+        if selected.is_empty() {
+            exit("need a site via command line parameters or via config");
+        }
+        selected.into_iter().cloned().collect()
+    };
 
     let full_name = config.full_name.as_ref()
         .unwrap_or_exit("need full name to generate master key");
 
-    let site_configs = config.sites.as_ref()
-        .unwrap_or_exit("need a site via command line parameters or via config");
+    let mut keys = KeySource::new(full_name.to_string(), password_source.clone(), use_agent,
+                                  config.key_id.clone())
+        .with_identicon(config.identicon.clone(), confirm_identicon);
 
-    let master_key = if let Some(key) = master_key {
-        key
-    } else {
-        generate_master_key(full_name)
-    };
+    let clear_after = options.value_of("clear after")
+        .map(|t| t.parse().unwrap_or_exit("clear-after must be a number of seconds"))
+        .unwrap_or(clipboard::DEFAULT_CLEAR_TIMEOUT);
+    let mut to_copy: Option<(String, SecretString)> = None;
+    let mut outdated_format = false;
+    let mut unbound_format = false;
+    let mut failed = false;
 
     // Generate or decrypt passwords.
     println!();
-    for site_config in site_configs {
+    for site_config in &site_configs {
         let site = Site::from_config(site_config).unwrap_or_else(|e| exit(&e.message));
-        // If a site was given, skip all other sites.
-        // FIXME: site from parameter should not be printed if already present?
-        if let Some(name) = param_site_name {
-            if name != site.name {
+        outdated_format |= site.has_outdated_encryption();
+        unbound_format |= site.has_unbound_encryption();
+        let password = match keys.password_for_site(&site, &templates) {
+            Ok(password) => password,
+            // A stored password that cannot be decrypted is reported, but does
+            // not prevent printing the other passwords.
+            Err(ref e) if site.type_ == SiteType::Stored => {
+                eprintln!("error: could not decrypt stored password for {}: {}", site.name, e);
+                failed = true;
                 continue;
-            }
-        }
-        // We have to define the containers of the passwords here, so that the
-        // slices into them survive until we print the password.
-        let mut buffer = ClearOnDrop::new(vec![]);
-        let password_string;
-        let password = match site.type_ {
-            SiteType::Stored => {
-                let encrypted = site.encrypted.as_ref()
-                    .unwrap_or_exit("found stored password without 'encrypted' field")
-                    .as_bytes();
-                let decoded = &base64::decode(encrypted)
-                    .unwrap_or_exit("could not decode 'encrypted' field");
-                buffer.resize(decoded.len(), 0);
-                buffer.clone_from_slice(decoded);
-                let decrypted = decrypt(&master_key, &mut buffer);
-                std::str::from_utf8(decrypted).unwrap_or_exit("could not decrypt stored password")
-            },
-            _ => {
-                password_string = password_for_site_v3(
-                    &master_key,
-                    site.name.as_bytes(),
-                    site.type_,
-                    site.counter,
-                    site.variant,
-                    site.context.as_bytes()
-                ).unwrap_or_exit("could not generate site password");
-                &password_string
             },
+            Err(e) => exit(&format!("{}: {}", site.name, e)),
         };
         // TODO: print non-default parameters
-        println!("Password for {}: {}", site.name, password);
+        if let Some(ref login_name) = site.login_name {
+            println!("Login for {}: {}", site.name, login_name);
+        }
+        if options.is_present("copy") {
+            if to_copy.is_some() {
+                exit(&format!("more than one password for {}, cannot copy all of them",
+                              site.name));
+            }
+            to_copy = Some((site.name.to_string(), password));
+            continue;
+        }
+        println!("Password for {}: {}", site.name, &*password.borrow());
+    }
+
+    // Remember the key ID and the identicon, so that a mistyped master
+    // password is detected next time.
+    if let Some(ref mut file) = config_file {
+        remember_in_config_file(&keys, file, &config_string);
+    }
+
+    if unbound_format {
+        eprintln!("note: some stored passwords are not bound to their site yet, \
+                   run `mpw reencrypt --legacy` to upgrade them");
+    } else if outdated_format {
+        eprintln!("note: some stored passwords use an old encryption format, \
+                   run `mpw reencrypt` to upgrade them");
+    }
+
+    // The master keys are not needed anymore.
+    drop(keys);
+
+    if let Some((name, password)) = to_copy {
+        clipboard::copy(options.value_of("clipboard").unwrap_or("auto"), &password.borrow(),
+                        Duration::from_secs(clear_after))
+            .unwrap_or_else(|e| exit(&e.message));
+        if clear_after == 0 {
+            println!("Copied password for {} to the clipboard.", name);
+        } else {
+            println!("Copied password for {} to the clipboard, it will be cleared in {} seconds.",
+                     name, clear_after);
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
     }
 }
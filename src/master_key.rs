@@ -0,0 +1,194 @@
+//! The master key of a user, the entry point for generating and storing the
+//! passwords of sites.
+
+extern crate data_encoding;
+
+use self::data_encoding::base64;
+
+use algorithm::{AlgorithmVersion, Error, ErrorKind, SiteType, Templates, master_key_salt,
+    master_key_for_user, site_key, password_for_site_key, key_id, associated_data,
+    min_buffer_len, encrypt, decrypt, decrypt_unbound};
+use config::Site;
+use secret::{Ref, SecretBox, SecretString};
+
+/// The master key of a user for an algorithm version.
+///
+/// The key is kept in protected memory.
+pub struct MasterKey {
+    full_name: String,
+    version: AlgorithmVersion,
+    key: SecretBox<[u8; 64]>,
+}
+
+impl MasterKey {
+    /// Derive the master key of a user from the master password.
+    ///
+    /// This is slow on purpose.
+    pub fn new(full_name: &str, master_password: &str, version: AlgorithmVersion)
+        -> Result<MasterKey, Error>
+    {
+        let key = master_key_for_user(full_name.as_bytes(), master_password.as_bytes(), version)?;
+        Ok(MasterKey::from_key(full_name, version, key))
+    }
+
+    /// Use a master key that was derived before.
+    pub fn from_key(full_name: &str, version: AlgorithmVersion, key: SecretBox<[u8; 64]>)
+        -> MasterKey
+    {
+        MasterKey { full_name: full_name.into(), version, key }
+    }
+
+    /// Return the full name of the user.
+    pub fn full_name(&self) -> &str {
+        &self.full_name
+    }
+
+    /// Return the algorithm version the key was derived with.
+    pub fn version(&self) -> AlgorithmVersion {
+        self.version
+    }
+
+    /// Return the raw key.
+    pub fn as_bytes(&self) -> Ref<'_, [u8; 64]> {
+        self.key.borrow()
+    }
+
+    /// Return the ID of the key, like the `keyID` of the official apps.
+    pub fn key_id(&self) -> String {
+        key_id(&self.key.borrow())
+    }
+
+    /// Whether the key can be used with the given algorithm version.
+    ///
+    /// Versions derive the same master key unless the full name contains
+    /// non-ASCII characters.
+    pub fn supports_version(&self, version: AlgorithmVersion) -> bool {
+        version == self.version ||
+            master_key_salt(self.full_name.as_bytes(), version).ok() ==
+            master_key_salt(self.full_name.as_bytes(), self.version).ok()
+    }
+
+    /// Fail unless the key can be used with the given algorithm version.
+    fn check_version(&self, version: AlgorithmVersion) -> Result<(), Error> {
+        if self.supports_version(version) {
+            return Ok(());
+        }
+        Err(Error {
+            message: format!("need master key of algorithm version {}, got version {}",
+                             version.to_u32(), self.version.to_u32()),
+            kind: ErrorKind::WrongVersion,
+        })
+    }
+
+    /// Derive the key of a site.
+    pub fn site_key(&self, site: &Site) -> Result<SecretBox<[u8; 32]>, Error> {
+        self.check_version(site.algorithm)?;
+        site_key(&self.key.borrow(), site.name.as_bytes(), site.counter, site.variant,
+                 site.context.as_bytes(), site.algorithm)
+    }
+
+    /// Return the password of a site, either generated or decrypted.
+    ///
+    /// Stored passwords are always encrypted with the master key of the
+    /// latest algorithm version.
+    pub fn password_for_site(&self, site: &Site, templates: &Templates)
+        -> Result<SecretString, Error>
+    {
+        if site.type_ != SiteType::Stored {
+            let key = self.site_key(site)?;
+            return password_for_site_key(&key.borrow(), &site.type_, site.algorithm, templates,
+                                         &site.policy);
+        }
+        self.check_version(AlgorithmVersion::latest())?;
+        let mut buffer = site.decode_stored()?;
+        let mut buffer = buffer.borrow_mut();
+        let decrypted = decrypt(&self.key.borrow(), &site_associated_data(site)?, &mut buffer)?;
+        stored_password(decrypted)
+    }
+
+    /// Decrypt a stored password that was encrypted before passwords were
+    /// bound to their site.
+    ///
+    /// It could have been copied from another site, so this is only meant for
+    /// re-encrypting it after warning the user.
+    pub fn unbound_password_for_site(&self, site: &Site) -> Result<SecretString, Error> {
+        self.check_version(AlgorithmVersion::latest())?;
+        let mut buffer = site.decode_stored()?;
+        let mut buffer = buffer.borrow_mut();
+        let decrypted = decrypt_unbound(&self.key.borrow(), &mut buffer)?;
+        stored_password(decrypted)
+    }
+
+    /// Encrypt a password to be stored for a site.
+    ///
+    /// The result is meant for the `encrypted` field of the site.
+    pub fn encrypt_password(&self, password: &str, site: &Site) -> Result<String, Error> {
+        self.check_version(AlgorithmVersion::latest())?;
+        let mut buffer = vec![0; min_buffer_len(password.len())];
+        encrypt(password.as_bytes(), &self.key.borrow(), &site_associated_data(site)?,
+                &mut buffer)?;
+        Ok(base64::encode(&buffer))
+    }
+}
+
+/// Return the data the stored password of a site is bound to, so that it
+/// cannot be moved to another site.
+pub fn site_associated_data(site: &Site) -> Result<Vec<u8>, Error> {
+    associated_data(site.name.as_bytes(), site.counter, site.variant, site.context.as_bytes())
+}
+
+/// Interpret a decrypted stored password.
+pub fn stored_password(decrypted: &[u8]) -> Result<SecretString, Error> {
+    let password = ::std::str::from_utf8(decrypted).map_err(|_| Error {
+        message: "stored password is not valid UTF-8".into(),
+        kind: ErrorKind::InvalidStoredPassword,
+    })?;
+    Ok(SecretString::new(password))
+}
+
+#[test]
+fn test_master_key() {
+    use config::SiteConfig;
+
+    let key = MasterKey::new("John Doe", "password", AlgorithmVersion::V3).unwrap();
+    assert_eq!(key.full_name(), "John Doe");
+    assert_eq!(key.version(), AlgorithmVersion::V3);
+    assert_eq!(key.key_id(), key_id(&key.as_bytes()));
+
+    let github = SiteConfig::new("github.com");
+    let site = Site::from_config(&github).unwrap();
+    let templates = Templates::new();
+    assert_eq!(&*key.password_for_site(&site, &templates).unwrap().borrow(), "VubeNazoRihe4(");
+
+    // The name is ASCII, so all versions use the same master key.
+    let mut old = SiteConfig::new("github.com");
+    old.algorithm = Some(AlgorithmVersion::V0);
+    assert!(key.password_for_site(&Site::from_config(&old).unwrap(), &templates).is_ok());
+
+    let mut stored = SiteConfig::new("wikipedia.org");
+    stored.type_ = Some(SiteType::Stored);
+    let encrypted = key.encrypt_password("secret", &Site::from_config(&stored).unwrap())
+        .unwrap();
+    stored.encrypted = Some(encrypted.into());
+    let site = Site::from_config(&stored).unwrap();
+    assert_eq!(&*key.password_for_site(&site, &templates).unwrap().borrow(), "secret");
+
+    stored.name = "wikipedia.com".into();
+    let site = Site::from_config(&stored).unwrap();
+    assert_eq!(key.password_for_site(&site, &templates).unwrap_err().kind, ErrorKind::WrongSite);
+}
+
+#[test]
+fn test_master_key_version() {
+    use config::SiteConfig;
+
+    // Versions before v3 measured the full name in characters.
+    let key = MasterKey::new("Max Müller", "passwort", AlgorithmVersion::V2).unwrap();
+    let mut site_config = SiteConfig::new("de.wikipedia.org");
+    let templates = Templates::new();
+    let e = key.password_for_site(&Site::from_config(&site_config).unwrap(), &templates)
+        .unwrap_err();
+    assert_eq!(e.kind, ErrorKind::WrongVersion);
+    site_config.algorithm = Some(AlgorithmVersion::V1);
+    assert!(key.password_for_site(&Site::from_config(&site_config).unwrap(), &templates).is_ok());
+}
@@ -0,0 +1,1115 @@
+//! This implements the flat and the JSON `.mpsites` export formats of the
+//! official Master Password apps.
+//!
+//! Only the parameters of the sites are exchanged. Passwords stored by the
+//! official apps are encrypted differently and can therefore not be imported.
+
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use algorithm::{SiteType, SiteVariant, AlgorithmVersion};
+use config::{Config, SiteConfig, Site};
+
+/// Import/export kind of error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The format of the file is not supported.
+    UnsupportedFormat,
+    /// The header is missing or invalid.
+    InvalidHeader,
+    /// The full name is missing.
+    MissingFullName,
+    /// A line describing a site is invalid.
+    InvalidSite,
+    /// A site has an unknown type.
+    UnknownType,
+    /// A site has an unknown algorithm version.
+    UnknownAlgorithm,
+    /// A site has an invalid counter.
+    InvalidCounter,
+    /// A site in the config is invalid.
+    InvalidConfig,
+    /// The JSON could not be parsed or generated.
+    Json,
+}
+
+/// Import/export error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        let message = match kind {
+            ErrorKind::UnsupportedFormat => "unsupported format",
+            ErrorKind::InvalidHeader => "invalid header",
+            ErrorKind::MissingFullName => "missing full name",
+            ErrorKind::InvalidSite => "invalid site",
+            ErrorKind::UnknownType => "unknown site type",
+            ErrorKind::UnknownAlgorithm => "unknown algorithm version",
+            ErrorKind::InvalidCounter => "invalid site counter",
+            ErrorKind::InvalidConfig => "invalid site in config",
+            ErrorKind::Json => "invalid JSON",
+        };
+        Error { message: message.into(), kind }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error {
+            message: format!("invalid JSON: {}", e),
+            kind: ErrorKind::Json,
+        }
+    }
+}
+
+impl Error {
+    /// Create an error of the given kind for the given line.
+    fn at_line(kind: ErrorKind, line: usize) -> Error {
+        let mut e = Error::from(kind);
+        e.message = format!("{} in line {}", e.message, line);
+        e
+    }
+}
+
+/// Type number of stored passwords that are exported by the official apps.
+const TYPE_STORED_PERSONAL: u32 = 0x420;
+/// Type number of stored passwords that are not exported by the official apps.
+const TYPE_STORED_DEVICE_PRIVATE: u32 = 0x821;
+
+/// Return the type number used by the official apps.
+///
+/// Returns None for custom templates, which the official apps do not support.
+fn type_to_u32(site_type: &SiteType) -> Option<u32> {
+    match *site_type {
+        SiteType::GeneratedMaximum => Some(0x10),
+        SiteType::GeneratedLong => Some(0x11),
+        SiteType::GeneratedMedium => Some(0x12),
+        SiteType::GeneratedShort => Some(0x13),
+        SiteType::GeneratedBasic => Some(0x14),
+        SiteType::GeneratedPIN => Some(0x15),
+        SiteType::GeneratedName => Some(0x1e),
+        SiteType::GeneratedPhrase => Some(0x1f),
+        SiteType::Stored => Some(TYPE_STORED_PERSONAL),
+        SiteType::Custom(_) => None,
+    }
+}
+
+/// Try to construct a SiteType from the type number used by the official apps.
+///
+/// Returns None if the number does not correspond to a type.
+fn type_from_u32(n: u32) -> Option<SiteType> {
+    match n {
+        0x10 => Some(SiteType::GeneratedMaximum),
+        0x11 => Some(SiteType::GeneratedLong),
+        0x12 => Some(SiteType::GeneratedMedium),
+        0x13 => Some(SiteType::GeneratedShort),
+        0x14 => Some(SiteType::GeneratedBasic),
+        0x15 => Some(SiteType::GeneratedPIN),
+        0x1e => Some(SiteType::GeneratedName),
+        0x1f => Some(SiteType::GeneratedPhrase),
+        TYPE_STORED_PERSONAL | TYPE_STORED_DEVICE_PRIVATE => Some(SiteType::Stored),
+        _ => None,
+    }
+}
+
+/// Format seconds since the Unix epoch as an ISO 8601 UTC date.
+fn format_date(secs: u64) -> String {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// Return the current time as an ISO 8601 UTC date.
+fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_date(secs)
+}
+
+/// Split off the next token delimited by whitespace.
+fn next_word<'a>(s: &mut &'a str) -> Option<&'a str> {
+    let trimmed = s.trim_start();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let end = trimmed.find(|c: char| c.is_whitespace()).unwrap_or(trimmed.len());
+    let (word, rest) = trimmed.split_at(end);
+    *s = rest;
+    Some(word)
+}
+
+/// Split off the next token delimited by a tab, trimming surrounding spaces.
+fn next_field<'a>(s: &mut &'a str) -> Option<&'a str> {
+    if s.is_empty() {
+        return None;
+    }
+    let end = s.find('\t').unwrap_or(s.len());
+    let field = &s[..end];
+    *s = if end < s.len() { &s[end + 1..] } else { &s[end..] };
+    Some(field.trim_matches(' '))
+}
+
+/// Return the key ID of the user, if `mpw` can check the master password
+/// against it.
+///
+/// Key IDs are always checked against the master key of the latest algorithm
+/// version. Older versions derive another master key for full names that are
+/// not ASCII, so their key IDs are dropped with a warning.
+fn user_key_id(key_id: Option<String>, algorithm: Option<u32>, warnings: &mut Vec<String>)
+    -> Option<String>
+{
+    let key_id = key_id?;
+    let latest = AlgorithmVersion::latest().to_u32();
+    if algorithm != Some(latest) {
+        warnings.push(format!("ignoring key ID, only key IDs of algorithm version {} \
+                               are supported", latest));
+        return None;
+    }
+    Some(key_id)
+}
+
+/// Read a site configuration from the flat `.mpsites` format.
+///
+/// Returns the configuration and warnings about everything that could not be
+/// imported.
+pub fn read_flat<'a>(s: &'a str) -> Result<(Config<'a>, Vec<String>), Error> {
+    let mut config = Config::new();
+    let mut sites = Vec::new();
+    let mut warnings = Vec::new();
+    let mut header_started = false;
+    let mut header_ended = false;
+    let mut format = None;
+    let mut key_id = None;
+    let mut user_algorithm = None;
+
+    for (i, line) in s.lines().enumerate() {
+        let line_number = i + 1;
+        if let Some(rest) = line.strip_prefix('#') {
+            if !header_started {
+                if rest.starts_with('#') {
+                    header_started = true;
+                }
+                continue;
+            }
+            if header_ended {
+                continue;
+            }
+            if rest.starts_with('#') {
+                header_ended = true;
+                continue;
+            }
+            let mut parts = rest.splitn(2, ':');
+            let name = parts.next().unwrap().trim();
+            //^ This unwrap is safe, because `splitn` always returns at least one part.
+            let value = parts.next().map(|v| v.trim()).unwrap_or("");
+            match name {
+                "Format" => format = Some(match value {
+                    "0" => 0,
+                    "1" => 1,
+                    _ => return Err(Error::at_line(ErrorKind::UnsupportedFormat, line_number)),
+                }),
+                "Full Name" | "User Name" if !value.is_empty() => {
+                    config.full_name = Some(value.into());
+                },
+                "Key ID" if !value.is_empty() => {
+                    key_id = Some(value.to_string());
+                },
+                "Algorithm" => user_algorithm = value.parse().ok(),
+                "Passwords" if value != "PROTECTED" => {
+                    warnings.push("file contains passwords in clear text, \
+                                   they are not imported".into());
+                },
+                _ => {},
+            }
+            continue;
+        }
+        if !header_ended {
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Err(Error::at_line(ErrorKind::InvalidHeader, line_number));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let format = format.ok_or_else(|| Error::from(ErrorKind::UnsupportedFormat))?;
+        if config.full_name.is_none() {
+            return Err(Error::from(ErrorKind::MissingFullName));
+        }
+
+        let invalid = || Error::at_line(ErrorKind::InvalidSite, line_number);
+        let mut rest = line;
+        let _last_used = next_word(&mut rest).ok_or_else(&invalid)?;
+        let _uses = next_word(&mut rest).ok_or_else(&invalid)?;
+        let parameters = next_word(&mut rest).ok_or_else(&invalid)?;
+        let mut parameters = parameters.split(':');
+        let type_number = parameters.next().unwrap();
+        //^ This unwrap is safe, because `split` always returns at least one part.
+        let algorithm = parameters.next().ok_or_else(&invalid)?;
+        let counter = if format == 0 { "1" } else { parameters.next().ok_or_else(&invalid)? };
+        rest = rest.trim_start_matches(' ');
+        let login_name = if format == 0 { "" } else { next_field(&mut rest).ok_or_else(&invalid)? };
+        let name = next_field(&mut rest).ok_or_else(&invalid)?;
+        let content = rest;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let type_number: u32 = type_number.parse()
+            .map_err(|_| Error::at_line(ErrorKind::UnknownType, line_number))?;
+        let type_ = type_from_u32(type_number)
+            .ok_or_else(|| Error::at_line(ErrorKind::UnknownType, line_number))?;
+        let algorithm = AlgorithmVersion::from_str(algorithm)
+            .ok_or_else(|| Error::at_line(ErrorKind::UnknownAlgorithm, line_number))?;
+        let counter: u32 = counter.parse()
+            .map_err(|_| Error::at_line(ErrorKind::InvalidCounter, line_number))?;
+
+        if type_ == SiteType::Stored {
+            warnings.push(format!(
+                "{}: passwords stored by the official apps cannot be imported, skipping site",
+                name));
+            continue;
+        }
+        if !content.is_empty() {
+            warnings.push(format!("{}: ignoring exported password", name));
+        }
+
+        let mut site = SiteConfig::new(name);
+        // Only store the parameters that differ from the defaults.
+        if type_ != SiteType::GeneratedLong {
+            site.type_ = Some(type_);
+        }
+        if counter != 1 {
+            site.counter = Some(counter);
+        }
+        if algorithm != AlgorithmVersion::latest() {
+            site.algorithm = Some(algorithm);
+        }
+        if !login_name.is_empty() {
+            site.login_name = Some(login_name.into());
+        }
+        sites.push(site);
+    }
+
+    if !header_ended {
+        return Err(Error::from(ErrorKind::InvalidHeader));
+    }
+    if config.full_name.is_none() {
+        return Err(Error::from(ErrorKind::MissingFullName));
+    }
+    config.key_id = user_key_id(key_id, user_algorithm, &mut warnings);
+    if !sites.is_empty() {
+        config.sites = Some(sites);
+    }
+    Ok((config, warnings))
+}
+
+/// Write a site configuration in the flat `.mpsites` format.
+///
+/// Only site passwords can be exported, other variants and stored passwords
+/// are skipped. Returns the exported file and warnings about everything that
+/// could not be exported.
+pub fn write_flat(config: &Config) -> Result<(String, Vec<String>), Error> {
+    let full_name = config.full_name.as_ref()
+        .ok_or_else(|| Error::from(ErrorKind::MissingFullName))?;
+    let date = now();
+    let mut warnings = Vec::new();
+    let mut s = String::new();
+
+    // Writing to a string cannot fail, so the results are ignored below.
+    let _ = write!(s, "\
+# Master Password site export
+#     Export of site names and stored passwords (unless device-private) encrypted with the master key.
+#
+##
+# Format: 1
+# Date: {date}
+# User Name: {full_name}
+# Full Name: {full_name}
+# Avatar: 0
+# Key ID: {key_id}
+# Version: 2.2
+# Algorithm: {algorithm}
+# Default Type: {default_type}
+# Passwords: PROTECTED
+##
+#
+#               Last     Times  Password                      Login\t                     Site\tSite
+#               used      used      type                       name\t                     name\tpassword
+",
+        date = date,
+        full_name = full_name,
+        key_id = config.key_id.as_deref().unwrap_or(""),
+        algorithm = AlgorithmVersion::latest().to_u32(),
+        default_type = TYPE_PASSWORD_DEFAULT);
+
+    for site_config in config.sites.iter().flat_map(|sites| sites.iter()) {
+        let site = Site::from_config(site_config).map_err(|e| Error {
+            message: format!("{}: {}", site_config.name, e.message),
+            kind: ErrorKind::InvalidConfig,
+        })?;
+        if site.variant != SiteVariant::Password {
+            warnings.push(format!(
+                "{}: only password variants can be exported, skipping site", site.name));
+            continue;
+        }
+        let type_ = match type_to_u32(&site.type_) {
+            Some(type_) => type_,
+            None => {
+                warnings.push(format!(
+                    "{}: custom templates cannot be exported, skipping site", site.name));
+                continue;
+            },
+        };
+        if !site.policy.is_empty() {
+            warnings.push(format!(
+                "{}: password policy cannot be exported, skipping site", site.name));
+            continue;
+        }
+        if !site.context.is_empty() {
+            warnings.push(format!("{}: context cannot be exported, skipping site", site.name));
+            continue;
+        }
+        if site.type_ == SiteType::Stored {
+            warnings.push(format!(
+                "{}: stored password cannot be exported, it has to be entered again",
+                site.name));
+        }
+        let parameters = format!("{}:{}:{}",
+            type_, site.algorithm.to_u32(), site.counter);
+        let login_name = site.login_name.as_ref().map(|s| s.as_ref()).unwrap_or("");
+        let _ = writeln!(s, "{}  {:>8}  {:>8}  {:>25}\t{:>25}\t",
+            date, 0, parameters, login_name, site.name);
+    }
+
+    Ok((s, warnings))
+}
+
+/// Type number of generated long passwords used by the official apps for passwords.
+const TYPE_PASSWORD_DEFAULT: u32 = 0x11;
+/// Type number of generated names used by the official apps for logins.
+const TYPE_LOGIN_DEFAULT: u32 = 0x1e;
+/// Type number of generated phrases used by the official apps for answers.
+const TYPE_ANSWER_DEFAULT: u32 = 0x1f;
+
+/// The JSON `.mpsites` format.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonFile {
+    export: JsonExport,
+    user: JsonUser,
+    #[serde(default)]
+    sites: BTreeMap<String, JsonSite>,
+}
+
+/// Information about the export.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonExport {
+    format: u32,
+    #[serde(default)]
+    redacted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+}
+
+/// The user the sites belong to.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonUser {
+    full_name: String,
+    #[serde(default)]
+    avatar: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_used: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_type: Option<u32>,
+}
+
+/// The parameters of a site, including its login and security questions.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonSite {
+    #[serde(rename = "type")]
+    type_: u32,
+    #[serde(default = "default_counter")]
+    counter: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login_type: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login_name: Option<String>,
+    #[serde(default)]
+    uses: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_used: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    questions: BTreeMap<String, JsonQuestion>,
+    /// Extensions of the official apps, like the URL of the site.
+    #[serde(rename = "_ext_mpw", skip_serializing_if = "Option::is_none")]
+    ext_mpw: Option<serde_json::Value>,
+}
+
+/// A security question of a site, identified by its keyword.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonQuestion {
+    #[serde(rename = "type", default = "default_answer_type")]
+    type_: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answer: Option<String>,
+}
+
+fn default_counter() -> u32 {
+    1
+}
+
+fn default_answer_type() -> u32 {
+    TYPE_ANSWER_DEFAULT
+}
+
+impl JsonSite {
+    /// Create a site with the default parameters.
+    fn new(date: &str) -> JsonSite {
+        JsonSite {
+            type_: TYPE_PASSWORD_DEFAULT,
+            counter: 1,
+            algorithm: Some(AlgorithmVersion::latest().to_u32()),
+            password: None,
+            login_type: None,
+            login_name: None,
+            uses: 0,
+            last_used: Some(date.into()),
+            questions: BTreeMap::new(),
+            ext_mpw: None,
+        }
+    }
+}
+
+/// Read a site configuration from the JSON `.mpsites` format.
+///
+/// Logins with a stored name are imported as `login_name` of the site,
+/// security questions become sites with the `answer` variant and the keyword
+/// as context. Returns the configuration and warnings about everything that
+/// could not be imported.
+pub fn read_json<'a>(s: &str) -> Result<(Config<'a>, Vec<String>), Error> {
+    let file: JsonFile = serde_json::from_str(s)?;
+    if file.export.format != 1 {
+        return Err(Error::from(ErrorKind::UnsupportedFormat));
+    }
+    if file.user.full_name.is_empty() {
+        return Err(Error::from(ErrorKind::MissingFullName));
+    }
+    let redacted = file.export.redacted;
+    let mut config = Config::new();
+    config.full_name = Some(file.user.full_name.into());
+    let mut sites = Vec::new();
+    let mut warnings = Vec::new();
+    config.key_id = user_key_id(file.user.key_id, file.user.algorithm, &mut warnings);
+
+    let invalid = |kind, name: &str| {
+        let mut e = Error::from(kind);
+        e.message = format!("{} for site {}", e.message, name);
+        e
+    };
+    for (name, json_site) in file.sites {
+        let type_ = type_from_u32(json_site.type_)
+            .ok_or_else(|| invalid(ErrorKind::UnknownType, &name))?;
+        let algorithm = match json_site.algorithm {
+            Some(n) => AlgorithmVersion::from_u32(n)
+                .ok_or_else(|| invalid(ErrorKind::UnknownAlgorithm, &name))?,
+            None => AlgorithmVersion::latest(),
+        };
+        let algorithm = if algorithm != AlgorithmVersion::latest() {
+            Some(algorithm)
+        } else {
+            None
+        };
+        let login_type = json_site.login_type.unwrap_or(TYPE_LOGIN_DEFAULT);
+        let login_type = type_from_u32(login_type)
+            .ok_or_else(|| invalid(ErrorKind::UnknownType, &name))?;
+        let login_name = match json_site.login_name {
+            Some(ref login_name) if !login_name.is_empty() => {
+                if login_type != SiteType::Stored {
+                    warnings.push(format!("{}: ignoring exported login name", name));
+                    None
+                } else if redacted {
+                    warnings.push(format!("{}: encrypted login name cannot be imported", name));
+                    None
+                } else {
+                    Some(login_name.clone())
+                }
+            },
+            _ => None,
+        };
+        let ext_mpw = match json_site.ext_mpw {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::Object(ref ext)) if ext.is_empty() => None,
+            Some(ref ext) => Some(serde_json::to_string(ext)?),
+        };
+
+        if type_ == SiteType::Stored {
+            warnings.push(format!(
+                "{}: passwords stored by the official apps cannot be imported, skipping site",
+                name));
+            if login_name.is_some() {
+                warnings.push(format!("{}: login name cannot be imported without site", name));
+            }
+        } else {
+            if json_site.password.is_some() {
+                warnings.push(format!("{}: ignoring exported password", name));
+            }
+            let mut site = SiteConfig::new(name.clone());
+            if type_ != SiteType::GeneratedLong {
+                site.type_ = Some(type_);
+            }
+            if json_site.counter != 1 {
+                site.counter = Some(json_site.counter);
+            }
+            site.algorithm = algorithm;
+            site.login_name = login_name.map(Into::into);
+            site.ext_mpw = ext_mpw.map(Into::into);
+            sites.push(site);
+        }
+
+        if login_type != SiteType::Stored && login_type != SiteType::GeneratedName {
+            let mut site = SiteConfig::new(name.clone());
+            site.type_ = Some(login_type);
+            site.variant = Some(SiteVariant::Login);
+            site.algorithm = algorithm;
+            sites.push(site);
+        }
+
+        for (keyword, question) in json_site.questions {
+            let type_ = type_from_u32(question.type_)
+                .ok_or_else(|| invalid(ErrorKind::UnknownType, &name))?;
+            if type_ == SiteType::Stored {
+                warnings.push(format!(
+                    "{}: stored answers cannot be imported, skipping question '{}'",
+                    name, keyword));
+                continue;
+            }
+            if question.answer.is_some() {
+                warnings.push(format!(
+                    "{}: ignoring exported answer to question '{}'", name, keyword));
+            }
+            let mut site = SiteConfig::new(name.clone());
+            if type_ != SiteType::GeneratedPhrase {
+                site.type_ = Some(type_);
+            }
+            site.variant = Some(SiteVariant::Answer);
+            if !keyword.is_empty() {
+                site.context = Some(keyword.into());
+            }
+            site.algorithm = algorithm;
+            sites.push(site);
+        }
+    }
+
+    if !sites.is_empty() {
+        config.sites = Some(sites);
+    }
+    Ok((config, warnings))
+}
+
+/// Write a site configuration in the JSON `.mpsites` format.
+///
+/// This is the inverse of `read_json`. Stored passwords are not exported.
+/// Returns the exported file and warnings about everything that could not be
+/// exported.
+pub fn write_json(config: &Config) -> Result<(String, Vec<String>), Error> {
+    let full_name = config.full_name.as_ref()
+        .ok_or_else(|| Error::from(ErrorKind::MissingFullName))?;
+    let date = now();
+    let mut warnings = Vec::new();
+    let mut sites: BTreeMap<String, JsonSite> = BTreeMap::new();
+    let mut has_password = Vec::new();
+
+    for site_config in config.sites.iter().flat_map(|sites| sites.iter()) {
+        let site = Site::from_config(site_config).map_err(|e| Error {
+            message: format!("{}: {}", site_config.name, e.message),
+            kind: ErrorKind::InvalidConfig,
+        })?;
+        let name = site.name.to_string();
+        let algorithm = site.algorithm.to_u32();
+        let ext_mpw = match site_config.ext_mpw {
+            Some(ref ext) if site.variant == SiteVariant::Password => {
+                match serde_json::from_str(ext) {
+                    Ok(ext) => Some(ext),
+                    Err(_) => {
+                        warnings.push(format!(
+                            "{}: ignoring invalid extensions of the official apps", name));
+                        None
+                    },
+                }
+            },
+            Some(_) => {
+                warnings.push(format!(
+                    "{}: extensions of the official apps are only exported with the password",
+                    name));
+                None
+            },
+            None => None,
+        };
+        let type_ = match type_to_u32(&site.type_) {
+            Some(type_) => type_,
+            None => {
+                warnings.push(format!(
+                    "{}: custom templates cannot be exported, skipping site", name));
+                continue;
+            },
+        };
+        if !site.policy.is_empty() {
+            warnings.push(format!(
+                "{}: password policy cannot be exported, skipping site", name));
+            continue;
+        }
+        match site.variant {
+            SiteVariant::Password => {
+                if !site.context.is_empty() {
+                    warnings.push(format!(
+                        "{}: context of password cannot be exported, skipping site", name));
+                    continue;
+                }
+                if has_password.contains(&name) {
+                    warnings.push(format!(
+                        "{}: only one password per site can be exported, skipping site", name));
+                    continue;
+                }
+                has_password.push(name.clone());
+                if site.type_ == SiteType::Stored {
+                    warnings.push(format!(
+                        "{}: stored password cannot be exported, it has to be entered again",
+                        name));
+                }
+                let json_site = sites.entry(name).or_insert_with(|| JsonSite::new(&date));
+                json_site.type_ = type_;
+                json_site.counter = site.counter;
+                json_site.algorithm = Some(algorithm);
+                json_site.ext_mpw = ext_mpw;
+                if let Some(login_name) = site.login_name {
+                    json_site.login_type = Some(TYPE_STORED_PERSONAL);
+                    json_site.login_name = Some(login_name.into_owned());
+                }
+            },
+            SiteVariant::Login => {
+                if site.type_ == SiteType::Stored || site.counter != 1 || !site.context.is_empty() {
+                    warnings.push(format!(
+                        "{}: only generated logins without counter or context can be exported, \
+                         skipping login", name));
+                    continue;
+                }
+                let json_site = sites.entry(name).or_insert_with(|| JsonSite::new(&date));
+                if json_site.login_name.is_none() {
+                    json_site.login_type = Some(type_);
+                }
+            },
+            SiteVariant::Answer => {
+                if site.type_ == SiteType::Stored || site.counter != 1 {
+                    warnings.push(format!(
+                        "{}: only generated answers without counter can be exported, \
+                         skipping answer", name));
+                    continue;
+                }
+                let json_site = sites.entry(name).or_insert_with(|| JsonSite::new(&date));
+                json_site.questions.insert(site.context.into_owned(), JsonQuestion {
+                    type_,
+                    answer: None,
+                });
+            },
+        }
+    }
+
+    let file = JsonFile {
+        export: JsonExport {
+            format: 1,
+            // Only public parameters and login names are exported.
+            redacted: false,
+            date: Some(date.clone()),
+        },
+        user: JsonUser {
+            full_name: full_name.to_string(),
+            avatar: 0,
+            last_used: Some(date),
+            key_id: config.key_id.clone(),
+            algorithm: Some(AlgorithmVersion::latest().to_u32()),
+            default_type: Some(TYPE_PASSWORD_DEFAULT),
+        },
+        sites,
+    };
+    let s = serde_json::to_string_pretty(&file)?;
+    Ok((s, warnings))
+}
+
+/// Read a site configuration from a `.mpsites` file, detecting its format.
+pub fn read<'a>(s: &'a str) -> Result<(Config<'a>, Vec<String>), Error> {
+    if s.trim_start().starts_with('{') {
+        read_json(s)
+    } else {
+        read_flat(s)
+    }
+}
+
+/// Merge imported sites into a configuration.
+///
+/// Sites that are already present are skipped. Returns warnings about the
+/// skipped sites.
+pub fn merge_import<'a>(config: &mut Config<'a>, imported: Config<'a>)
+    -> Result<Vec<String>, ::config::Error>
+{
+    if let (Some(name), Some(imported_name)) =
+        (config.full_name.as_ref(), imported.full_name.as_ref())
+    {
+        if name != imported_name {
+            return Err(::config::Error::from(::config::ErrorKind::ConflictingFullName));
+        }
+    }
+    if let (Some(key_id), Some(imported_key_id)) =
+        (config.key_id.as_ref(), imported.key_id.as_ref())
+    {
+        if key_id != imported_key_id {
+            return Err(::config::Error::from(::config::ErrorKind::ConflictingKeyId));
+        }
+    }
+    let mut warnings = Vec::new();
+    let mut new_sites = Vec::new();
+    for site in imported.sites.unwrap_or_default() {
+        let present = config.sites.iter().flat_map(|sites| sites.iter()).any(|s|
+            s.name == site.name && s.variant == site.variant && s.context == site.context);
+        if present {
+            warnings.push(format!("{}: already present in config, skipping site", site.name));
+        } else {
+            new_sites.push(site);
+        }
+    }
+    let mut new_config = Config::new();
+    new_config.full_name = imported.full_name;
+    new_config.key_id = imported.key_id;
+    if !new_sites.is_empty() {
+        new_config.sites = Some(new_sites);
+    }
+    config.merge(new_config);
+    Ok(warnings)
+}
+
+#[cfg(test)]
+static FLAT_EXAMPLE: &str = "\
+# Master Password site export
+#     Export of site names and stored passwords (unless device-private) encrypted with the master key.
+#
+##
+# Format: 1
+# Date: 2017-06-15T12:01:55Z
+# User Name: Robert Lee Mitchell
+# Full Name: Robert Lee Mitchell
+# Avatar: 0
+# Key ID: 98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302
+# Version: 2.2
+# Algorithm: 3
+# Default Type: 17
+# Passwords: PROTECTED
+##
+#
+#               Last     Times  Password                      Login\t                     Site\tSite
+#               used      used      type                       name\t                     name\tpassword
+2017-06-15T12:01:55Z         3    17:3:1                           \t    masterpasswordapp.com\t
+2017-06-15T12:02:11Z         1    16:2:4                           \t               github.com\t
+2017-06-15T12:03:42Z         0  1056:3:1                           \t            wikipedia.org\tc2VjcmV0
+2017-06-15T12:04:05Z         0    21:0:1                     robert\t                 bank.com\t
+";
+
+#[test]
+fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_date(1497528115), "2017-06-15T12:01:55Z");
+    assert_eq!(format_date(951825600), "2000-02-29T12:00:00Z");
+}
+
+#[test]
+fn test_read_flat() {
+    let (config, warnings) = read_flat(FLAT_EXAMPLE).unwrap();
+    assert_eq!(config.full_name, Some("Robert Lee Mitchell".into()));
+    assert_eq!(config.key_id, Some("98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302".into()));
+
+    let mut github = SiteConfig::new("github.com");
+    github.type_ = Some(SiteType::GeneratedMaximum);
+    github.counter = Some(4);
+    github.algorithm = Some(AlgorithmVersion::V2);
+    let mut bank = SiteConfig::new("bank.com");
+    bank.type_ = Some(SiteType::GeneratedPIN);
+    bank.algorithm = Some(AlgorithmVersion::V0);
+    bank.login_name = Some("robert".into());
+    assert_eq!(config.sites, Some(vec![
+        SiteConfig::new("masterpasswordapp.com"),
+        github,
+        bank,
+    ]));
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("wikipedia.org:"));
+}
+
+#[test]
+fn test_read_flat_format_0() {
+    let s = "\
+##
+# Format: 0
+# Full Name: John Doe
+##
+2014-01-01T00:00:00Z 2 17:1 github.com\t
+";
+    let (config, warnings) = read_flat(s).unwrap();
+    let mut github = SiteConfig::new("github.com");
+    github.algorithm = Some(AlgorithmVersion::V1);
+    assert_eq!(config.sites, Some(vec![github]));
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_read_flat_key_id() {
+    use algorithm::{master_key_for_user, key_id};
+
+    // Before version 3, the master key of a full name that is not ASCII
+    // differs, so its key ID would never match.
+    let full_name = "Max Müller";
+    let id = |version| key_id(&master_key_for_user(
+        full_name.as_bytes(), b"passwort", version).unwrap().borrow());
+    let (old_id, latest_id) = (id(AlgorithmVersion::V2), id(AlgorithmVersion::V3));
+    assert_ne!(old_id, latest_id);
+
+    let file = |algorithm, key_id| format!(
+        "##\n# Format: 1\n# Full Name: {}\n# Key ID: {}\n# Algorithm: {}\n##\n",
+        full_name, key_id, algorithm);
+    let old = file(2, &old_id);
+    let (config, warnings) = read_flat(&old).unwrap();
+    assert_eq!(config.key_id, None);
+    assert_eq!(warnings, vec!["ignoring key ID, only key IDs of algorithm version 3 are \
+                               supported".to_string()]);
+    let latest = file(3, &latest_id);
+    let (config, warnings) = read_flat(&latest).unwrap();
+    assert_eq!(config.key_id, Some(latest_id));
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_read_flat_errors() {
+    assert!(read_flat("").is_err());
+    assert!(read_flat("##\n# Format: 1\n##\n").is_err());
+    assert!(read_flat("##\n# Format: 2\n# Full Name: John Doe\n##\n").is_err());
+    assert!(read_flat(
+        "##\n# Format: 1\n# Full Name: John Doe\n##\nx 0 17:9:1 \tgithub.com\t\n").is_err());
+    assert!(read_flat(
+        "##\n# Format: 1\n# Full Name: John Doe\n##\nx 0 99:3:1 \tgithub.com\t\n").is_err());
+}
+
+#[test]
+fn test_flat_roundtrip() {
+    let (config, _) = read_flat(FLAT_EXAMPLE).unwrap();
+    let (s, warnings) = write_flat(&config).unwrap();
+    assert!(warnings.is_empty());
+    let (roundtrip, _) = read_flat(&s).unwrap();
+    assert_eq!(config, roundtrip);
+}
+
+#[test]
+fn test_write_flat_stored() {
+    let mut config = Config::new();
+    config.full_name = Some("John Doe".into());
+    let mut wikipedia = SiteConfig::new("wikipedia.org");
+    wikipedia.encrypted = Some("c2VjcmV0".into());
+    let mut answer = SiteConfig::new("bank.com");
+    answer.variant = Some(SiteVariant::Answer);
+    config.sites = Some(vec![wikipedia, answer]);
+    let (s, warnings) = write_flat(&config).unwrap();
+    assert_eq!(warnings.len(), 2);
+    assert!(s.ends_with(&format!("{:>25}\t{:>25}\t\n", "", "wikipedia.org")));
+    assert!(s.contains("  1056:3:1  "));
+    assert!(!s.contains("bank.com"));
+}
+
+#[test]
+fn test_merge_import() {
+    let mut config = Config::new();
+    config.full_name = Some("Robert Lee Mitchell".into());
+    config.sites = Some(vec![SiteConfig::new("github.com")]);
+    let (imported, _) = read_flat(FLAT_EXAMPLE).unwrap();
+    let warnings = merge_import(&mut config, imported).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(config.sites.as_ref().unwrap().len(), 3);
+    assert_eq!(config.key_id, Some("98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302".into()));
+
+    let mut other = Config::new();
+    other.full_name = Some("John Doe".into());
+    let (imported, _) = read_flat(FLAT_EXAMPLE).unwrap();
+    assert!(merge_import(&mut other, imported).is_err());
+
+    // The sites belong to a different master password.
+    let mut other = Config::new();
+    other.key_id = Some("0123".into());
+    let (imported, _) = read_flat(FLAT_EXAMPLE).unwrap();
+    assert!(merge_import(&mut other, imported).is_err());
+}
+
+#[cfg(test)]
+static JSON_EXAMPLE: &str = r#"{
+  "export": {
+    "format": 1,
+    "redacted": false,
+    "date": "2017-06-15T12:01:55Z"
+  },
+  "user": {
+    "avatar": 0,
+    "full_name": "Robert Lee Mitchell",
+    "last_used": "2017-06-15T12:01:55Z",
+    "key_id": "98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302",
+    "algorithm": 3,
+    "default_type": 17
+  },
+  "sites": {
+    "bank.com": {
+      "type": 21,
+      "counter": 1,
+      "algorithm": 0,
+      "login_type": 1056,
+      "login_name": "robert",
+      "uses": 0,
+      "last_used": "2017-06-15T12:04:05Z",
+      "questions": {
+        "": {
+          "type": 31
+        },
+        "mother": {
+          "type": 31,
+          "answer": "zemta kotlifu"
+        }
+      }
+    },
+    "github.com": {
+      "type": 16,
+      "counter": 4,
+      "algorithm": 3,
+      "login_type": 17,
+      "uses": 1,
+      "last_used": "2017-06-15T12:02:11Z",
+      "_ext_mpw": {
+        "url": "https://github.com"
+      }
+    },
+    "wikipedia.org": {
+      "type": 1056,
+      "counter": 1,
+      "algorithm": 3,
+      "password": "secret",
+      "uses": 0,
+      "last_used": "2017-06-15T12:03:42Z"
+    }
+  }
+}"#;
+
+#[test]
+fn test_read_json() {
+    let (config, warnings) = read(JSON_EXAMPLE).unwrap();
+    assert_eq!(config.full_name, Some("Robert Lee Mitchell".into()));
+    assert_eq!(config.key_id, Some("98EEF4D1DF46D849574A82A03C3177056B15DFFCA29BB3899DE4628453675302".into()));
+
+    let mut bank = SiteConfig::new("bank.com");
+    bank.type_ = Some(SiteType::GeneratedPIN);
+    bank.algorithm = Some(AlgorithmVersion::V0);
+    bank.login_name = Some("robert".into());
+    let mut bank_answer = SiteConfig::new("bank.com");
+    bank_answer.variant = Some(SiteVariant::Answer);
+    bank_answer.algorithm = Some(AlgorithmVersion::V0);
+    let mut bank_mother = bank_answer.clone();
+    bank_mother.context = Some("mother".into());
+    let mut github = SiteConfig::new("github.com");
+    github.type_ = Some(SiteType::GeneratedMaximum);
+    github.counter = Some(4);
+    github.ext_mpw = Some(r#"{"url":"https://github.com"}"#.into());
+    let mut github_login = SiteConfig::new("github.com");
+    github_login.type_ = Some(SiteType::GeneratedLong);
+    github_login.variant = Some(SiteVariant::Login);
+    assert_eq!(config.sites, Some(vec![
+        bank, bank_answer, bank_mother, github, github_login,
+    ]));
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].starts_with("bank.com:"));
+    assert!(warnings[1].starts_with("wikipedia.org:"));
+}
+
+#[test]
+fn test_read_json_redacted() {
+    let s = JSON_EXAMPLE.replace(r#""redacted": false"#, r#""redacted": true"#);
+    let (config, warnings) = read_json(&s).unwrap();
+    assert_eq!(config.sites.as_ref().unwrap()[0].login_name, None);
+    assert!(warnings.iter().any(|w| w == "bank.com: encrypted login name cannot be imported"));
+}
+
+#[test]
+fn test_read_json_key_id() {
+    let s = JSON_EXAMPLE
+        .replace("Robert Lee Mitchell", "Robert Lee Müller")
+        .replace(r#""algorithm": 3,
+    "default_type""#, r#""algorithm": 2,
+    "default_type""#);
+    let (config, warnings) = read_json(&s).unwrap();
+    assert_eq!(config.full_name, Some("Robert Lee Müller".into()));
+    assert_eq!(config.key_id, None);
+    assert!(warnings[0].starts_with("ignoring key ID"));
+}
+
+#[test]
+fn test_read_json_errors() {
+    assert!(read_json("{}").is_err());
+    let s = JSON_EXAMPLE.replace(r#""format": 1"#, r#""format": 3"#);
+    assert!(read_json(&s).is_err());
+    let s = JSON_EXAMPLE.replace(r#""type": 16"#, r#""type": 99"#);
+    assert!(read_json(&s).is_err());
+}
+
+#[test]
+fn test_json_roundtrip() {
+    let (config, _) = read_json(JSON_EXAMPLE).unwrap();
+    let (s, warnings) = write_json(&config).unwrap();
+    assert!(warnings.is_empty());
+    assert!(s.contains(r#""_ext_mpw": {
+        "url": "https://github.com"
+      }"#));
+    let (roundtrip, warnings) = read_json(&s).unwrap();
+    assert!(warnings.is_empty());
+    assert_eq!(config, roundtrip);
+}
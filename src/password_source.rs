@@ -0,0 +1,377 @@
+//! Sources of the master password besides the terminal, so that `mpw` can be
+//! used in scripts.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use rpassword::read_password;
+
+use mpw::clear_on_drop::{ClearOnDrop, clear};
+
+/// Maximal length of a password read from a source, in bytes.
+///
+/// The buffer is allocated in advance, so that the password is never copied
+/// by a reallocation.
+const MAX_PASSWORD_LEN: usize = 1024;
+
+/// Password source kind of error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An `std::io::Error` occured.
+    Io,
+    /// A password file can be accessed by other users.
+    InsecurePermissions,
+    /// The password is longer than `MAX_PASSWORD_LEN` or not valid UTF-8.
+    InvalidPassword,
+    /// The environment variable is not set.
+    MissingVariable,
+    /// An external command failed.
+    Command,
+    /// The pinentry program failed or the user cancelled it.
+    Pinentry,
+}
+
+/// Password source error.
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    // Only the message is reported by the binary.
+    #[allow(dead_code)]
+    pub kind: ErrorKind,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error {
+            message: format!("could not read password: {}", e),
+            kind: ErrorKind::Io,
+        }
+    }
+}
+
+/// Where to read a password from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordSource {
+    /// Ask interactively on the terminal.
+    Terminal,
+    /// Read the first line from an open file descriptor.
+    Fd(RawFd),
+    /// Read the first line of a file that only the user can access.
+    File(PathBuf),
+    /// Read an environment variable, which is removed afterwards.
+    Env(String),
+    /// Read the first line printed by a shell command, like `pass show mpw`.
+    Command(String),
+    /// Ask via a pinentry program using the Assuan protocol.
+    Pinentry(String),
+}
+
+/// Read the first line from a reader, without the line terminator.
+///
+/// The reader is not buffered, so that nothing after the line is consumed and
+/// no copies of the password are left behind.
+#[allow(clippy::unbuffered_bytes)]
+fn read_line<R: Read>(reader: R) -> Result<ClearOnDrop<String>, Error> {
+    let mut buffer = ClearOnDrop::new(Vec::with_capacity(MAX_PASSWORD_LEN));
+    for byte in reader.bytes() {
+        let byte = byte?;
+        if byte == b'\n' {
+            break;
+        }
+        if buffer.len() == MAX_PASSWORD_LEN {
+            return Err(Error {
+                message: format!("password longer than {} bytes", MAX_PASSWORD_LEN),
+                kind: ErrorKind::InvalidPassword,
+            });
+        }
+        buffer.push(byte);
+    }
+    if buffer.last() == Some(&b'\r') {
+        buffer.pop();
+    }
+    to_string(&buffer)
+}
+
+/// Copy a password into a string.
+fn to_string(buffer: &[u8]) -> Result<ClearOnDrop<String>, Error> {
+    let s = ::std::str::from_utf8(buffer).map_err(|_| Error {
+        message: "password is not valid UTF-8".into(),
+        kind: ErrorKind::InvalidPassword,
+    })?;
+    let mut password = ClearOnDrop::new(String::with_capacity(s.len()));
+    password.push_str(s);
+    Ok(password)
+}
+
+/// Escape a string for the Assuan protocol.
+fn assuan_escape(s: &str) -> String {
+    s.replace('%', "%25").replace('\n', "%0A").replace('\r', "%0D")
+}
+
+/// Decode the percent escapes of Assuan data into the given buffer.
+fn assuan_unescape(data: &[u8], buffer: &mut Vec<u8>) -> Result<(), Error> {
+    let invalid = || Error {
+        message: "invalid response from pinentry".into(),
+        kind: ErrorKind::Pinentry,
+    };
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'%' {
+            let hex = data.get(i + 1..i + 3).ok_or_else(invalid)?;
+            let hex = ::std::str::from_utf8(hex).map_err(|_| invalid())?;
+            buffer.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            buffer.push(data[i]);
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Read a line of an Assuan response into `line`, without the newline.
+///
+/// The previous line is overwritten with zeros first, so that no data is
+/// left behind in the buffer. The output is not buffered either, so that no
+/// copies of the data are left behind.
+#[allow(clippy::unbuffered_bytes)]
+fn read_assuan_line<R: Read>(output: &mut R, line: &mut Vec<u8>) -> Result<(), Error> {
+    clear(line);
+    line.clear();
+    for byte in output.bytes() {
+        let byte = byte?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() == line.capacity() {
+            return Err(Error {
+                message: "response of pinentry too long".into(),
+                kind: ErrorKind::Pinentry,
+            });
+        }
+        line.push(byte);
+    }
+    Ok(())
+}
+
+/// Send an Assuan command and wait for its completion.
+///
+/// Data lines of the response are decoded and appended to `data`.
+fn assuan_command<W: Write, R: Read>(input: &mut W, output: &mut R, command: Option<&str>,
+        data: &mut Vec<u8>)
+    -> Result<(), Error>
+{
+    let failed = |message: String| Error { message, kind: ErrorKind::Pinentry };
+    if let Some(command) = command {
+        writeln!(input, "{}", command)?;
+        input.flush()?;
+    }
+    let mut line = ClearOnDrop::new(Vec::with_capacity(3 * MAX_PASSWORD_LEN + 2));
+    loop {
+        read_assuan_line(output, &mut line)?;
+        if line.is_empty() {
+            return Err(failed("pinentry exited unexpectedly".into()));
+        }
+        if line.starts_with(b"OK") {
+            return Ok(());
+        } else if line.starts_with(b"ERR") {
+            let message = String::from_utf8_lossy(&line[3..]).trim().to_string();
+            return Err(failed(format!("pinentry failed: {}", message)));
+        } else if line.starts_with(b"D ") {
+            if data.len() + line.len() - 2 > MAX_PASSWORD_LEN {
+                return Err(failed(format!("password longer than {} bytes", MAX_PASSWORD_LEN)));
+            }
+            assuan_unescape(&line[2..], data)?;
+        }
+        // Status lines and comments are ignored.
+    }
+}
+
+/// Ask for a password via a pinentry program.
+fn pinentry(program: &str, description: &str, prompt: &str) -> Result<ClearOnDrop<String>, Error> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error {
+            message: format!("could not run {}: {}", program, e),
+            kind: ErrorKind::Pinentry,
+        })?;
+    let mut input = child.stdin.take().unwrap();
+    let mut output = child.stdout.take().unwrap();
+    //^ These unwraps are safe, because both were piped above.
+    let mut password = ClearOnDrop::new(Vec::with_capacity(MAX_PASSWORD_LEN));
+    let result = assuan_command(&mut input, &mut output, None, &mut password)
+        .and_then(|_| assuan_command(&mut input, &mut output,
+            Some(&format!("SETDESC {}", assuan_escape(description))), &mut password))
+        .and_then(|_| assuan_command(&mut input, &mut output,
+            Some(&format!("SETPROMPT {}", assuan_escape(prompt))), &mut password))
+        .and_then(|_| assuan_command(&mut input, &mut output, Some("GETPIN"), &mut password))
+        .and_then(|_| to_string(&password));
+    let _ = writeln!(input, "BYE");
+    drop(input);
+    let _ = child.wait();
+    result
+}
+
+impl PasswordSource {
+    /// Whether the user is asked, so that reading again can be retried.
+    pub fn is_interactive(&self) -> bool {
+        matches!(*self, PasswordSource::Terminal | PasswordSource::Pinentry(_))
+    }
+
+    /// Read a password from the source.
+    ///
+    /// The prompt is only used for interactive sources.
+    pub fn read(&self, prompt: &str) -> Result<ClearOnDrop<String>, Error> {
+        match *self {
+            PasswordSource::Terminal => {
+                eprint!("{}", prompt);
+                io::stderr().flush()?;
+                Ok(ClearOnDrop::new(read_password()?))
+            },
+            PasswordSource::Fd(fd) => {
+                // The file descriptor is borrowed, so it must not be closed.
+                let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+                read_line(&*file)
+            },
+            PasswordSource::File(ref path) => {
+                let file = File::open(path).map_err(|e| Error {
+                    message: format!("could not open {}: {}", path.display(), e),
+                    kind: ErrorKind::Io,
+                })?;
+                let mode = file.metadata()?.permissions().mode();
+                if mode & 0o077 != 0 {
+                    return Err(Error {
+                        message: format!(
+                            "refusing to read password from {}, because it is accessible by \
+                             other users (mode {:o}, should be 600)",
+                            path.display(), mode & 0o777),
+                        kind: ErrorKind::InsecurePermissions,
+                    });
+                }
+                read_line(&file)
+            },
+            PasswordSource::Env(ref name) => {
+                let password = env::var(name).map_err(|_| Error {
+                    message: format!("environment variable {} is not set or not valid UTF-8",
+                                     name),
+                    kind: ErrorKind::MissingVariable,
+                })?;
+                // Don't pass the password on to child processes.
+                env::remove_var(name);
+                Ok(ClearOnDrop::new(password))
+            },
+            PasswordSource::Command(ref command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| Error {
+                        message: format!("could not run '{}': {}", command, e),
+                        kind: ErrorKind::Command,
+                    })?;
+                let password = read_line(child.stdout.take().unwrap());
+                //^ This unwrap is safe, because stdout was piped above.
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(Error {
+                        message: format!("'{}' failed with {}", command, status),
+                        kind: ErrorKind::Command,
+                    });
+                }
+                password
+            },
+            PasswordSource::Pinentry(ref program) => {
+                let prompt = prompt.trim().trim_end_matches(':');
+                pinentry(program, prompt, "Password:")
+            },
+        }
+    }
+}
+
+#[test]
+fn test_read_line() {
+    assert_eq!(*read_line(&b"secret\nsecond line"[..]).unwrap(), "secret");
+    assert_eq!(*read_line(&b"secret\r\n"[..]).unwrap(), "secret");
+    assert_eq!(*read_line(&b"no newline"[..]).unwrap(), "no newline");
+    assert_eq!(*read_line("passwört\n".as_bytes()).unwrap(), "passwört");
+    let long = vec![b'x'; MAX_PASSWORD_LEN + 1];
+    assert_eq!(read_line(&long[..]).unwrap_err().kind, ErrorKind::InvalidPassword);
+    assert_eq!(read_line(&b"\xff\n"[..]).unwrap_err().kind, ErrorKind::InvalidPassword);
+}
+
+#[test]
+fn test_read_assuan_line() {
+    use std::slice;
+
+    let mut output = &b"D secret\nOK\n"[..];
+    let mut line = Vec::with_capacity(16);
+    read_assuan_line(&mut output, &mut line).unwrap();
+    assert_eq!(line, b"D secret");
+    read_assuan_line(&mut output, &mut line).unwrap();
+    assert_eq!(line, b"OK");
+    // The data line is not left behind the response.
+    let buffer = unsafe { slice::from_raw_parts(line.as_ptr(), 8) };
+    assert_eq!(buffer, b"OK\0\0\0\0\0\0");
+}
+
+#[test]
+fn test_sources() {
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("mpw-test-password-source-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("password");
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path).unwrap()
+        .write_all(b"secret\n").unwrap();
+    let source = PasswordSource::File(path.clone());
+    assert_eq!(*source.read("").unwrap(), "secret");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    assert_eq!(source.read("").unwrap_err().kind, ErrorKind::InsecurePermissions);
+
+    let file = File::open(&path).unwrap();
+    let source = PasswordSource::Fd(file.as_raw_fd());
+    assert_eq!(*source.read("").unwrap(), "secret");
+    // The file descriptor is still open.
+    assert_eq!(source.read("").unwrap().len(), 0);
+    drop(file);
+
+    env::set_var("MPW_TEST_PASSWORD", "secret");
+    let source = PasswordSource::Env("MPW_TEST_PASSWORD".into());
+    assert_eq!(*source.read("").unwrap(), "secret");
+    assert!(env::var_os("MPW_TEST_PASSWORD").is_none());
+    assert_eq!(source.read("").unwrap_err().kind, ErrorKind::MissingVariable);
+
+    let source = PasswordSource::Command("printf 'secret\\nlogin: john\\n'".into());
+    assert_eq!(*source.read("").unwrap(), "secret");
+    let source = PasswordSource::Command("exit 1".into());
+    assert_eq!(source.read("").unwrap_err().kind, ErrorKind::Command);
+
+    let script = dir.join("pinentry");
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o700).open(&script).unwrap()
+        .write_all(b"#!/bin/sh\n\
+                     echo 'OK Pleased to meet you'\n\
+                     while read -r command args; do\n\
+                         case \"$command\" in\n\
+                             GETPIN) echo 'S PASSWORD_FROM_CACHE'; echo 'D 100%25 secret'; echo OK;;\n\
+                             BYE) echo OK; exit 0;;\n\
+                             *) echo OK;;\n\
+                         esac\n\
+                     done\n").unwrap();
+    let source = PasswordSource::Pinentry(script.to_str().unwrap().into());
+    assert_eq!(*source.read("Please enter the master password: ").unwrap(), "100% secret");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,239 @@
+//! Find sites by partial names, glob patterns or fuzzy patterns.
+//!
+//! Patterns are matched against the names, aliases and tags of sites,
+//! ignoring case. Fuzzy matching works like fzf: the characters of the
+//! pattern have to appear in order, and matches are scored higher if they are
+//! consecutive or start at word boundaries.
+
+use std::cmp;
+
+use config::SiteConfig;
+
+/// Score of a matched character.
+const SCORE_MATCH: i64 = 16;
+/// Bonus for a character matched at the start of a word.
+const BONUS_BOUNDARY: i64 = 8;
+/// Bonus for a character matched right after the previous one.
+const BONUS_CONSECUTIVE: i64 = 8;
+/// Bonus for matching the whole text.
+const BONUS_EXACT: i64 = 1000;
+/// Penalty for the first character skipped between two matched ones.
+const PENALTY_GAP_START: i64 = 3;
+/// Penalty for every further character skipped.
+const PENALTY_GAP_EXTENSION: i64 = 1;
+
+/// How a pattern is matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Glob matching if the pattern contains `*` or `?`, fuzzy matching
+    /// otherwise.
+    Auto,
+    /// The pattern has to be part of the text.
+    Substring,
+    /// The pattern has to match the whole text, where `*` matches any text
+    /// and `?` any character.
+    Glob,
+    /// The characters of the pattern have to appear in the text in order.
+    Fuzzy,
+}
+
+impl Mode {
+    /// Try to construct a Mode from a string.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Mode> {
+        match s {
+            "auto" => Some(Mode::Auto),
+            "substring" => Some(Mode::Substring),
+            "glob" => Some(Mode::Glob),
+            "fuzzy" => Some(Mode::Fuzzy),
+            _ => None,
+        }
+    }
+
+    /// Resolve `Auto` for the given pattern.
+    fn for_pattern(self, pattern: &str) -> Mode {
+        match self {
+            Mode::Auto if pattern.contains(['*', '?']) => Mode::Glob,
+            Mode::Auto => Mode::Fuzzy,
+            mode => mode,
+        }
+    }
+}
+
+/// A site matching a pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'c, 'a: 'c> {
+    pub site: &'c SiteConfig<'a>,
+    /// How well the site matches, higher is better.
+    pub score: i64,
+}
+
+/// Whether a character starts a word.
+fn is_boundary(text: &[char], i: usize) -> bool {
+    i == 0 || !text[i - 1].is_alphanumeric()
+}
+
+/// Lower-case a string into characters.
+fn lower_chars(s: &str) -> Vec<char> {
+    s.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// Score how well the pattern matches the text as a subsequence, ignoring
+/// case.
+///
+/// Returns None if the characters of the pattern do not appear in the text
+/// in order.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<i64> {
+    let pattern = lower_chars(pattern);
+    let text = lower_chars(text);
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    // The best score of the pattern so far if its last character is matched
+    // at the given position of the text.
+    let mut previous: Vec<Option<i64>> = vec![None; text.len()];
+    for (i, &p) in pattern.iter().enumerate() {
+        let mut current = vec![None; text.len()];
+        // The best score of the previous row with a gap up to the current
+        // position.
+        let mut gap: Option<i64> = None;
+        for j in 0..text.len() {
+            if j >= 2 {
+                let extended = gap.map(|s| s - PENALTY_GAP_EXTENSION);
+                let started = previous[j - 2].map(|s| s - PENALTY_GAP_START);
+                gap = cmp::max(extended, started);
+            }
+            if text[j] != p {
+                continue;
+            }
+            let bonus = SCORE_MATCH + if is_boundary(&text, j) { BONUS_BOUNDARY } else { 0 };
+            current[j] = if i == 0 {
+                Some(bonus)
+            } else {
+                let consecutive = if j >= 1 {
+                    previous[j - 1].map(|s| s + BONUS_CONSECUTIVE)
+                } else {
+                    None
+                };
+                cmp::max(consecutive, gap).map(|s| s + bonus)
+            };
+        }
+        previous = current;
+    }
+    let score = previous.into_iter().max().and_then(|s| s);
+    score.map(|s| if pattern == text { s + BONUS_EXACT } else { s })
+}
+
+/// Whether the glob pattern matches the whole text, ignoring case.
+///
+/// `*` matches any text and `?` any single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = lower_chars(pattern);
+    let text = lower_chars(text);
+    let (mut p, mut t) = (0, 0);
+    // Where to continue if the text after the last `*` does not match.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the `*` match one more character.
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Score how well the pattern matches the text.
+fn score(pattern: &str, text: &str, mode: Mode) -> Option<i64> {
+    match mode {
+        Mode::Glob => if glob_match(pattern, text) { Some(0) } else { None },
+        Mode::Substring => if text.to_lowercase().contains(&pattern.to_lowercase()) {
+            fuzzy_score(pattern, text)
+        } else {
+            None
+        },
+        _ => fuzzy_score(pattern, text),
+    }
+}
+
+/// Find the sites whose name, aliases or tags match the pattern, the best
+/// matches first.
+///
+/// Sites that match equally well keep their order.
+pub fn search<'c, 'a>(sites: &'c [SiteConfig<'a>], pattern: &str, mode: Mode)
+    -> Vec<Match<'c, 'a>>
+{
+    let mode = mode.for_pattern(pattern);
+    let mut matches: Vec<Match> = sites.iter().filter_map(|site| {
+        let names = Some(&site.name).into_iter()
+            .chain(site.aliases.iter().flat_map(|aliases| aliases.iter()))
+            .chain(site.tags.iter().flat_map(|tags| tags.iter()));
+        names.filter_map(|name| score(pattern, name, mode)).max()
+            .map(|score| Match { site, score })
+    }).collect();
+    matches.sort_by_key(|m| cmp::Reverse(m.score));
+    matches
+}
+
+#[test]
+fn test_fuzzy_score() {
+    assert_eq!(fuzzy_score("", "github.com"), Some(0));
+    assert_eq!(fuzzy_score("gh", "github.com"), Some(2 * SCORE_MATCH + BONUS_BOUNDARY
+                                                      - PENALTY_GAP_START - PENALTY_GAP_EXTENSION));
+    assert_eq!(fuzzy_score("hg", "github.com"), None);
+    assert_eq!(fuzzy_score("GitHub", "github.com"), fuzzy_score("github", "GITHUB.COM"));
+    assert!(fuzzy_score("github.com", "github.com").unwrap() > BONUS_EXACT);
+
+    // Consecutive characters and word boundaries are preferred.
+    let score = |text| fuzzy_score("git", text).unwrap();
+    // The text after the match does not matter.
+    assert_eq!(score("github.com"), score("gitlab-internal.example.org"));
+    assert!(score("git.example.org") > score("digital.com"));
+    assert!(score("my.github.com") > score("mygithub.com"));
+    assert!(score("gitlab.com") > score("g-i-t.com"));
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.com", "github.com"));
+    assert!(glob_match("*.COM", "github.com"));
+    assert!(!glob_match("*.com", "wikipedia.org"));
+    assert!(glob_match("git*", "github.com"));
+    assert!(glob_match("g?thub.*", "github.com"));
+    assert!(!glob_match("g?thub", "github.com"));
+    assert!(glob_match("*i*i*", "wikipedia.org"));
+    assert!(glob_match("*", ""));
+    assert!(!glob_match("?", ""));
+    assert!(glob_match("**.org", "wikipedia.org"));
+}
+
+#[test]
+fn test_search() {
+    let mut github = SiteConfig::new("github.com");
+    github.aliases = Some(vec!["gh".into()]);
+    let mut gitlab = SiteConfig::new("gitlab.com");
+    gitlab.tags = Some(vec!["work".into()]);
+    let wikipedia = SiteConfig::new("wikipedia.org");
+    let sites = vec![wikipedia, gitlab, github];
+    let names = |pattern, mode| search(&sites, pattern, mode).iter()
+        .map(|m| m.site.name.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(names("git", Mode::Auto), ["gitlab.com", "github.com"]);
+    assert_eq!(names("ghub", Mode::Fuzzy), ["github.com"]);
+    assert_eq!(names("ghub", Mode::Substring), Vec::<String>::new());
+    assert_eq!(names("gh", Mode::Auto), ["github.com"]);
+    assert_eq!(names("work", Mode::Substring), ["gitlab.com"]);
+    assert_eq!(names("*.com", Mode::Auto), ["gitlab.com", "github.com"]);
+    assert_eq!(names("wiki*", Mode::Auto), ["wikipedia.org"]);
+    assert_eq!(names("github.com", Mode::Auto)[0], "github.com");
+    assert_eq!(names("", Mode::Auto).len(), 3);
+}
@@ -0,0 +1,772 @@
+//! Memory for secrets, protected by the operating system.
+//!
+//! Secrets live in pages mapped with `mmap` and surrounded by guard pages
+//! that cannot be accessed, so that overflowing reads and writes crash
+//! instead of leaking or corrupting the secret. Random canaries next to the
+//! secret detect writes out of its bounds. The pages are locked into memory,
+//! excluded from core dumps and cannot be accessed at all while no secret in
+//! them is borrowed.
+//!
+//! Small secrets share the pages of an arena that is locked once, so that
+//! the default limit of locked memory (`RLIMIT_MEMLOCK`, usually 64 KiB)
+//! suffices. Larger secrets and those not fitting into the arena get pages
+//! of their own. Failing to lock memory is handled according to the
+//! `MlockPolicy`, failing to map or protect it exits the process. Forked
+//! children have to lock the memory again via `relock_after_fork`. Small
+//! containers of `ClearOnDrop` are pooled in a second arena, which stays
+//! accessible.
+//!
+//! Master keys, site keys and passwords are derived, generated and
+//! decrypted directly in this memory instead of being copied here at the
+//! end. `ClearOnDrop` is only left for secrets passing through code that
+//! does not know about this module, like the master password as read from
+//! the terminal and the messages of the agent.
+
+extern crate libc;
+extern crate ring;
+
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::process;
+use std::ptr;
+use std::slice;
+use std::str;
+use std::sync::{Mutex, MutexGuard};
+
+use self::libc::c_void;
+use self::ring::rand::{SecureRandom, SystemRandom};
+
+use clear_on_drop::{clear, lock, lock_range, munlock, relock_heap};
+
+/// Length of the canaries next to a secret.
+const CANARY_LEN: usize = 16;
+/// Length of the slots of the arenas.
+pub(crate) const SLOT_LEN: usize = 256;
+/// Length of the arena, without the guard pages.
+const ARENA_LEN: usize = 16 * 1024;
+
+lazy_static! {
+    /// The size of a memory page.
+    pub(crate) static ref PAGE_SIZE: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    /// The canary of all secrets of the process.
+    static ref CANARY: [u8; CANARY_LEN] = {
+        let mut canary = [0; CANARY_LEN];
+        SystemRandom::new().fill(&mut canary).expect("could not generate canary");
+        canary
+    };
+    /// The arena for small secrets, mapped on first use.
+    static ref ARENA: Mutex<Option<Arena>> = Mutex::new(None);
+    /// The arena for small containers of `ClearOnDrop`, mapped on first use.
+    static ref BUFFERS: Mutex<Option<Arena>> = Mutex::new(None);
+    /// The start and length of the accessible pages of all mappings.
+    static ref MAPPINGS: Mutex<HashSet<(usize, usize)>> = Mutex::new(HashSet::new());
+}
+
+/// Lock the list of mappings, even if another thread panicked while holding
+/// it.
+fn mappings() -> MutexGuard<'static, HashSet<(usize, usize)>> {
+    MAPPINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Lock the memory of all secrets again after `fork`.
+///
+/// Memory locks are not inherited by a forked child, so a child keeping
+/// secrets allocated before the fork has to call this. This includes the
+/// arenas and the contents of `ClearOnDrop` on the heap.
+pub fn relock_after_fork() {
+    for &(start, len) in mappings().iter() {
+        lock_range(start as *const u8, len);
+    }
+    relock_heap();
+}
+
+/// Exclude memory from core dumps.
+#[cfg(target_os = "linux")]
+unsafe fn dont_dump(address: *mut u8, len: usize) {
+    // Failing to do so is not fatal, the memory is still protected.
+    let _ = libc::madvise(address as *mut c_void, len, libc::MADV_DONTDUMP);
+}
+
+/// Exclude memory from core dumps.
+#[cfg(not(target_os = "linux"))]
+unsafe fn dont_dump(_: *mut u8, _: usize) {}
+
+/// Print the last OS error and exit the process.
+///
+/// Unlike failing to lock memory, this cannot be handled by the
+/// `MlockPolicy`, since the secrets would end up in memory that is either
+/// missing or not protected at all.
+fn fail(action: &str) -> ! {
+    let error = io::Error::last_os_error();
+    // Stderr may be closed, for instance in the agent.
+    let _ = writeln!(io::stderr(), "error: could not {} memory holding secrets: {}",
+                     action, error);
+    process::exit(1);
+}
+
+/// Set the protection of pages.
+unsafe fn mprotect(address: *mut u8, len: usize, protection: libc::c_int) {
+    if libc::mprotect(address as *mut c_void, len, protection) != 0 {
+        fail("protect");
+    }
+}
+
+/// Pages surrounded by guard pages.
+struct Mapping {
+    /// Start of the mapping, which is the leading guard page.
+    base: *mut u8,
+    /// Length of the mapping, including the guard pages.
+    len: usize,
+}
+
+// The mapping is owned like a `Box`.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    /// Map zeroed pages of at least the given length, which are locked into
+    /// memory and excluded from core dumps, but inaccessible.
+    fn new(len: usize) -> Mapping {
+        let page_size = *PAGE_SIZE;
+        let accessible = len.div_ceil(page_size) * page_size;
+        let base = unsafe {
+            libc::mmap(ptr::null_mut(), accessible + 2 * page_size,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if base == libc::MAP_FAILED {
+            fail("map");
+        }
+        let mapping = Mapping { base: base as *mut u8, len: accessible + 2 * page_size };
+        unsafe {
+            mprotect(mapping.base, page_size, libc::PROT_NONE);
+            mprotect(mapping.start().add(accessible), page_size, libc::PROT_NONE);
+            dont_dump(mapping.start(), accessible);
+            lock(slice::from_raw_parts(mapping.start(), accessible));
+        }
+        mapping.protect(libc::PROT_NONE);
+        mappings().insert((mapping.start() as usize, accessible));
+        mapping
+    }
+
+    /// Return the start of the accessible pages.
+    fn start(&self) -> *mut u8 {
+        unsafe { self.base.add(*PAGE_SIZE) }
+    }
+
+    /// Return the length of the accessible pages.
+    fn accessible_len(&self) -> usize {
+        self.len - 2 * *PAGE_SIZE
+    }
+
+    /// Set the protection of the accessible pages.
+    fn protect(&self, protection: libc::c_int) {
+        unsafe { mprotect(self.start(), self.accessible_len(), protection) };
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        mappings().remove(&(self.start() as usize, self.accessible_len()));
+        self.protect(libc::PROT_READ | libc::PROT_WRITE);
+        unsafe {
+            let memory = slice::from_raw_parts_mut(self.start(), self.accessible_len());
+            clear(memory);
+            let _ = munlock(memory);
+            libc::munmap(self.base as *mut c_void, self.len);
+        }
+    }
+}
+
+/// Pages shared by small secrets, split into slots.
+///
+/// The pages are readable while any of the secrets is borrowed, and writable
+/// while any of them is borrowed mutably.
+struct Arena {
+    mapping: Mapping,
+    /// Which slots are in use.
+    used: Vec<bool>,
+    /// Number of active shared borrows.
+    readers: usize,
+    /// Number of active mutable borrows.
+    writers: usize,
+    /// The current protection of the pages.
+    protection: libc::c_int,
+}
+
+impl Arena {
+    /// Map an arena.
+    fn new() -> Arena {
+        let mapping = Mapping::new(ARENA_LEN);
+        let slots = mapping.accessible_len() / SLOT_LEN;
+        Arena {
+            mapping,
+            used: vec![false; slots],
+            readers: 0,
+            writers: 0,
+            protection: libc::PROT_NONE,
+        }
+    }
+
+    /// Return the start of a free slot, if any.
+    fn allocate(&mut self) -> Option<*mut u8> {
+        let index = self.used.iter().position(|&used| !used)?;
+        self.used[index] = true;
+        Some(unsafe { self.mapping.start().add(index * SLOT_LEN) })
+    }
+
+    /// Mark the slot starting at the given address as free.
+    ///
+    /// The slot has to be cleared before.
+    fn free(&mut self, slot: *mut u8) {
+        let index = (slot as usize - self.mapping.start() as usize) / SLOT_LEN;
+        self.used[index] = false;
+    }
+
+    /// Make the pages accessible for a borrow.
+    fn acquire(&mut self, writable: bool) {
+        if writable {
+            self.writers += 1;
+        } else {
+            self.readers += 1;
+        }
+        self.update();
+    }
+
+    /// End a borrow, making the pages inaccessible after the last one.
+    fn release(&mut self, writable: bool) {
+        if writable {
+            self.writers -= 1;
+        } else {
+            self.readers -= 1;
+        }
+        self.update();
+    }
+
+    /// Protect the pages according to the active borrows.
+    fn update(&mut self) {
+        let protection = if self.writers > 0 {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else if self.readers > 0 {
+            libc::PROT_READ
+        } else {
+            libc::PROT_NONE
+        };
+        if protection != self.protection {
+            self.mapping.protect(protection);
+            self.protection = protection;
+        }
+    }
+}
+
+/// Lock the arena, even if another thread panicked while holding it.
+fn arena() -> MutexGuard<'static, Option<Arena>> {
+    ARENA.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Lock the arena for `ClearOnDrop`, even if another thread panicked while
+/// holding it.
+fn buffers() -> MutexGuard<'static, Option<Arena>> {
+    BUFFERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Allocate a slot of `SLOT_LEN` bytes for the container of a `ClearOnDrop`,
+/// if any is free.
+///
+/// The slot is locked into memory like the arena for secrets, but always
+/// accessible, since `ClearOnDrop` hands out plain references.
+pub(crate) fn allocate_buffer() -> Option<*mut u8> {
+    buffers().get_or_insert_with(|| {
+        let mut arena = Arena::new();
+        arena.acquire(true);
+        arena
+    }).allocate()
+}
+
+/// Clear and free a slot allocated by `allocate_buffer`.
+pub(crate) fn free_buffer(slot: *mut u8) {
+    unsafe { clear(slice::from_raw_parts_mut(slot, SLOT_LEN)) };
+    buffers().as_mut().unwrap().free(slot);
+    //^ This unwrap is safe, because the arena exists if a slot was allocated.
+}
+
+/// Where the pages of a secret come from.
+enum Pages {
+    /// Pages of its own.
+    Own(Mapping),
+    /// The slot of the arena starting at the given address.
+    Pooled(*mut u8),
+}
+
+/// The memory of a secret.
+///
+/// The secret is preceded by a canary. In its own pages, it is placed at their
+/// end, so that overflows immediately hit the trailing guard page. In the
+/// arena, it is followed by another canary.
+struct Region {
+    pages: Pages,
+    /// Start of the secret.
+    data: *mut u8,
+    /// Length of the secret.
+    capacity: usize,
+    /// Number of active borrows of own pages.
+    borrows: Cell<usize>,
+}
+
+// The region is owned like a `Box`.
+unsafe impl Send for Region {}
+
+impl Region {
+    /// Allocate a region for a zeroed secret of the given length and
+    /// alignment.
+    fn new(capacity: usize, align: usize) -> Region {
+        let slot = if 2 * CANARY_LEN + capacity + align - 1 <= SLOT_LEN {
+            arena().get_or_insert_with(Arena::new).allocate()
+        } else {
+            None
+        };
+        let (pages, end) = match slot {
+            Some(slot) => (Pages::Pooled(slot), slot as usize + SLOT_LEN - CANARY_LEN),
+            None => {
+                let mapping = Mapping::new(CANARY_LEN + capacity + align - 1);
+                let end = mapping.start() as usize + mapping.accessible_len();
+                (Pages::Own(mapping), end)
+            },
+        };
+        let region = Region {
+            pages,
+            data: ((end - capacity) & !(align - 1)) as *mut u8,
+            capacity,
+            borrows: Cell::new(0),
+        };
+        region.acquire(true);
+        for canary in region.canaries() {
+            unsafe { ptr::copy_nonoverlapping(CANARY.as_ptr(), canary, CANARY_LEN) };
+        }
+        region.release(true);
+        region
+    }
+
+    /// Return the start of the canaries.
+    fn canaries(&self) -> Vec<*mut u8> {
+        let mut canaries = vec![unsafe { self.data.sub(CANARY_LEN) }];
+        if let Pages::Pooled(_) = self.pages {
+            canaries.push(unsafe { self.data.add(self.capacity) });
+        }
+        canaries
+    }
+
+    /// Make the secret accessible for a borrow.
+    fn acquire(&self, writable: bool) {
+        match self.pages {
+            Pages::Own(ref mapping) => {
+                if writable {
+                    debug_assert_eq!(self.borrows.get(), 0);
+                    mapping.protect(libc::PROT_READ | libc::PROT_WRITE);
+                } else if self.borrows.get() == 0 {
+                    mapping.protect(libc::PROT_READ);
+                }
+                self.borrows.set(self.borrows.get() + 1);
+            },
+            Pages::Pooled(_) => arena().as_mut().unwrap().acquire(writable),
+            //^ This unwrap is safe, because the arena exists for pooled secrets.
+        }
+    }
+
+    /// End a borrow, making the secret inaccessible after the last one.
+    fn release(&self, writable: bool) {
+        match self.pages {
+            Pages::Own(ref mapping) => {
+                self.borrows.set(self.borrows.get() - 1);
+                if self.borrows.get() == 0 {
+                    mapping.protect(libc::PROT_NONE);
+                }
+            },
+            Pages::Pooled(_) => arena().as_mut().unwrap().release(writable),
+            //^ This unwrap is safe, because the arena exists for pooled secrets.
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        self.acquire(true);
+        let intact = self.canaries().iter().all(|&canary|
+            unsafe { slice::from_raw_parts(canary, CANARY_LEN) } == &CANARY[..]);
+        if !intact {
+            // Something wrote out of bounds, continuing is not safe.
+            eprintln!("fatal: the memory of a secret was corrupted");
+            process::abort();
+        }
+        // Own pages are cleared when they are unmapped.
+        if let Pages::Pooled(slot) = self.pages {
+            unsafe { clear(slice::from_raw_parts_mut(slot, SLOT_LEN)) };
+        }
+        self.release(true);
+        if let Pages::Pooled(slot) = self.pages {
+            arena().as_mut().unwrap().free(slot);
+            //^ This unwrap is safe, because the arena exists for pooled secrets.
+        }
+    }
+}
+
+/// A shared borrow of a secret, which can be read while the borrow lasts.
+pub struct Ref<'a, T: ?Sized + 'a> {
+    region: &'a Region,
+    value: &'a T,
+}
+
+impl<'a, T: ?Sized> Ref<'a, T> {
+    /// Make a new `Ref` for a component of the borrowed secret.
+    pub fn map<U: ?Sized, F: FnOnce(&'a T) -> &'a U>(orig: Ref<'a, T>, f: F) -> Ref<'a, U> {
+        let region = orig.region;
+        let value = f(orig.value);
+        // The borrow is handed over to the new `Ref`.
+        mem::forget(orig);
+        Ref { region, value }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for Ref<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> Drop for Ref<'a, T> {
+    fn drop(&mut self) {
+        self.region.release(false);
+    }
+}
+
+/// A mutable borrow of a secret, which can be written while the borrow lasts.
+pub struct RefMut<'a, T: ?Sized + 'a> {
+    region: &'a Region,
+    value: &'a mut T,
+}
+
+impl<'a, T: ?Sized> Deref for RefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RefMut<'a, T> {
+    fn drop(&mut self) {
+        self.region.release(true);
+    }
+}
+
+/// A secret of fixed size, like a key.
+///
+/// Only plain data can be stored, which is why `T` has to be `Copy`.
+pub struct SecretBox<T: Copy> {
+    region: Region,
+    marker: PhantomData<T>,
+}
+
+impl<T: Copy> SecretBox<T> {
+    /// Move a value into protected memory.
+    ///
+    /// To avoid copies of the secret, create the box with a dummy value and
+    /// write the secret via `borrow_mut`.
+    pub fn new(value: T) -> SecretBox<T> {
+        let region = Region::new(mem::size_of::<T>(), mem::align_of::<T>());
+        region.acquire(true);
+        unsafe { ptr::write(region.data as *mut T, value) };
+        region.release(true);
+        SecretBox { region, marker: PhantomData }
+    }
+
+    /// Borrow the secret for reading.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.region.acquire(false);
+        Ref { region: &self.region, value: unsafe { &*(self.region.data as *const T) } }
+    }
+
+    /// Borrow the secret for writing.
+    pub fn borrow_mut(&mut self) -> RefMut<'_, T> {
+        self.region.acquire(true);
+        RefMut { region: &self.region, value: unsafe { &mut *(self.region.data as *mut T) } }
+    }
+}
+
+impl<T: Copy> fmt::Debug for SecretBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretBox(..)")
+    }
+}
+
+/// A secret sequence of bytes, like a decrypted buffer.
+pub struct SecretVec {
+    region: Region,
+}
+
+impl SecretVec {
+    /// Allocate a secret of the given length, initialized with zeros.
+    pub fn new(len: usize) -> SecretVec {
+        SecretVec { region: Region::new(len, 1) }
+    }
+
+    /// Copy bytes into protected memory.
+    pub fn from_slice(bytes: &[u8]) -> SecretVec {
+        let mut secret = SecretVec::new(bytes.len());
+        secret.borrow_mut().copy_from_slice(bytes);
+        secret
+    }
+
+    /// Return the number of bytes.
+    pub fn len(&self) -> usize {
+        self.region.capacity
+    }
+
+    /// Return whether there are no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Borrow the bytes for reading.
+    pub fn borrow(&self) -> Ref<'_, [u8]> {
+        self.region.acquire(false);
+        let value = unsafe { slice::from_raw_parts(self.region.data, self.region.capacity) };
+        Ref { region: &self.region, value }
+    }
+
+    /// Borrow the bytes for writing.
+    pub fn borrow_mut(&mut self) -> RefMut<'_, [u8]> {
+        self.region.acquire(true);
+        let value = unsafe { slice::from_raw_parts_mut(self.region.data, self.region.capacity) };
+        RefMut { region: &self.region, value }
+    }
+}
+
+impl fmt::Debug for SecretVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretVec(..)")
+    }
+}
+
+/// A secret string, like a password.
+pub struct SecretString {
+    bytes: SecretVec,
+}
+
+impl SecretString {
+    /// Copy a string into protected memory.
+    pub fn new(s: &str) -> SecretString {
+        SecretString { bytes: SecretVec::from_slice(s.as_bytes()) }
+    }
+
+    /// Collect characters into protected memory.
+    ///
+    /// The characters are iterated twice, first to measure the string, so
+    /// that it is not reallocated.
+    pub fn from_chars<I: Iterator<Item = char> + Clone>(chars: I) -> SecretString {
+        let len = chars.clone().map(char::len_utf8).sum();
+        let mut bytes = SecretVec::new(len);
+        {
+            let mut buffer = bytes.borrow_mut();
+            let mut written = 0;
+            for c in chars {
+                written += c.encode_utf8(&mut buffer[written..]).len();
+            }
+        }
+        SecretString { bytes }
+    }
+
+    /// Convert secret bytes to a string, if they are valid UTF-8.
+    pub fn from_utf8(bytes: SecretVec) -> Result<SecretString, SecretVec> {
+        if str::from_utf8(&bytes.borrow()).is_err() {
+            return Err(bytes);
+        }
+        Ok(SecretString { bytes })
+    }
+
+    /// Return the length of the string in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Return whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Borrow the string for reading.
+    pub fn borrow(&self) -> Ref<'_, str> {
+        Ref::map(self.bytes.borrow(), |bytes| unsafe { str::from_utf8_unchecked(bytes) })
+        //^ This is safe, because the bytes were checked when the string was created.
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretString(..)")
+    }
+}
+
+/// Return the protection of the page containing the given address, as shown
+/// in `/proc/self/maps`.
+#[cfg(all(test, target_os = "linux"))]
+fn page_protection(address: *const u8) -> String {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut maps = String::new();
+    File::open("/proc/self/maps").unwrap().read_to_string(&mut maps).unwrap();
+    let address = address as usize;
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().unwrap();
+        let mut bounds = range.split('-').map(|b| usize::from_str_radix(b, 16).unwrap());
+        let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+        if start <= address && address < end {
+            return fields.next().unwrap().into();
+        }
+    }
+    panic!("address is not mapped");
+}
+
+#[test]
+fn test_secret_box() {
+    let mut key = SecretBox::new([0u8; 64]);
+    assert_eq!(&key.borrow()[..], &[0; 64][..]);
+    key.borrow_mut()[3] = 42;
+    {
+        let first = key.borrow();
+        let second = key.borrow();
+        assert_eq!(first[3], 42);
+        assert_eq!(*first, *second);
+    }
+    assert_eq!(format!("{:?}", key), "SecretBox(..)");
+
+    let number = SecretBox::new(0x1234_5678_u64);
+    assert_eq!(number.region.data as usize % mem::align_of::<u64>(), 0);
+    assert_eq!(*number.borrow(), 0x1234_5678);
+}
+
+#[test]
+fn test_secret_string() {
+    let password = SecretString::new("VubeNazoRihe4(");
+    assert_eq!(&*password.borrow(), "VubeNazoRihe4(");
+    assert_eq!(password.len(), 14);
+
+    let empty = SecretString::new("");
+    assert!(empty.is_empty());
+    assert_eq!(&*empty.borrow(), "");
+
+    let collected = SecretString::from_chars("Grüße, 世界".chars());
+    assert_eq!(&*collected.borrow(), "Grüße, 世界");
+
+    let bytes = SecretVec::from_slice(b"secret");
+    assert_eq!(&*SecretString::from_utf8(bytes).unwrap().borrow(), "secret");
+    let bytes = SecretVec::from_slice(b"\xff");
+    assert_eq!(&*SecretString::from_utf8(bytes).unwrap_err().borrow(), b"\xff");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_secret_protection() {
+    // This is too large for the arena.
+    let mut contents = vec![b's'; 1000];
+    let mut secret = SecretVec::from_slice(&contents);
+    let data = secret.region.data;
+    let (base, end) = match secret.region.pages {
+        Pages::Own(ref mapping) =>
+            (mapping.base, mapping.start() as usize + mapping.accessible_len()),
+        Pages::Pooled(_) => panic!("large secret in arena"),
+    };
+    assert_eq!(page_protection(data), "---p");
+    assert_eq!(page_protection(base), "---p");
+    assert_eq!(page_protection(end as *const u8), "---p");
+    // The secret ends right before the trailing guard page.
+    assert_eq!(data as usize + secret.len(), end);
+    {
+        let first = secret.borrow();
+        let second = secret.borrow();
+        assert_eq!(page_protection(data), "r--p");
+        drop(first);
+        assert_eq!(page_protection(data), "r--p");
+        assert_eq!(&*second, &contents[..]);
+    }
+    assert_eq!(page_protection(data), "---p");
+    {
+        let mut bytes = secret.borrow_mut();
+        assert_eq!(page_protection(data), "rw-p");
+        bytes[0] = b'S';
+    }
+    assert_eq!(page_protection(data), "---p");
+    assert_eq!(page_protection(base), "---p");
+    contents[0] = b'S';
+    assert_eq!(&*secret.borrow(), &contents[..]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_secret_arena() {
+    // Other tests use the arena concurrently, so its protection cannot be
+    // checked here.
+    let secrets: Vec<SecretBox<[u8; 64]>> = (0..100).map(|i| SecretBox::new([i; 64])).collect();
+    let pooled: Vec<*mut u8> = secrets.iter().filter_map(|secret| match secret.region.pages {
+        Pages::Pooled(slot) => Some(slot),
+        Pages::Own(_) => None,
+    }).collect();
+    // The arena is shared with other tests, but some slots must be left.
+    assert!(!pooled.is_empty());
+    assert!(pooled.len() <= ARENA_LEN / SLOT_LEN);
+    assert!(pooled.len() < secrets.len());
+    for secret in &secrets {
+        let canaries = secret.region.canaries();
+        assert_eq!(canaries.len(), if let Pages::Pooled(_) = secret.region.pages { 2 } else { 1 });
+        let _key = secret.borrow();
+        assert_ne!(page_protection(secret.region.data), "---p");
+        for &canary in &canaries {
+            assert_eq!(unsafe { slice::from_raw_parts(canary, CANARY_LEN) }, &CANARY[..]);
+        }
+    }
+    for (i, secret) in secrets.iter().enumerate() {
+        assert_eq!(&secret.borrow()[..], &[i as u8; 64][..]);
+    }
+
+    drop(secrets);
+
+    // Freed slots are cleared, including the canaries. Other tests may take
+    // the slot as soon as it is freed, so it is only inspected if it is still
+    // free while the arena is locked.
+    let mut checked = false;
+    for _ in 0..10 {
+        let secret = SecretVec::from_slice(&[0xff; 64]);
+        let slot = match secret.region.pages {
+            Pages::Pooled(slot) => slot,
+            Pages::Own(_) => continue,
+        };
+        drop(secret);
+        let mut guard = arena();
+        let arena = guard.as_mut().unwrap();
+        let index = (slot as usize - arena.mapping.start() as usize) / SLOT_LEN;
+        if arena.used[index] {
+            continue;
+        }
+        arena.acquire(false);
+        let cleared = unsafe { slice::from_raw_parts(slot, SLOT_LEN) }.iter().all(|&b| b == 0);
+        arena.release(false);
+        assert!(cleared);
+        checked = true;
+        break;
+    }
+    assert!(checked);
+}
@@ -0,0 +1,83 @@
+extern crate libc;
+extern crate mpw;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+use mpw::config_file::{ConfigFile, Lock};
+
+/// Start an agent in the background with a config and password file in the
+/// given directory.
+///
+/// Returns the path of the config and the process ID of the agent.
+fn start_agent(dir: &Path) -> (PathBuf, libc::pid_t) {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "full_name = \"John Doe\"\n").unwrap();
+    let password = dir.join("password");
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(&password).unwrap()
+        .write_all(b"password\n").unwrap();
+
+    // The agent forks into the background, so the command returns while it
+    // keeps running.
+    let output = Command::new(env!("CARGO_BIN_EXE_mpw"))
+        .arg("--config").arg(&config)
+        .arg("--password-file").arg(&password)
+        .arg("agent").arg("--timeout").arg("60")
+        .env("XDG_RUNTIME_DIR", dir)
+        .output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let prefix = "MPW_AGENT_PID=";
+    let pid = stdout.split(';').map(|s| s.trim())
+        .find(|s| s.starts_with(prefix))
+        .unwrap()[prefix.len()..].parse().unwrap();
+    (config, pid)
+}
+
+#[test]
+fn test_agent_releases_config_lock() {
+    let dir = env::temp_dir().join(format!("mpw-test-agent-lock-{}", process::id()));
+    let (config, pid) = start_agent(&dir);
+
+    let locked = ConfigFile::try_open(&config, Lock::Exclusive).map(|_| ());
+    unsafe { libc::kill(pid, libc::SIGTERM); }
+    locked.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Return the amount of locked memory of a process, in kB.
+#[cfg(target_os = "linux")]
+fn locked_memory(pid: libc::pid_t) -> u64 {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    let line = status.lines().find(|line| line.starts_with("VmLck:")).unwrap();
+    line["VmLck:".len()..].trim().trim_end_matches("kB").trim().parse().unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_agent_locks_keys_after_fork() {
+    let dir = env::temp_dir().join(format!("mpw-test-agent-mlock-{}", process::id()));
+    let (_, pid) = start_agent(&dir);
+
+    // The child locks its memory right after the fork, but may not have run
+    // yet.
+    let mut locked = 0;
+    for _ in 0..50 {
+        locked = locked_memory(pid);
+        if locked > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    unsafe { libc::kill(pid, libc::SIGTERM); }
+    assert!(locked > 0, "the forked agent holds no locked memory");
+    fs::remove_dir_all(&dir).unwrap();
+}